@@ -1,10 +1,15 @@
-use std::{error::Error, sync::Arc};
+use std::sync::Arc;
 
 use async_trait::async_trait;
 
 use crate::{
+    errors::LLMError,
     prompt::TemplateArgs,
-    schemas::agent::{AgentAction, AgentEvent, AgentPlan},
+    schemas::{
+        agent::{AgentAction, AgentEvent, AgentPlan},
+        llm::ChatResult,
+        run::RunOptions,
+    },
     tools::tool_trait::Tool,
 };
 
@@ -12,14 +17,39 @@ use crate::{
 pub trait Agent: Send + Sync {
     async fn plan(
         &self,
-        intermediate_steps: &Vec<(AgentAction, String)>,
+        intermediate_steps: &[(AgentAction, String)],
         inputs: &dyn TemplateArgs,
-    ) -> Result<AgentPlan, Box<dyn Error>>;
+    ) -> Result<AgentPlan, LLMError>;
+
+    // Like `plan`, stopped when the run is cancelled or past its deadline.
+    async fn plan_with_options(
+        &self,
+        intermediate_steps: &[(AgentAction, String)],
+        inputs: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<AgentPlan, LLMError> {
+        match options.guard(self.plan(intermediate_steps, inputs)).await? {
+            AgentPlan::Stream(stream) => Ok(AgentPlan::Stream(options.guard_receiver(stream))),
+            plan => Ok(plan),
+        }
+    }
+
+    // Plans the next step without streaming and also returns the generation the
+    // plan was parsed from, when the agent has one.
+    async fn plan_with_result(
+        &self,
+        intermediate_steps: &[(AgentAction, String)],
+        inputs: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<(AgentPlan, Option<ChatResult>), LLMError> {
+        let plan = options.guard(self.plan(intermediate_steps, inputs)).await?;
+        Ok((plan, None))
+    }
 
     fn get_tools(&self) -> Vec<Arc<dyn Tool>>;
 }
 
 pub trait AgentOutputParser: Send + Sync {
-    fn parse(&self, text: &str) -> Result<AgentEvent, Box<dyn Error>>;
+    fn parse(&self, text: &str) -> Result<AgentEvent, LLMError>;
     fn get_format_instructions(&self) -> &str;
 }
@@ -1,7 +1,8 @@
 use std::sync::Arc;
 
 use crate::{
-    agents::agent::AgentOutputParser, chains::llmchat_chain::LLMChatChain, tools::tool_trait::Tool,
+    agents::agent::AgentOutputParser, chains::llmchat_chain::LLMChatChain, errors::LLMError,
+    tools::tool_trait::Tool,
 };
 
 use super::{
@@ -18,6 +19,12 @@ pub struct ConversationalAgentBuilder {
     template_tool_response: Option<String>,
 }
 
+impl Default for ConversationalAgentBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ConversationalAgentBuilder {
     pub fn new() -> Self {
         Self {
@@ -60,18 +67,12 @@ impl ConversationalAgentBuilder {
         self
     }
 
-    pub fn build(self) -> Result<ConversationalAgent, Box<dyn std::error::Error>> {
-        let llm = self.llm.ok_or_else(|| {
-            Box::new(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "LLM is not provided.",
-            ))
-        })?;
+    pub fn build(self) -> Result<ConversationalAgent, LLMError> {
+        let llm = self
+            .llm
+            .ok_or_else(|| LLMError::InvalidConfiguration(String::from("LLM is not provided.")))?;
         let tools = self.tools.ok_or_else(|| {
-            Box::new(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "Tools are not provided.",
-            ))
+            LLMError::InvalidConfiguration(String::from("Tools are not provided."))
         })?;
         let output_parser = self
             .output_parser
@@ -84,7 +85,7 @@ impl ConversationalAgentBuilder {
             .unwrap_or_else(|| TEMPLATE_TOOL_RESPONSE.to_string());
 
         let prompt =
-            ConversationalAgent::create_prompt(&tools, &prefix, &suffix, &FORMAT_INSTRUCTIONS)?;
+            ConversationalAgent::create_prompt(&tools, &prefix, &suffix, FORMAT_INSTRUCTIONS)?;
         let chain = Box::new(LLMChatChain::new(prompt, llm));
 
         Ok(ConversationalAgent {
@@ -1,7 +1,8 @@
-use std::{error::Error, sync::Arc};
+use std::sync::Arc;
 
 use crate::{
     chains::{chain_trait::ChainTrait, llmchat_chain::LLMChatChain},
+    errors::{ApiError, LLMError, PromptError},
     prompt::{
         ChatPromptTemplate, HumanMessagePromptTemplate, MessageLike, MessagesPlaceholder,
         PromptTemplate, TemplateArgs,
@@ -9,7 +10,9 @@ use crate::{
     schemas::{
         agent::{AgentAction, AgentPlan},
         chain::ChainResponse,
+        llm::ChatResult,
         messages::{AIMessage, BaseMessage, HumanMessage, SystemMessage},
+        run::RunOptions,
     },
     tools::tool_trait::Tool,
 };
@@ -17,6 +20,7 @@ use async_trait::async_trait;
 use handlebars::Handlebars;
 use serde_json::json;
 use tokio::sync::mpsc;
+use tracing::Instrument;
 
 use self::prompt::{FORMAT_INSTRUCTIONS, PREFIX, SUFFIX, TEMPLATE_TOOL_RESPONSE};
 
@@ -43,7 +47,7 @@ impl ConversationalAgent {
         system_message: &str,
         human_message: &str,
         format_instruction: &str,
-    ) -> Result<ChatPromptTemplate, Box<dyn Error>> {
+    ) -> Result<ChatPromptTemplate, LLMError> {
         let tool_string = tools
             .iter()
             .map(|tool| format!("> {}: {}", tool.name(), tool.description()))
@@ -56,25 +60,29 @@ impl ConversationalAgent {
             .join(", ");
 
         let handlebars = Handlebars::new();
-        let format_instruction = handlebars.render_template(
-            human_message,
-            &json!({
-                "format_instructions":format_instruction,
-                "tools":"{{tools}}",
-                "input":"{{input}}"
-            }),
-        )?;
-
-        let final_prompt = handlebars.render_template(
-            &format_instruction,
-            &json!({
-                    "tool_names":tool_names,
-                    "tools":tool_string,
+        let format_instruction = handlebars
+            .render_template(
+                human_message,
+                &json!({
+                    "format_instructions":format_instruction,
+                    "tools":"{{tools}}",
                     "input":"{{input}}"
-            }),
-        )?;
+                }),
+            )
+            .map_err(PromptError::from)?;
+
+        let final_prompt = handlebars
+            .render_template(
+                &format_instruction,
+                &json!({
+                        "tool_names":tool_names,
+                        "tools":tool_string,
+                        "input":"{{input}}"
+                }),
+            )
+            .map_err(PromptError::from)?;
         let prompt = html_escape::decode_html_entities(&final_prompt).to_string();
-        log::debug!("Prompt:{}", prompt);
+        tracing::debug!("Prompt:{}", prompt);
 
         let prompt = ChatPromptTemplate::from_messages(vec![
             MessageLike::base_message(SystemMessage::new(system_message)),
@@ -90,19 +98,20 @@ impl ConversationalAgent {
 
     fn construct_scratchpad(
         &self,
-        intermediate_steps: &Vec<(AgentAction, String)>,
-    ) -> Result<Vec<Box<dyn BaseMessage>>, Box<dyn Error>> {
-        log::debug!("Building scratchpad");
+        intermediate_steps: &[(AgentAction, String)],
+    ) -> Result<Vec<Box<dyn BaseMessage>>, LLMError> {
+        tracing::debug!(steps = intermediate_steps.len(), "Building scratchpad");
         let mut thoughts: Vec<Box<dyn BaseMessage>> = Vec::new();
 
-        for (action, observation) in intermediate_steps.into_iter() {
-            log::debug!("Action: {:?}:{}", action, observation);
+        for (action, observation) in intermediate_steps.iter() {
             thoughts.push(Box::new(AIMessage::new(&action.log)) as Box<dyn BaseMessage>);
             let handlebars = Handlebars::new();
-            let tool_response = handlebars.render_template(
-                self.template_tool_response.as_str(),
-                &json!({ "observation": observation }),
-            )?;
+            let tool_response = handlebars
+                .render_template(
+                    self.template_tool_response.as_str(),
+                    &json!({ "observation": observation }),
+                )
+                .map_err(PromptError::from)?;
             thoughts.push(Box::new(HumanMessage::new(&tool_response)));
         }
 
@@ -113,9 +122,9 @@ impl ConversationalAgent {
         llm: Box<dyn crate::chat_models::chat_model_trait::ChatTrait>,
         tools: Vec<Arc<dyn Tool>>,
         output_parser: Box<dyn AgentOutputParser>,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    ) -> Result<Self, LLMError> {
         let prompt =
-            ConversationalAgent::create_prompt(&tools, &PREFIX, &SUFFIX, &FORMAT_INSTRUCTIONS)?;
+            ConversationalAgent::create_prompt(&tools, PREFIX, SUFFIX, FORMAT_INSTRUCTIONS)?;
         let chain = Box::new(LLMChatChain::new(prompt, llm));
         Ok(Self {
             tools,
@@ -131,51 +140,67 @@ impl ConversationalAgent {
 impl Agent for ConversationalAgent {
     async fn plan(
         &self,
-        intermediate_steps: &Vec<(AgentAction, String)>,
+        intermediate_steps: &[(AgentAction, String)],
         inputs: &dyn TemplateArgs,
-    ) -> Result<AgentPlan, Box<dyn Error>> {
-        log::debug!("Planning");
-        let scratchpad = self.construct_scratchpad(&intermediate_steps)?;
+    ) -> Result<AgentPlan, LLMError> {
+        self.plan_with_options(intermediate_steps, inputs, &RunOptions::default())
+            .await
+    }
+
+    async fn plan_with_options(
+        &self,
+        intermediate_steps: &[(AgentAction, String)],
+        inputs: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<AgentPlan, LLMError> {
+        let scratchpad = self.construct_scratchpad(intermediate_steps)?;
         let mut inputs = inputs.clone_as_map();
         inputs.insert("agent_scratchpad".to_string(), json!(scratchpad)); // Assuming scratchpad is a Stringhapad
 
-        log::debug!("Running chain");
-        let output = self.chain.run(&inputs).await?;
+        let output = self.chain.run_with_options(&inputs, options).await?;
         match output {
             ChainResponse::Text(text) => {
-                log::debug!("Parsing output:{}", text);
+                tracing::debug!(output = %options.redact(|| text.clone()), "Parsing output");
                 let parsed_output = self.output_parser.parse(&text)?;
-                log::debug!("Parsed output");
                 return Ok(AgentPlan::Text(parsed_output));
             }
             ChainResponse::Stream(mut stream) => {
                 let mut complete_message = String::new();
-                let (tx, mut temp_rx) =
-                    mpsc::channel::<Result<String, reqwest_eventsource::Error>>(100);
+                let (tx, mut temp_rx) = mpsc::channel::<Result<String, ApiError>>(100);
 
-                tokio::spawn(async move {
-                    while let Some(event_result) = stream.recv().await {
+                let task = async move {
+                    loop {
+                        // Dropping `stream` when nobody listens stops the chain
+                        let event_result = tokio::select! {
+                            _ = tx.closed() => break,
+                            event_result = stream.recv() => match event_result {
+                                Some(event_result) => event_result,
+                                None => break,
+                            },
+                        };
                         match event_result {
                             Ok(message) => {
                                 if message.contains("}") && !message.contains(r#"}""#) {
                                     break;
                                 } else {
                                     if tx.send(Ok(message.clone())).await.is_err() {
-                                        eprintln!("Failed to send message to the channel");
+                                        tracing::error!("Failed to send message to the channel");
                                         break;
                                     }
                                 }
                             }
                             Err(err) => {
-                                eprintln!("Error while processing the stream: {:?}", err);
+                                tracing::error!("Error while processing the stream: {}", err);
                                 if tx.send(Err(err)).await.is_err() {
-                                    eprintln!("Failed to send error to the channel");
+                                    tracing::error!("Failed to send error to the channel");
                                 }
                                 break;
                             }
                         }
                     }
-                });
+                }
+                .in_current_span();
+                tokio::spawn(task);
 
                 // Consume the temporary stream
                 while let Some(temp_event_result) = temp_rx.recv().await {
@@ -190,8 +215,8 @@ impl Agent for ConversationalAgent {
                             }
                         }
                         Err(err) => {
-                            log::error!("Error receiving message:{}", err);
-                            return Err(err.into());
+                            tracing::error!("Error receiving message:{}", err);
+                            return Err(LLMError::Stream(err));
                         }
                     }
                 }
@@ -203,6 +228,21 @@ impl Agent for ConversationalAgent {
         }
     }
 
+    async fn plan_with_result(
+        &self,
+        intermediate_steps: &[(AgentAction, String)],
+        inputs: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<(AgentPlan, Option<ChatResult>), LLMError> {
+        let scratchpad = self.construct_scratchpad(intermediate_steps)?;
+        let mut inputs = inputs.clone_as_map();
+        inputs.insert("agent_scratchpad".to_string(), json!(scratchpad));
+
+        let result = self.chain.call_with_options(&inputs, options).await?;
+        let parsed_output = self.output_parser.parse(&result.text())?;
+        Ok((AgentPlan::Text(parsed_output), Some(result)))
+    }
+
     fn get_tools(&self) -> Vec<Arc<dyn Tool>> {
         self.tools.clone()
     }
@@ -210,9 +250,19 @@ impl Agent for ConversationalAgent {
 
 #[cfg(test)]
 mod tests {
-    use std::{error::Error, sync::Arc};
+    use std::{
+        collections::HashMap,
+        fmt,
+        sync::{Arc, Mutex},
+    };
 
     use async_trait::async_trait;
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Subscriber,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
 
     use crate::{
         agents::{
@@ -220,8 +270,9 @@ mod tests {
             executor::AgentExecutor,
         },
         chains::chain_trait::ChainTrait,
-        chat_models::openai::ChatModel,
-        schemas::chain::ChainResponse,
+        chat_models::fake::FakeChatModel,
+        errors::{BoxError, LLMError},
+        schemas::{chain::ChainResponse, llm::TokenUsage, run::RunOptions},
         tools::tool_trait::Tool,
     };
 
@@ -240,7 +291,7 @@ A wrapper around M&V Product Information. Useful for when you need to get the in
                 .to_string()
         }
 
-        async fn call(&self, _input: &str) -> Result<String, Box<dyn Error>> {
+        async fn call(&self, _input: &str) -> Result<String, BoxError> {
             Ok("Luis Fernando is the president of Peru. tiene 30 anos".to_string())
         }
     }
@@ -256,19 +307,193 @@ A wrapper around M&V Product Information. Useful for when you need to get the in
             "Use this tool if you want to calculate ages".to_string()
         }
 
-        async fn call(&self, _input: &str) -> Result<String, Box<dyn Error>> {
+        async fn call(&self, _input: &str) -> Result<String, BoxError> {
             Ok("50".to_string())
         }
     }
 
+    #[tokio::test]
+    async fn test_executor_call_sums_usage_over_steps() {
+        let llm = FakeChatModel::new(vec![
+            "```json\n{\"action\": \"Calculator\", \"action_input\": \"20 + 30\"}\n```",
+            "```json\n{\"action\": \"Final Answer\", \"action_input\": \"He is 50\"}\n```",
+        ])
+        .with_usage(TokenUsage::new(10, 5));
+        let agent = ConversationalAgent::from_llm_and_tools(
+            Box::new(llm),
+            vec![Arc::new(CalcTool)],
+            Box::new(ConvoOutputParser::new()),
+        )
+        .unwrap();
+        let exec = AgentExecutor::from_agent(Box::new(agent));
+
+        let result = exec.call(&String::from("How old is he?")).await.unwrap();
+
+        assert_eq!(result.text(), "He is 50");
+        assert_eq!(result.model, "fake");
+        assert_eq!(result.usage, Some(TokenUsage::new(20, 10)));
+    }
+
+    #[derive(Default)]
+    struct Fields(HashMap<String, String>);
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+    }
+
+    struct ClosedSpan {
+        name: String,
+        parent: String,
+        fields: HashMap<String, String>,
+    }
+
+    // Records the spans once they are closed
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: Arc<Mutex<Vec<ClosedSpan>>>,
+    }
+    impl SpanRecorder {
+        // The parent name and the fields of the spans called `name`
+        fn named(&self, name: &str) -> Vec<(String, HashMap<String, String>)> {
+            let spans = self.spans.lock().unwrap();
+            spans
+                .iter()
+                .filter(|span| span.name == name)
+                .map(|span| (span.parent.clone(), span.fields.clone()))
+                .collect()
+        }
+    }
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            ctx.span(id).unwrap().extensions_mut().insert(fields);
+        }
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let mut extensions = span.extensions_mut();
+            values.record(extensions.get_mut::<Fields>().unwrap());
+        }
+        fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let parent = span
+                .parent()
+                .map(|parent| parent.name())
+                .unwrap_or_default();
+            let fields = span.extensions_mut().remove::<Fields>().unwrap();
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(ClosedSpan {
+                name: span.name().to_string(),
+                parent: parent.to_string(),
+                fields: fields.0,
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_executor_traces_nested_spans() {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing_subscriber::registry()
+            .with(recorder.clone())
+            .set_default();
+        let llm = FakeChatModel::new(vec![
+            "```json\n{\"action\": \"Calculator\", \"action_input\": \"20 + 30\"}\n```",
+            "```json\n{\"action\": \"Final Answer\", \"action_input\": \"He is 50\"}\n```",
+        ])
+        .with_usage(TokenUsage::new(10, 5));
+        let agent = ConversationalAgent::from_llm_and_tools(
+            Box::new(llm),
+            vec![Arc::new(CalcTool)],
+            Box::new(ConvoOutputParser::new()),
+        )
+        .unwrap();
+        let exec = AgentExecutor::from_agent(Box::new(agent));
+        let input = String::from("How old is he?");
+        exec.call(&input).await.unwrap();
+
+        let runs = recorder.named("agent_run");
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].1["iterations"], "2");
+        assert!(runs[0].1.contains_key("latency_ms"));
+        let iterations = recorder.named("agent_iteration");
+        assert_eq!(iterations[0].0, "agent_run");
+        assert_eq!(iterations[0].1["action"], "Calculator");
+        assert_eq!(iterations[1].1["action"], "Final Answer");
+        assert!(recorder
+            .named("chain_run")
+            .iter()
+            .all(|(parent, _)| parent == "agent_iteration"));
+
+        let calls = recorder.named("llm_call");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].0, "chain_run");
+        assert_eq!(calls[0].1["model"], "fake");
+        assert_eq!(calls[0].1["prompt_tokens"], "10");
+        assert_eq!(calls[0].1["prompt"], "[REDACTED]");
+        let tools = recorder.named("tool_call");
+        assert_eq!(tools[0].0, "agent_iteration");
+        assert_eq!(tools[0].1["tool"], "Calculator");
+        assert_eq!(tools[0].1["input"], "[REDACTED]");
+
+        // The content is recorded once the run opts in
+        let options = RunOptions::new().with_traced_content();
+        exec.call_with_options(&input, &options).await.unwrap();
+        let calls = recorder.named("llm_call");
+        assert!(calls[2].1["prompt"].contains("How old is he?"));
+        assert_eq!(recorder.named("tool_call")[1].1["output"], "50");
+    }
+
+    #[tokio::test]
+    async fn test_executor_reports_typed_errors() {
+        let llm = FakeChatModel::new(vec![
+            "```json\n{\"action\": \"Search\", \"action_input\": \"age\"}\n```",
+        ]);
+        let agent = ConversationalAgent::from_llm_and_tools(
+            Box::new(llm),
+            vec![Arc::new(CalcTool)],
+            Box::new(ConvoOutputParser::new()),
+        )
+        .unwrap();
+        let exec = AgentExecutor::from_agent(Box::new(agent));
+        let err = exec
+            .call(&String::from("How old is he?"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LLMError::ToolNotFound(tool) if tool == "Search"));
+
+        let llm = FakeChatModel::new(vec![
+            "```json\n{\"action\": \"Calculator\", \"action_input\": \"1 + 1\"}\n```",
+        ]);
+        let agent = ConversationalAgent::from_llm_and_tools(
+            Box::new(llm),
+            vec![Arc::new(CalcTool)],
+            Box::new(ConvoOutputParser::new()),
+        )
+        .unwrap();
+        let exec = AgentExecutor::from_agent(Box::new(agent)).with_max_iterations(1);
+        let err = exec
+            .call(&String::from("How old is he?"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LLMError::MaxIterations(1)));
+    }
+
     #[tokio::test]
     async fn test_agent_run_with_string() {
+        // One character per chunk, like the tokens of a real stream
+        let llm = FakeChatModel::new(vec![
+            "```json\n{\n    \"action\": \"Get products by name\",\n    \"action_input\": \"presidente\"\n}\n```",
+            "```json\n{\n    \"action\": \"Final Answer\",\n    \"action_input\": \"Luis Fernando tiene 30 anos\"\n}\n```",
+        ])
+        .with_stream()
+        .with_chunk_size(1);
         let agent = ConversationalAgent::from_llm_and_tools(
-            Box::new(
-                crate::chat_models::openai::chat_llm::ChatOpenAI::default()
-                    .with_model(ChatModel::Gpt4TURBO)
-                    .with_stream(),
-            ),
+            Box::new(llm.clone()),
             vec![Arc::new(MockPeruPresidentTool), Arc::new(CalcTool)],
             Box::new(ConvoOutputParser::new()),
         );
@@ -276,26 +501,26 @@ A wrapper around M&V Product Information. Useful for when you need to get the in
         let exec = AgentExecutor::from_agent(Box::new(agent.unwrap()));
 
         let result = exec
-            .run(&String::from("Que chip tiene la macbook pro m1 "))
+            .run(&String::from("Quien es el presidente del Peru?"))
             .await
-            .map_err(|e| println!("{}", e))
             .unwrap();
-        match result {
-            ChainResponse::Text(text) => {
-                println!("{}", text);
-            }
-            ChainResponse::Stream(mut stream) => {
-                while let Some(event_result) = stream.recv().await {
-                    match event_result {
-                        Ok(message) => {
-                            println!("Streamed message: {:#?}", message);
-                        }
-                        Err(err) => {
-                            println!("Error in stream: {}", err);
-                        }
-                    }
-                }
-            }
+        let ChainResponse::Stream(mut stream) = result else {
+            panic!("expected a stream");
+        };
+        let mut text = String::new();
+        while let Some(delta) = stream.recv().await {
+            text.push_str(&delta.unwrap());
         }
+        assert_eq!(
+            text.trim_end().trim_end_matches('"'),
+            "Luis Fernando tiene 30 anos"
+        );
+
+        // The answer of the tool is shown to the model in the second step
+        let calls = llm.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[1].iter().any(|message| message
+            .get_content()
+            .contains("Luis Fernando is the president")));
     }
 }
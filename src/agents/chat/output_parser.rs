@@ -1,7 +1,6 @@
-use std::error::Error;
-
 use crate::{
     agents::agent::AgentOutputParser,
+    errors::LLMError,
     schemas::agent::{AgentAction, AgentEvent, AgentFinish},
 };
 use regex::Regex;
@@ -16,6 +15,12 @@ struct AgentOutput {
 }
 
 pub struct ConvoOutputParser {}
+impl Default for ConvoOutputParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ConvoOutputParser {
     pub fn new() -> Self {
         Self {}
@@ -23,7 +28,7 @@ impl ConvoOutputParser {
 }
 
 impl AgentOutputParser for ConvoOutputParser {
-    fn parse(&self, text: &str) -> Result<AgentEvent, Box<dyn Error>> {
+    fn parse(&self, text: &str) -> Result<AgentEvent, LLMError> {
         let sanitized_text = text
             .chars()
             .map(|c| if c.is_control() { ' ' } else { c })
@@ -34,7 +39,8 @@ impl AgentOutputParser for ConvoOutputParser {
         let json_match = re.captures(&sanitized_text).and_then(|cap| cap.get(1));
         log::debug!("Finish extracting json");
         let agent_output: AgentOutput = match json_match {
-            Some(json_str) => serde_json::from_str(&json_str.as_str())?,
+            Some(json_str) => serde_json::from_str(json_str.as_str())
+                .map_err(|e| LLMError::output_parsing(&sanitized_text, e))?,
             None => {
                 log::debug!("No JSON found in text: {}", sanitized_text);
                 return Ok(AgentEvent::Finish(AgentFinish {
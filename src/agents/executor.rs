@@ -1,20 +1,25 @@
 use std::{
     collections::HashMap,
-    error::Error,
     sync::{Arc, RwLock},
+    time::Instant,
 };
 
 use async_trait::async_trait;
 use tokio::sync::mpsc;
+use tracing::{field, Instrument, Span};
 
 use crate::{
     chains::chain_trait::ChainTrait,
+    errors::{ApiError, LLMError, PromptError},
     prompt::TemplateArgs,
     schemas::{
-        agent::{AgentAction, AgentEvent, AgentPlan},
+        agent::{AgentAction, AgentEvent, AgentFinish, AgentPlan},
+        callbacks::Uuid,
         chain::ChainResponse,
+        llm::{ChatResult, TokenUsage},
         memory::BaseChatMessageHistory,
         messages::{AIMessage, BaseMessage, HumanMessage},
+        run::RunOptions,
     },
     tools::tool_trait::Tool,
 };
@@ -31,7 +36,7 @@ impl AgentExecutor {
     fn get_name_to_tools(&self) -> HashMap<String, Arc<dyn Tool>> {
         let mut name_to_tool = HashMap::new();
         for tool in self.agent.get_tools().iter() {
-            log::debug!("Loading Tool:{}", tool.name());
+            tracing::debug!("Loading Tool:{}", tool.name());
             name_to_tool.insert(tool.name(), tool.clone());
         }
         name_to_tool
@@ -55,28 +60,87 @@ impl AgentExecutor {
     }
 }
 
-#[async_trait]
-impl ChainTrait for AgentExecutor {
-    async fn run(&self, input: &dyn TemplateArgs) -> Result<ChainResponse, Box<dyn Error>> {
+impl AgentExecutor {
+    // Runs the agent loop in an `agent_run` span, reporting the run to the
+    // callbacks. A streamed answer is reported once the stream is done.
+    async fn execute(
+        &self,
+        input: &dyn TemplateArgs,
+        collect_results: bool,
+        options: &RunOptions,
+    ) -> Result<(ChainResponse, Vec<ChatResult>), LLMError> {
+        let (run_id, child_options) = options.start_run();
+        if options.has_callbacks() {
+            let inputs = input.clone_as_map();
+            options
+                .emit(|handler| handler.on_chain_start(run_id, options.parent_run_id(), &inputs));
+        }
+        let span = tracing::info_span!(
+            "agent_run",
+            %run_id,
+            iterations = field::Empty,
+            latency_ms = field::Empty,
+            error = field::Empty,
+        );
+        let started = Instant::now();
+        let result = self
+            .run_loop(input, collect_results, run_id, &child_options, started)
+            .instrument(span.clone())
+            .await;
+        match &result {
+            Ok((ChainResponse::Text(text), _)) => {
+                span.record("latency_ms", started.elapsed().as_millis() as u64);
+                options.emit(|handler| handler.on_chain_end(run_id, text))
+            }
+            // Recorded by the task forwarding the stream
+            Ok((ChainResponse::Stream(_), _)) => {}
+            Err(e) => end_with_error(&span, started, run_id, options, e),
+        }
+        result
+    }
+
+    // When `collect_results` is set, the agent plans without streaming and the
+    // generation behind every step is returned as well. Every step checks
+    // `options`, which also reach the model requests and tool calls, and runs in
+    // an `agent_iteration` span.
+    async fn run_loop(
+        &self,
+        input: &dyn TemplateArgs,
+        collect_results: bool,
+        run_id: Uuid,
+        options: &RunOptions,
+        started: Instant,
+    ) -> Result<(ChainResponse, Vec<ChatResult>), LLMError> {
+        let run_span = Span::current();
         let name_to_tools = self.get_name_to_tools();
 
         let mut steps: Vec<(AgentAction, String)> = Vec::new();
+        let mut results: Vec<ChatResult> = Vec::new();
 
-        log::debug!("Starting agent");
+        tracing::debug!("Starting agent");
         let mut max_iterations = self.max_iterations;
+        let mut iteration: u32 = 0;
 
         let mut input_map = input.clone_as_map();
 
         if let Some(memory_arc) = &self.memory {
             let memory_guard = memory_arc
                 .read()
-                .map_err(|_| "Failed to acquire read lock")?;
+                .map_err(|_| LLMError::Memory(String::from("Failed to acquire read lock")))?;
             let message_history = memory_guard;
 
-            log::debug!("Messaage History");
-            for message in message_history.messages().iter() {
-                log::debug!("{}", message.get_content());
-            }
+            tracing::debug!(
+                messages = message_history.messages().len(),
+                history = %options.redact(|| {
+                    message_history
+                        .messages()
+                        .iter()
+                        .map(|message| message.get_content())
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }),
+                "Loaded the chat history"
+            );
             input_map.insert(
                 "chat_history".to_string(),
                 serde_json::json!(message_history.messages()),
@@ -87,116 +151,237 @@ impl ChainTrait for AgentExecutor {
         }
 
         loop {
-            let agent_event = self.agent.plan(&steps, &input_map).await?;
+            options.check()?;
+            iteration += 1;
+            run_span.record("iterations", iteration);
+            let iteration_span =
+                tracing::info_span!("agent_iteration", iteration, action = field::Empty);
+            let agent_event = if collect_results {
+                let (plan, result) = self
+                    .agent
+                    .plan_with_result(&steps, &input_map, options)
+                    .instrument(iteration_span.clone())
+                    .await?;
+                results.extend(result);
+                plan
+            } else {
+                self.agent
+                    .plan_with_options(&steps, &input_map, options)
+                    .instrument(iteration_span.clone())
+                    .await?
+            };
             match agent_event {
-                AgentPlan::Text(event) => {
-                    match event {
-                        AgentEvent::Action(action) => {
-                            log::debug!("Action: {:?}", action.tool_input);
-                            let tool = name_to_tools.get(&action.tool).ok_or("Tool not found")?; //No se si
-                                                                                                 //lanzar error o poner este mensage evaluar
-                            let observarion = tool.call(&action.tool_input).await?;
-                            steps.push((action, observarion));
-                        }
-                        AgentEvent::Finish(finish) => {
-                            println!("Finish: {:?}", finish.return_values);
-                            log::debug!("AgentEvent::Finish branch entered");
-
-                            if let Some(memory_arc) = &self.memory {
-                                log::debug!("Attempting to add to memory");
-                                let mut memory_guard = memory_arc
-                                    .write()
-                                    .map_err(|_| "Failed to acquire write lock")?;
-                                log::debug!("Successfully acquired write lock");
-
-                                log::debug!(
-                                    "AgentExecutor::run's memory address: {:?}",
-                                    memory_arc as *const _
-                                );
-                                let inputs = input.clone_as_map();
-                                let human_str = inputs
-                                    .get("input")
-                                    .ok_or("Human not found")?
-                                    .as_str()
-                                    .ok_or("Human not found")?
-                                    .to_string();
-                                log::debug!("Adding Human message: {}", human_str.to_string());
-                                memory_guard.add_message(Box::new(HumanMessage::new(
-                                    &human_str.to_string(),
-                                )));
-                                log::debug!("Successfully added Human message to memory");
-
-                                memory_guard
-                                    .add_message(Box::new(AIMessage::new(&finish.return_values)));
-                                log::debug!("Successfully added AI message to memory");
-                            }
+                AgentPlan::Text(event) => match event {
+                    AgentEvent::Action(action) => {
+                        iteration_span.record("action", action.tool.as_str());
+                        iteration_span.in_scope(|| {
+                            tracing::debug!(
+                                tool = %action.tool,
+                                input = %options.redact(|| action.tool_input.clone()),
+                                "Calling tool"
+                            )
+                        });
+                        options.emit(|handler| handler.on_agent_action(run_id, &action));
+                        let tool = name_to_tools
+                            .get(&action.tool)
+                            .ok_or_else(|| LLMError::ToolNotFound(action.tool.clone()))?;
+                        let observarion = tool
+                            .call_with_options(&action.tool_input, options)
+                            .instrument(iteration_span.clone())
+                            .await
+                            .map_err(|source| LLMError::ToolFailed {
+                                tool: action.tool.clone(),
+                                source,
+                            })?;
+                        steps.push((action, observarion));
+                    }
+                    AgentEvent::Finish(finish) => {
+                        iteration_span.record("action", "Final Answer");
+                        iteration_span.in_scope(|| {
+                            tracing::debug!(
+                                answer = %options.redact(|| finish.return_values.clone()),
+                                "Finished"
+                            )
+                        });
+                        options.emit(|handler| handler.on_agent_finish(run_id, &finish));
 
-                            return Ok(ChainResponse::Text(finish.return_values));
+                        if let Some(memory_arc) = &self.memory {
+                            let mut memory_guard = memory_arc.write().map_err(|_| {
+                                LLMError::Memory(String::from("Failed to acquire write lock"))
+                            })?;
+                            let human_str = human_input(input)?;
+                            memory_guard
+                                .add_message(Box::new(HumanMessage::new(&human_str.to_string())));
+                            memory_guard
+                                .add_message(Box::new(AIMessage::new(&finish.return_values)));
+                            tracing::debug!("Added the question and the answer to memory");
                         }
+
+                        return Ok((ChainResponse::Text(finish.return_values), results));
                     }
-                }
+                },
                 AgentPlan::Stream(mut internal_stream) => {
                     let (tx, rx) = mpsc::channel(100);
 
                     // Clone necessary data
                     let memory_arc_clone = self.memory.clone();
+                    let options = options.clone();
 
-                    let inputs = input.clone_as_map();
-                    let human_str = inputs
-                        .get("input")
-                        .ok_or("Human not found")?
-                        .as_str()
-                        .ok_or("Human not found")?
-                        .to_string();
+                    let human_str = human_input(input)?;
+                    iteration_span.record("action", "Final Answer");
+                    let span = run_span.clone();
                     // Spawn a new asynchronous task to handle stream
-                    tokio::spawn(async move {
+                    let task = async move {
                         let mut concatenated_stream_content = String::new();
 
-                        while let Some(event) = internal_stream.recv().await {
-                            match &event {
+                        let finished = loop {
+                            let event = tokio::select! {
+                                _ = tx.closed() => break false,
+                                error = options.interrupted() => Err(error),
+                                event = internal_stream.recv() => match event {
+                                    Some(event) => event,
+                                    None => break true,
+                                },
+                            };
+                            match event {
                                 Ok(message) => {
                                     concatenated_stream_content.push_str(&message);
-
-                                    // Stop the stream when finish_reason is not null
+                                    if tx.send(Ok(message)).await.is_err() {
+                                        break false;
+                                    }
+                                }
+                                Err(e) => {
+                                    let error = LLMError::Stream(e);
+                                    end_with_error(&span, started, run_id, &options, &error);
+                                    // The caller gets the error itself
+                                    if let LLMError::Stream(e) = error {
+                                        if tx.send(Err(e)).await.is_err() {
+                                            tracing::error!(
+                                                "Failed to send the error to the channel"
+                                            );
+                                        }
+                                    }
+                                    return;
                                 }
-                                _ => {}
                             }
+                        };
 
-                            if let Err(_) = tx.send(event).await {
-                                eprintln!("Failed to send the event to the channel");
-                                break;
-                            }
+                        span.record("latency_ms", started.elapsed().as_millis() as u64);
+                        if !finished {
+                            // Dropping `internal_stream` stops the agent's stream. The
+                            // partial answer is not saved to memory.
+                            tracing::debug!("The receiver was dropped, stopping the stream");
+                            let error = LLMError::Stream(ApiError::Cancelled);
+                            options.emit(|handler| handler.on_chain_error(run_id, &error));
+                            return;
                         }
-
-                        // Save to memory
+                        let finish = AgentFinish {
+                            return_values: concatenated_stream_content.clone(),
+                        };
+                        options.emit(|handler| handler.on_agent_finish(run_id, &finish));
+                        options.emit(|handler| {
+                            handler.on_chain_end(run_id, &concatenated_stream_content)
+                        });
                         save_to_memory(&memory_arc_clone, &human_str, &concatenated_stream_content);
-                    });
+                    };
+                    tokio::spawn(task.instrument(run_span.clone()));
 
-                    return Ok(ChainResponse::Stream(rx));
+                    return Ok((ChainResponse::Stream(rx), results));
                 }
             }
 
             if let Some(max_iterations) = max_iterations.as_mut() {
                 *max_iterations -= 1;
                 if *max_iterations == 0 {
-                    return Err("Max iterations reached".into());
+                    return Err(LLMError::MaxIterations(
+                        self.max_iterations.unwrap_or_default(),
+                    ));
                 }
             }
         }
     }
 }
 
+#[async_trait]
+impl ChainTrait for AgentExecutor {
+    async fn run(&self, input: &dyn TemplateArgs) -> Result<ChainResponse, LLMError> {
+        self.run_with_options(input, &RunOptions::default()).await
+    }
+
+    async fn call(&self, input: &dyn TemplateArgs) -> Result<ChatResult, LLMError> {
+        self.call_with_options(input, &RunOptions::default()).await
+    }
+
+    async fn run_with_options(
+        &self,
+        input: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<ChainResponse, LLMError> {
+        let (response, _) = self.execute(input, false, options).await?;
+        Ok(response)
+    }
+
+    // Returns the final answer, with the token usage summed over every step the
+    // agent took. Id, model and finish reason come from the last step.
+    async fn call_with_options(
+        &self,
+        input: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<ChatResult, LLMError> {
+        let (response, results) = self.execute(input, true, options).await?;
+        let text = response.into_text().await.map_err(LLMError::Stream)?;
+
+        let mut result = ChatResult::from_text(&text);
+        for step in &results {
+            if let Some(usage) = step.usage {
+                *result.usage.get_or_insert_with(TokenUsage::default) += usage;
+            }
+        }
+        if let Some(last) = results.last() {
+            result.id = last.id.clone();
+            result.model = last.model.clone();
+            result.system_fingerprint = last.system_fingerprint.clone();
+            result.generations[0].finish_reason = last.finish_reason().map(String::from);
+        }
+        Ok(result)
+    }
+}
+
+// Ends the `agent_run` span and the run of the executor with `error`.
+fn end_with_error(
+    span: &Span,
+    started: Instant,
+    run_id: Uuid,
+    options: &RunOptions,
+    error: &LLMError,
+) {
+    span.record("latency_ms", started.elapsed().as_millis() as u64);
+    span.record("error", field::display(error));
+    span.in_scope(|| tracing::error!("The agent failed: {}", error));
+    options.emit(|handler| handler.on_chain_error(run_id, error));
+}
+
+// The human message saved to memory, which is the `input` variable.
+fn human_input(input: &dyn TemplateArgs) -> Result<String, LLMError> {
+    input
+        .clone_as_map()
+        .get("input")
+        .and_then(|human| human.as_str())
+        .map(String::from)
+        .ok_or_else(|| PromptError::DataNotProvided(String::from("input")).into())
+}
+
 fn save_to_memory(
     memory_arc_clone: &Option<Arc<RwLock<dyn BaseChatMessageHistory>>>,
     human_message: &str,
-    concatenated_stream_content: &String,
+    concatenated_stream_content: &str,
 ) {
     if let Some(memory_arc) = memory_arc_clone {
         if let Ok(mut memory_guard) = memory_arc.write() {
             memory_guard.add_message(Box::new(HumanMessage::new(human_message)));
             memory_guard.add_message(Box::new(AIMessage::new(concatenated_stream_content)));
         } else {
-            eprintln!("Failed to acquire write lock for memory");
+            tracing::error!("Failed to acquire write lock for memory");
         }
     }
 }
@@ -35,7 +35,6 @@ impl TextractService {
                     name: Some(key.clone()),
                     ..Default::default()
                 }),
-                ..Default::default()
             },
             ..Default::default()
         };
@@ -44,9 +43,7 @@ impl TextractService {
             .client
             .start_document_text_detection(request)
             .await
-            .map_err(|e| {
-                ApiError::AWSError(AWSError::new_server_error(String::from(e.to_string())))
-            })?;
+            .map_err(|e| ApiError::AWSError(AWSError::new_server_error(e.to_string())))?;
         let job_id = start_response.job_id.unwrap();
 
         let mut status = "IN_PROGRESS".to_string();
@@ -65,9 +62,7 @@ impl TextractService {
                     .client
                     .get_document_text_detection(result_request)
                     .await
-                    .map_err(|e| {
-                        ApiError::AWSError(AWSError::new_server_error(String::from(e.to_string())))
-                    })?;
+                    .map_err(|e| ApiError::AWSError(AWSError::new_server_error(e.to_string())))?;
 
                 status = result_response.job_status.unwrap();
                 blocks.extend(result_response.blocks.unwrap_or_else(Vec::new));
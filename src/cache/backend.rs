@@ -0,0 +1,152 @@
+use std::{
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use lru::LruCache;
+use serde_json::Value;
+
+/// Where cached responses are kept. Keys are the hex digests built by
+/// `cache_key`. A backend that fails to read or write treats it as a miss, so a
+/// broken cache never fails a model call.
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    async fn get(&self, key: &str) -> Option<Value>;
+
+    async fn set(&self, key: &str, value: Value);
+}
+
+/// Keeps the `capacity` most recently used entries in memory.
+pub struct InMemoryCache {
+    entries: Mutex<LruCache<String, Value>>,
+}
+
+impl InMemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+#[async_trait]
+impl CacheBackend for InMemoryCache {
+    async fn get(&self, key: &str) -> Option<Value> {
+        let mut entries = self.entries.lock().ok()?;
+        entries.get(key).cloned()
+    }
+
+    async fn set(&self, key: &str, value: Value) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.put(key.to_string(), value);
+        }
+    }
+}
+
+// Makes the names of temporary files unique within the process
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Stores each entry as `<key>.json` in a directory, so the cache survives
+/// restarts and can be shared by several processes. Entries are written to a
+/// temporary file first and renamed, so readers never see a partial file.
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+#[async_trait]
+impl CacheBackend for DiskCache {
+    async fn get(&self, key: &str) -> Option<Value> {
+        let contents = match tokio::fs::read(self.path(key)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                log::warn!("Could not read cache entry {}: {}", key, e);
+                return None;
+            }
+        };
+        match serde_json::from_slice(&contents) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::warn!("Ignoring corrupt cache entry {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    async fn set(&self, key: &str, value: Value) {
+        let temp_path = self.dir.join(format!(
+            ".{}.{}.{}.tmp",
+            key,
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = async {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            tokio::fs::write(&temp_path, value.to_string()).await?;
+            tokio::fs::rename(&temp_path, self.path(key)).await
+        }
+        .await;
+        if let Err(e) = result {
+            log::warn!("Could not write cache entry {}: {}", key, e);
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_cache_evicts_least_recently_used() {
+        let cache = InMemoryCache::new(2);
+        cache.set("a", json!(1)).await;
+        cache.set("b", json!(2)).await;
+        assert_eq!(cache.get("a").await, Some(json!(1)));
+        cache.set("c", json!(3)).await;
+
+        assert_eq!(cache.get("b").await, None);
+        assert_eq!(cache.get("a").await, Some(json!(1)));
+        assert_eq!(cache.get("c").await, Some(json!(3)));
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_round_trip() {
+        let dir = std::env::temp_dir().join(format!("llm_rust_cache_{}", std::process::id()));
+        let cache = DiskCache::new(&dir);
+        assert_eq!(cache.get("missing").await, None);
+
+        cache.set("key", json!({"text": "hello"})).await;
+        // A new instance on the same directory sees the entry
+        assert_eq!(
+            DiskCache::new(&dir).get("key").await,
+            Some(json!({"text": "hello"}))
+        );
+
+        tokio::fs::write(dir.join("corrupt.json"), "{")
+            .await
+            .unwrap();
+        assert_eq!(cache.get("corrupt").await, None);
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    chat_models::chat_model_trait::ChatTrait,
+    errors::ApiError,
+    schemas::{
+        llm::{ChatChunk, ChatResult, ChatStream, LlmResponse},
+        messages::BaseMessage,
+    },
+};
+
+use super::{backend::CacheBackend, cache_key};
+
+#[derive(Serialize, Deserialize)]
+enum CachedResponse {
+    Chat(ChatResult),
+    Stream(Vec<ChatChunk>),
+}
+
+/// Answers repeated requests from a cache instead of the model. The key covers
+/// the `identifying_params` of the model and the messages, so models that do not
+/// override `identifying_params` should not share a backend.
+///
+/// Streams are stored once they complete without error and replayed as streams
+/// on a hit.
+pub struct CachedChat {
+    inner: Box<dyn ChatTrait>,
+    cache: Arc<dyn CacheBackend>,
+}
+
+impl CachedChat {
+    pub fn new(inner: Box<dyn ChatTrait>, cache: Arc<dyn CacheBackend>) -> Self {
+        Self { inner, cache }
+    }
+
+    fn key(&self, messages: &[Box<dyn BaseMessage>]) -> String {
+        cache_key(&json!({
+            "kind": "chat",
+            "model": self.inner.identifying_params(),
+            "messages": messages,
+        }))
+    }
+}
+
+#[async_trait]
+impl ChatTrait for CachedChat {
+    async fn invoke(&self, messages: Vec<Box<dyn BaseMessage>>) -> Result<LlmResponse, ApiError> {
+        let key = self.key(&messages);
+        if let Some(value) = self.cache.get(&key).await {
+            match serde_json::from_value(value) {
+                Ok(CachedResponse::Chat(result)) => return Ok(LlmResponse::Chat(result)),
+                Ok(CachedResponse::Stream(chunks)) => {
+                    return Ok(LlmResponse::Stream(
+                        stream::iter(chunks.into_iter().map(Ok)).boxed(),
+                    ))
+                }
+                Err(e) => log::warn!("Ignoring unreadable cache entry {}: {}", key, e),
+            }
+        }
+
+        match self.inner.invoke(messages).await? {
+            LlmResponse::Chat(result) => {
+                if let Ok(value) = serde_json::to_value(CachedResponse::Chat(result.clone())) {
+                    self.cache.set(&key, value).await;
+                }
+                Ok(LlmResponse::Chat(result))
+            }
+            LlmResponse::Stream(chat_stream) => Ok(LlmResponse::Stream(record_stream(
+                chat_stream,
+                self.cache.clone(),
+                key,
+            ))),
+        }
+    }
+
+    fn identifying_params(&self) -> Value {
+        self.inner.identifying_params()
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        self.inner.context_window()
+    }
+
+    fn max_tokens(&self) -> Option<u32> {
+        self.inner.max_tokens()
+    }
+}
+
+// Hands the chunks on as they arrive and stores them when the stream ends.
+// Nothing is stored if the stream fails or is dropped before its end.
+fn record_stream(inner: ChatStream, cache: Arc<dyn CacheBackend>, key: String) -> ChatStream {
+    stream::unfold(Some((inner, Vec::new())), move |state| {
+        let cache = cache.clone();
+        let key = key.clone();
+        async move {
+            let (mut inner, mut chunks) = state?;
+            match inner.next().await {
+                Some(Ok(chunk)) => {
+                    chunks.push(chunk.clone());
+                    Some((Ok(chunk), Some((inner, chunks))))
+                }
+                Some(Err(e)) => Some((Err(e), None)),
+                None => {
+                    if let Ok(value) = serde_json::to_value(CachedResponse::Stream(chunks)) {
+                        cache.set(&key, value).await;
+                    }
+                    None
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{cache::InMemoryCache, schemas::messages::HumanMessage};
+
+    use super::*;
+
+    struct CountingChat {
+        stream: bool,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ChatTrait for CountingChat {
+        async fn invoke(
+            &self,
+            messages: Vec<Box<dyn BaseMessage>>,
+        ) -> Result<LlmResponse, ApiError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let text = format!("echo: {}", messages[0].get_content());
+            if self.stream {
+                return Ok(LlmResponse::Stream(
+                    stream::iter(vec![
+                        Ok(ChatChunk::Content(text)),
+                        Ok(ChatChunk::Finish(String::from("stop"))),
+                    ])
+                    .boxed(),
+                ));
+            }
+            Ok(LlmResponse::Chat(ChatResult::from_text(&text)))
+        }
+
+        fn identifying_params(&self) -> Value {
+            json!({ "model": "counting", "stream": self.stream })
+        }
+    }
+
+    fn cached(stream: bool, calls: &Arc<AtomicUsize>) -> CachedChat {
+        CachedChat::new(
+            Box::new(CountingChat {
+                stream,
+                calls: calls.clone(),
+            }),
+            Arc::new(InMemoryCache::new(10)),
+        )
+    }
+
+    fn messages(text: &str) -> Vec<Box<dyn BaseMessage>> {
+        vec![Box::new(HumanMessage::new(text))]
+    }
+
+    #[tokio::test]
+    async fn test_repeated_request_is_answered_from_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let chat = cached(false, &calls);
+        for _ in 0..2 {
+            match chat.invoke(messages("hi")).await {
+                Ok(LlmResponse::Chat(result)) => assert_eq!(result.text(), "echo: hi"),
+                _ => panic!("expected a chat result"),
+            }
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        chat.invoke(messages("bye")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cached_stream_replays_as_stream() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let chat = cached(true, &calls);
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            let Ok(LlmResponse::Stream(chat_stream)) = chat.invoke(messages("hi")).await else {
+                panic!("expected a stream");
+            };
+            let chunks: Vec<ChatChunk> = chat_stream.map(|chunk| chunk.unwrap()).collect().await;
+            replies.push(chunks);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(replies[0], replies[1]);
+        assert_eq!(replies[1].len(), 2);
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::{embedding::embedder_trait::Embedder, errors::ApiError};
+
+use super::{backend::CacheBackend, cache_key};
+
+/// Caches embeddings text by text. Documents and queries are kept apart, since
+/// some models embed them differently. Only the documents missing from the cache
+/// are sent to the inner embedder, in a single batch.
+pub struct CachedEmbedder {
+    inner: Box<dyn Embedder>,
+    cache: Arc<dyn CacheBackend>,
+}
+
+impl CachedEmbedder {
+    pub fn new(inner: Box<dyn Embedder>, cache: Arc<dyn CacheBackend>) -> Self {
+        Self { inner, cache }
+    }
+
+    fn key(&self, kind: &str, text: &str) -> String {
+        cache_key(&json!({
+            "kind": kind,
+            "model": self.inner.identifying_params(),
+            "text": text,
+        }))
+    }
+
+    async fn get(&self, key: &str) -> Option<Vec<f64>> {
+        serde_json::from_value(self.cache.get(key).await?).ok()
+    }
+}
+
+#[async_trait]
+impl Embedder for CachedEmbedder {
+    async fn embed_documents(&self, documents: Vec<String>) -> Result<Vec<Vec<f64>>, ApiError> {
+        let mut embeddings = Vec::with_capacity(documents.len());
+        let mut missing = Vec::new();
+        for (index, document) in documents.iter().enumerate() {
+            let key = self.key("document", document);
+            let embedding = self.get(&key).await;
+            if embedding.is_none() {
+                missing.push((index, key));
+            }
+            embeddings.push(embedding);
+        }
+
+        if !missing.is_empty() {
+            let texts = missing
+                .iter()
+                .map(|(index, _)| documents[*index].clone())
+                .collect();
+            let computed = self.inner.embed_documents(texts).await?;
+            if computed.len() != missing.len() {
+                return Err(ApiError::InvalidResponse(format!(
+                    "Expected {} embeddings, got {}",
+                    missing.len(),
+                    computed.len()
+                )));
+            }
+            for ((index, key), embedding) in missing.into_iter().zip(computed) {
+                self.cache.set(&key, json!(embedding)).await;
+                embeddings[index] = Some(embedding);
+            }
+        }
+
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Vec<f64>, ApiError> {
+        let key = self.key("query", text);
+        if let Some(embedding) = self.get(&key).await {
+            return Ok(embedding);
+        }
+        let embedding = self.inner.embed_query(text).await?;
+        self.cache.set(&key, json!(embedding)).await;
+        Ok(embedding)
+    }
+
+    fn identifying_params(&self) -> Value {
+        self.inner.identifying_params()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::cache::InMemoryCache;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct RecordingEmbedder {
+        batches: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    #[async_trait]
+    impl Embedder for RecordingEmbedder {
+        async fn embed_documents(&self, documents: Vec<String>) -> Result<Vec<Vec<f64>>, ApiError> {
+            self.batches.lock().unwrap().push(documents.clone());
+            Ok(documents.iter().map(|d| vec![d.len() as f64]).collect())
+        }
+
+        async fn embed_query(&self, text: &str) -> Result<Vec<f64>, ApiError> {
+            Ok(vec![text.len() as f64, 0.0])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_missing_documents_are_embedded() {
+        let inner = RecordingEmbedder::default();
+        let embedder =
+            CachedEmbedder::new(Box::new(inner.clone()), Arc::new(InMemoryCache::new(10)));
+
+        embedder
+            .embed_documents(vec![String::from("a"), String::from("bb")])
+            .await
+            .unwrap();
+        let embeddings = embedder
+            .embed_documents(vec![
+                String::from("ccc"),
+                String::from("a"),
+                String::from("bb"),
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(embeddings, vec![vec![3.0], vec![1.0], vec![2.0]]);
+        assert_eq!(
+            *inner.batches.lock().unwrap(),
+            vec![
+                vec![String::from("a"), String::from("bb")],
+                vec![String::from("ccc")]
+            ]
+        );
+
+        // A query is not answered with the embedding of the same text as a document
+        assert_eq!(embedder.embed_query("a").await.unwrap(), vec![1.0, 0.0]);
+    }
+}
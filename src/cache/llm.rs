@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::{errors::ApiError, llm::base::BaseLLM};
+
+use super::{backend::CacheBackend, cache_key};
+
+/// Answers repeated prompts from a cache instead of the completion model.
+pub struct CachedLLM {
+    inner: Box<dyn BaseLLM>,
+    cache: Arc<dyn CacheBackend>,
+}
+
+impl CachedLLM {
+    pub fn new(inner: Box<dyn BaseLLM>, cache: Arc<dyn CacheBackend>) -> Self {
+        Self { inner, cache }
+    }
+}
+
+#[async_trait]
+impl BaseLLM for CachedLLM {
+    async fn generate(&self, prompt: String) -> Result<String, ApiError> {
+        let key = cache_key(&json!({
+            "kind": "completion",
+            "model": self.inner.identifying_params(),
+            "prompt": &prompt,
+        }));
+        if let Some(Value::String(text)) = self.cache.get(&key).await {
+            return Ok(text);
+        }
+
+        let text = self.inner.generate(prompt).await?;
+        self.cache.set(&key, Value::String(text.clone())).await;
+        Ok(text)
+    }
+
+    fn identifying_params(&self) -> Value {
+        self.inner.identifying_params()
+    }
+}
@@ -0,0 +1,65 @@
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+pub mod backend;
+pub mod chat;
+pub mod embedder;
+pub mod llm;
+pub use backend::{CacheBackend, DiskCache, InMemoryCache};
+pub use chat::CachedChat;
+pub use embedder::CachedEmbedder;
+pub use llm::CachedLLM;
+
+/// A stable key for `value`: the SHA-256 of its JSON, with object keys sorted so
+/// the key does not depend on the order fields were inserted in.
+pub fn cache_key(value: &Value) -> String {
+    let mut hasher = Sha256::new();
+    write_sorted(value, &mut hasher);
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn write_sorted(value: &Value, hasher: &mut Sha256) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            hasher.update(b"{");
+            for (key, value) in entries {
+                hasher.update(Value::String(key.clone()).to_string());
+                hasher.update(b":");
+                write_sorted(value, hasher);
+                hasher.update(b",");
+            }
+            hasher.update(b"}");
+        }
+        Value::Array(values) => {
+            hasher.update(b"[");
+            for value in values {
+                write_sorted(value, hasher);
+                hasher.update(b",");
+            }
+            hasher.update(b"]");
+        }
+        value => hasher.update(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_cache_key_ignores_key_order() {
+        let a = json!({"model": "gpt-4", "temperature": 0.0, "messages": [{"a": 1, "b": 2}]});
+        let b = json!({"messages": [{"b": 2, "a": 1}], "temperature": 0.0, "model": "gpt-4"});
+        assert_eq!(cache_key(&a), cache_key(&b));
+        assert_eq!(cache_key(&a).len(), 64);
+        assert_ne!(cache_key(&a), cache_key(&json!({"model": "gpt-4"})));
+    }
+}
@@ -1,10 +1,42 @@
-use std::error::Error;
-
 use async_trait::async_trait;
 
-use crate::{prompt::TemplateArgs, schemas::chain::ChainResponse};
+use crate::{
+    errors::LLMError,
+    prompt::TemplateArgs,
+    schemas::{chain::ChainResponse, llm::ChatResult, run::RunOptions},
+};
 
 #[async_trait]
 pub trait ChainTrait: Send + Sync {
-    async fn run(&self, input: &dyn TemplateArgs) -> Result<ChainResponse, Box<dyn Error>>;
+    async fn run(&self, input: &dyn TemplateArgs) -> Result<ChainResponse, LLMError>;
+
+    // Runs the chain to completion and returns the full generation result. Chains
+    // that have no usage or finish reason to report only fill in the text.
+    async fn call(&self, input: &dyn TemplateArgs) -> Result<ChatResult, LLMError> {
+        let response = self.run(input).await?;
+        let text = response.into_text().await.map_err(LLMError::Stream)?;
+        Ok(ChatResult::from_text(&text))
+    }
+
+    // Like `run`, stopped when the run is cancelled or past its deadline.
+    async fn run_with_options(
+        &self,
+        input: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<ChainResponse, LLMError> {
+        match options.guard(self.run(input)).await? {
+            ChainResponse::Stream(stream) => {
+                Ok(ChainResponse::Stream(options.guard_receiver(stream)))
+            }
+            response => Ok(response),
+        }
+    }
+
+    async fn call_with_options(
+        &self,
+        input: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<ChatResult, LLMError> {
+        options.guard(self.call(input)).await
+    }
 }
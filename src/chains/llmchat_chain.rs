@@ -1,26 +1,32 @@
 use std::{
-    error::Error,
     sync::{Arc, RwLock},
+    time::Instant,
 };
 
 use async_trait::async_trait;
 use futures::StreamExt;
-use reqwest_eventsource::Event;
 use tokio::sync::mpsc;
+use tracing::{field, Instrument, Span};
 
 use crate::{
     chat_models::chat_model_trait::ChatTrait,
+    errors::{ApiError, LLMError},
     prompt::{BaseChatPromptTemplate, ChatPromptTemplate, TemplateArgs},
     schemas::{
+        callbacks::Uuid,
         chain::ChainResponse,
-        llm::LlmResponse,
+        llm::{ChatChunk, ChatResult, ChatStream, LlmResponse},
         memory::BaseChatMessageHistory,
         messages::{AIMessage, BaseMessage},
-        StreamData,
+        run::RunOptions,
     },
+    tokenizer::Tokenizer,
 };
 
-use super::chain_trait::ChainTrait;
+use super::{
+    chain_trait::ChainTrait,
+    trim::{trim_memory, TrimStrategy, TOKENS_PER_MESSAGE},
+};
 
 //Chat Chain
 pub struct LLMChatChain {
@@ -29,6 +35,8 @@ pub struct LLMChatChain {
     sandwich_prompts: Option<Vec<Box<dyn BaseMessage>>>,
     llm: Box<dyn ChatTrait>,
     pub memory: Option<Arc<RwLock<dyn BaseChatMessageHistory>>>,
+    trim_strategy: Option<TrimStrategy>,
+    context_window: Option<usize>,
 }
 
 impl LLMChatChain {
@@ -39,6 +47,8 @@ impl LLMChatChain {
             memory: None,
             header_prompts: None,
             sandwich_prompts: None,
+            trim_strategy: None,
+            context_window: None,
         }
     }
 
@@ -57,168 +67,485 @@ impl LLMChatChain {
         self
     }
 
+    /// Trims the memory so the conversation and the `max_tokens` of the answer
+    /// fit the context window of the model. Tokens are counted with cl100k_base,
+    /// an estimate for models of other providers.
+    pub fn with_trimming(mut self, strategy: TrimStrategy) -> Self {
+        self.trim_strategy = Some(strategy);
+        self
+    }
+
+    /// Overrides the context window reported by the model, e.g. for a model
+    /// that does not report one.
+    pub fn with_context_window(mut self, tokens: usize) -> Self {
+        self.context_window = Some(tokens);
+        self
+    }
+
+    // Header prompts, then the memory, the sandwich prompts and the formatted
+    // prompt, as one conversation.
     fn order_messages(
         &self,
         prompt_messages: Vec<Box<dyn BaseMessage>>,
-    ) -> Result<Vec<Vec<Box<dyn BaseMessage>>>, Box<dyn Error>> {
-        let mut all_messages: Vec<Vec<Box<dyn BaseMessage>>> = Vec::new();
-
-        if let Some(header) = self.header_prompts.as_ref() {
-            all_messages.push(header.clone());
+    ) -> Result<Vec<Box<dyn BaseMessage>>, LLMError> {
+        let header = self.header_prompts.as_deref().unwrap_or_default();
+        let sandwich = self.sandwich_prompts.as_deref().unwrap_or_default();
+
+        let mut memory = Vec::new();
+        if let Some(memory_arc) = self.memory.as_ref() {
+            let memory_lock = memory_arc
+                .read()
+                .map_err(|_| LLMError::Memory(String::from("Failed to acquire read lock")))?;
+            memory = memory_lock.messages();
+        }
+        if let Some(strategy) = self.trim_strategy {
+            if let Some(available) = self.memory_budget(header, sandwich, &prompt_messages) {
+                memory = trim_memory(memory, available, strategy, &Tokenizer::default());
+            }
         }
 
-        {
-            let memory_messages = if let Some(memory_arc) = self.memory.as_ref() {
-                let memory_lock = memory_arc
-                    .read()
-                    .map_err(|_| "Failed to acquire read lock")?;
-                memory_lock.messages()
-            } else {
-                Vec::new()
-            };
-            all_messages.push(memory_messages);
+        let mut all_messages: Vec<Box<dyn BaseMessage>> = Vec::new();
+        all_messages.extend(header.iter().cloned());
+        all_messages.extend(memory);
+        all_messages.extend(sandwich.iter().cloned());
+        all_messages.extend(prompt_messages);
+
+        Ok(all_messages)
+    }
+
+    // The tokens left for the memory once the messages that are always sent and
+    // the answer are counted, or `None` if the context window is unknown.
+    fn memory_budget(
+        &self,
+        header: &[Box<dyn BaseMessage>],
+        sandwich: &[Box<dyn BaseMessage>],
+        prompt_messages: &[Box<dyn BaseMessage>],
+    ) -> Option<usize> {
+        let Some(window) = self.context_window.or_else(|| self.llm.context_window()) else {
+            tracing::warn!("No context window known for the model, the memory is not trimmed");
+            return None;
+        };
+        let kept = Tokenizer::default().count_conversation(
+            header.iter().chain(sandwich).chain(prompt_messages),
+            TOKENS_PER_MESSAGE,
+        );
+        let reserved = kept + self.llm.max_tokens().unwrap_or_default() as usize;
+        if reserved > window {
+            tracing::warn!(
+                tokens = reserved,
+                context_window = window,
+                "The prompts and the answer alone exceed the context window"
+            );
         }
+        Some(window.saturating_sub(reserved))
+    }
 
-        if let Some(sandwich) = self.sandwich_prompts.as_ref() {
-            all_messages.push(sandwich.clone());
+    // Reports the start of a run of the chain, returning its id, the options for
+    // the model request it makes and its `chain_run` span.
+    fn start_run(
+        &self,
+        inputs: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> (Uuid, RunOptions, Span) {
+        let (run_id, child_options) = options.start_run();
+        if options.has_callbacks() {
+            let inputs = inputs.clone_as_map();
+            options
+                .emit(|handler| handler.on_chain_start(run_id, options.parent_run_id(), &inputs));
         }
+        let span = tracing::info_span!(
+            "chain_run",
+            %run_id,
+            chain = "LLMChatChain",
+            model = %self.llm.identifying_params()["model"].as_str().unwrap_or_default(),
+            latency_ms = field::Empty,
+            error = field::Empty,
+        );
+        (run_id, child_options, span)
+    }
 
-        all_messages.push(prompt_messages);
+    async fn generate(
+        &self,
+        inputs: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<(LlmResponse, Vec<Box<dyn BaseMessage>>), LLMError> {
+        let prompt_value = self.prompt.format_prompt(inputs)?;
+        let prompt_messages = prompt_value.to_chat_messages()?;
+        let all_messages = self.order_messages(prompt_messages.clone())?;
 
-        Ok(all_messages)
+        let response = self.llm.invoke_with_options(all_messages, options).await?;
+        Ok((response, prompt_messages))
     }
 
-    async fn execute(
+    // Forwards the content deltas of the stream to the returned channel, saving
+    // the full answer to memory and closing `span` once the stream is done. A
+    // failed or abandoned answer, or one with tool calls, is not saved. Stops,
+    // dropping the upstream request, as soon as the receiver is dropped.
+    fn forward_stream(
         &self,
+        mut stream: ChatStream,
         prompt_messages: Vec<Box<dyn BaseMessage>>,
-    ) -> Result<ChainResponse, Box<dyn Error>> {
-        let all_messages = self.order_messages(prompt_messages.clone())?;
-
-        let response = self.llm.generate(all_messages).await?;
-        match response {
-            LlmResponse::Text(response) => {
-                if let Some(memory_arc) = &self.memory {
-                    let mut memory_guard = memory_arc
-                        .write()
-                        .map_err(|_| "Failed to acquire write lock")?;
-                    for message in &prompt_messages {
-                        if message.get_type() == String::from("user") {
-                            memory_guard.add_message(message.clone());
+        run_id: Uuid,
+        options: &RunOptions,
+        span: Span,
+        started: Instant,
+    ) -> ChainResponse {
+        let (tx, rx) = mpsc::channel::<Result<String, ApiError>>(100);
+        let memory_arc_clone = self.memory.clone();
+        let options = options.clone();
+
+        let task_span = span.clone();
+        let task = async move {
+            let mut concatenated_stream_content = String::new();
+
+            let finished = loop {
+                let chunk = tokio::select! {
+                    _ = tx.closed() => break false,
+                    chunk = stream.next() => match chunk {
+                        Some(chunk) => chunk,
+                        None => break true,
+                    },
+                };
+                match chunk {
+                    Ok(ChatChunk::Content(content)) => {
+                        concatenated_stream_content.push_str(&content);
+                        // Send just the text delta through the tx channel
+                        if tx.send(Ok(content)).await.is_err() {
+                            break false;
                         }
                     }
-                    memory_guard.add_message(Box::new(AIMessage::new(&response)));
-                }
-
-                return Ok(ChainResponse::Text(response));
-            }
-
-            LlmResponse::Stream(es) => {
-                let (tx, rx) = mpsc::channel::<Result<String, reqwest_eventsource::Error>>(100);
-
-                // Clone needed data
-                let memory_arc_clone = self.memory.clone();
-                let prompt_messages_clone = prompt_messages.clone();
-
-                tokio::spawn(async move {
-                    let mut concatenated_stream_content = String::new();
-                    let mut internal_es = es;
-
-                    while let Some(event) = internal_es.next().await {
-                        match event {
-                            Ok(Event::Message(message)) => {
-                                // Deserialize the JSON data
-                                if let Ok(data) = serde_json::from_str::<StreamData>(&message.data)
-                                {
-                                    // Only concatenate delta["content"]
-                                    if let Some(delta) =
-                                        data.choices.get(0).and_then(|choice| choice.delta.as_ref())
-                                    {
-                                        if let Some(content) = &delta.content {
-                                            concatenated_stream_content.push_str(content);
-                                            // Send just the delta.content through the tx channel
-                                            if let Err(_) = tx.send(Ok(content.clone())).await {
-                                                break;
-                                            }
-                                        }
-                                    }
-
-                                    // Stop the stream when finish_reason is not null
-                                    if data
-                                        .choices
-                                        .get(0)
-                                        .and_then(|choice| choice.finish_reason.as_ref())
-                                        .is_some()
-                                    {
-                                        break;
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Error while processing the stream: {:?}", e);
-                                // Send the error through the tx channel
-                                if let Err(_) = tx.send(Err(e)).await {
-                                    eprintln!("Failed to send the error to the channel");
-                                    break;
-                                }
+                    Ok(ChatChunk::ToolCall(_)) => {
+                        // Like `run`, which only returns text
+                        let error = LLMError::UnexpectedToolCalls;
+                        record_error(&span, started, &error);
+                        options.emit(|handler| handler.on_chain_error(run_id, &error));
+                        let e = ApiError::InvalidResponse(error.to_string());
+                        if tx.send(Err(e)).await.is_err() {
+                            tracing::error!("Failed to send the error to the channel");
+                        }
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        let error = LLMError::Stream(e);
+                        record_error(&span, started, &error);
+                        options.emit(|handler| handler.on_chain_error(run_id, &error));
+                        // Send the error through the tx channel
+                        if let LLMError::Stream(e) = error {
+                            if tx.send(Err(e)).await.is_err() {
+                                tracing::error!("Failed to send the error to the channel");
                             }
-                            // For other event types, you might want to decide how to handle them
-                            _ => {}
                         }
+                        return;
                     }
+                }
+            };
 
-                    // Save to memory
-                    save_to_memory(
-                        &memory_arc_clone,
-                        &prompt_messages_clone,
-                        &concatenated_stream_content,
-                    );
-                });
-
-                Ok(ChainResponse::Stream(rx))
+            span.record("latency_ms", started.elapsed().as_millis() as u64);
+            if !finished {
+                // Nobody reads the answer, so it is not saved to memory either
+                tracing::debug!("The receiver was dropped, stopping the stream");
+                let error = LLMError::Stream(ApiError::Cancelled);
+                options.emit(|handler| handler.on_chain_error(run_id, &error));
+                return;
             }
-        }
+            options.emit(|handler| handler.on_chain_end(run_id, &concatenated_stream_content));
+            save_to_memory(
+                &memory_arc_clone,
+                &prompt_messages,
+                AIMessage::new(&concatenated_stream_content),
+            );
+        };
+        tokio::spawn(task.instrument(task_span));
+
+        ChainResponse::Stream(rx)
     }
 }
 
+fn record_error(span: &Span, started: Instant, error: &LLMError) {
+    span.record("latency_ms", started.elapsed().as_millis() as u64);
+    span.record("error", field::display(error));
+    span.in_scope(|| tracing::error!("The chain failed: {}", error));
+}
+
+fn first_message(result: &ChatResult) -> AIMessage {
+    result
+        .generations
+        .first()
+        .map(|generation| generation.message.clone())
+        .unwrap_or_else(|| AIMessage::new(""))
+}
+
 fn save_to_memory(
     memory_arc_clone: &Option<Arc<RwLock<dyn BaseChatMessageHistory>>>,
     prompt_messages_clone: &Vec<Box<dyn BaseMessage>>,
-    concatenated_stream_content: &String,
+    answer: AIMessage,
 ) {
     if let Some(memory_arc) = memory_arc_clone {
         if let Ok(mut memory_guard) = memory_arc.write() {
             for message in prompt_messages_clone {
-                if message.get_type() == String::from("user") {
+                if message.get_type() == "user" {
                     memory_guard.add_message(message.clone());
                 }
             }
-            memory_guard.add_message(Box::new(AIMessage::new(concatenated_stream_content)));
+            memory_guard.add_message(Box::new(answer));
         } else {
-            eprintln!("Failed to acquire write lock for memory");
+            tracing::error!("Failed to acquire write lock for memory");
         }
     }
 }
 
 #[async_trait]
 impl ChainTrait for LLMChatChain {
-    async fn run(&self, inputs: &dyn TemplateArgs) -> Result<ChainResponse, Box<dyn Error>> {
-        let prompt_value = self.prompt.format_prompt(inputs)?;
-        let prompt_messages = prompt_value.to_chat_messages()?;
-        Ok(self.execute(prompt_messages).await?)
+    async fn run(&self, inputs: &dyn TemplateArgs) -> Result<ChainResponse, LLMError> {
+        self.run_with_options(inputs, &RunOptions::default()).await
+    }
+
+    async fn call(&self, inputs: &dyn TemplateArgs) -> Result<ChatResult, LLMError> {
+        self.call_with_options(inputs, &RunOptions::default()).await
+    }
+
+    async fn run_with_options(
+        &self,
+        inputs: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<ChainResponse, LLMError> {
+        let (run_id, child_options, span) = self.start_run(inputs, options);
+        let started = Instant::now();
+        let response = async {
+            let (response, prompt_messages) = self
+                .generate(inputs, &child_options)
+                .instrument(span.clone())
+                .await?;
+            match response {
+                LlmResponse::Chat(result) => {
+                    if !result.tool_calls().is_empty() {
+                        return Err(LLMError::UnexpectedToolCalls);
+                    }
+                    let message = first_message(&result);
+                    save_to_memory(&self.memory, &prompt_messages, message.clone());
+                    Ok(ChainResponse::Text(message.content))
+                }
+                LlmResponse::Stream(stream) => Ok(self.forward_stream(
+                    stream,
+                    prompt_messages,
+                    run_id,
+                    options,
+                    span.clone(),
+                    started,
+                )),
+            }
+        }
+        .await;
+        match &response {
+            Ok(ChainResponse::Text(text)) => {
+                span.record("latency_ms", started.elapsed().as_millis() as u64);
+                options.emit(|handler| handler.on_chain_end(run_id, text))
+            }
+            // Reported by `forward_stream` once the stream is done
+            Ok(ChainResponse::Stream(_)) => {}
+            Err(e) => {
+                record_error(&span, started, e);
+                options.emit(|handler| handler.on_chain_error(run_id, e))
+            }
+        }
+        response
+    }
+
+    async fn call_with_options(
+        &self,
+        inputs: &dyn TemplateArgs,
+        options: &RunOptions,
+    ) -> Result<ChatResult, LLMError> {
+        let (run_id, child_options, span) = self.start_run(inputs, options);
+        let started = Instant::now();
+        let result = async {
+            let (response, prompt_messages) = self.generate(inputs, &child_options).await?;
+            let result = match response {
+                LlmResponse::Chat(result) => result,
+                LlmResponse::Stream(stream) => ChatResult::from_stream(stream)
+                    .await
+                    .map_err(LLMError::Stream)?,
+            };
+            save_to_memory(&self.memory, &prompt_messages, first_message(&result));
+            Ok(result)
+        }
+        .instrument(span.clone())
+        .await;
+        match &result {
+            Ok(result) => {
+                span.record("latency_ms", started.elapsed().as_millis() as u64);
+                options.emit(|handler| handler.on_chain_end(run_id, &result.text()))
+            }
+            Err(e) => {
+                record_error(&span, started, e);
+                options.emit(|handler| handler.on_chain_error(run_id, e))
+            }
+        }
+        result
     }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use futures::stream;
+
     use crate::{
         chains::llmchat_chain::LLMChatChain,
-        chat_models::openai::chat_llm::ChatOpenAI,
+        chat_models::fake::FakeChatModel,
         prompt::{HumanMessagePromptTemplate, MessageLike, PromptTemplate},
-        schemas::messages::{AIMessage, SystemMessage},
+        schemas::{
+            messages::{AIMessage, HumanMessage, SystemMessage, ToolCall},
+            run::CancellationToken,
+        },
     };
 
     use super::*;
+
+    // Streams one chunk, then hangs. Flags when the stream is dropped.
+    struct HangingChat {
+        dropped: Arc<AtomicBool>,
+    }
+    struct DropFlag(Arc<AtomicBool>);
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+    #[async_trait]
+    impl ChatTrait for HangingChat {
+        async fn invoke(
+            &self,
+            _messages: Vec<Box<dyn BaseMessage>>,
+        ) -> Result<LlmResponse, ApiError> {
+            let flag = DropFlag(self.dropped.clone());
+            let chunks = stream::iter(vec![Ok(ChatChunk::Content(String::from("Hi")))])
+                .chain(stream::pending())
+                .map(move |chunk| {
+                    let _ = &flag;
+                    chunk
+                });
+            Ok(LlmResponse::Stream(chunks.boxed()))
+        }
+    }
+
+    fn hanging_chain(dropped: &Arc<AtomicBool>) -> LLMChatChain {
+        let prompt = ChatPromptTemplate::from_messages(vec![MessageLike::base_prompt_template(
+            HumanMessagePromptTemplate::new(PromptTemplate::from_template("{{input}}")),
+        )]);
+        LLMChatChain::new(
+            prompt,
+            Box::new(HangingChat {
+                dropped: dropped.clone(),
+            }),
+        )
+    }
+
+    async fn wait_for(flag: &AtomicBool) -> bool {
+        for _ in 0..100 {
+            if flag.load(Ordering::SeqCst) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_stream_stops_when_receiver_is_dropped_or_cancelled() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let chain = hanging_chain(&dropped);
+        let Ok(ChainResponse::Stream(mut rx)) = chain.run(&"hello".to_string()).await else {
+            panic!("expected a stream");
+        };
+        assert_eq!(rx.recv().await.unwrap().unwrap(), "Hi");
+        drop(rx);
+        assert!(wait_for(&dropped).await);
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let chain = hanging_chain(&dropped);
+        let token = CancellationToken::new();
+        let options = RunOptions::new().with_cancellation_token(token.clone());
+        let Ok(ChainResponse::Stream(mut rx)) =
+            chain.run_with_options(&"hello".to_string(), &options).await
+        else {
+            panic!("expected a stream");
+        };
+        assert_eq!(rx.recv().await.unwrap().unwrap(), "Hi");
+        token.cancel();
+        assert!(matches!(rx.recv().await, Some(Err(ApiError::Cancelled))));
+        assert!(rx.recv().await.is_none());
+        assert!(wait_for(&dropped).await);
+    }
+    // Streams one chunk, then fails
+    struct FailingChat;
+    #[async_trait]
+    impl ChatTrait for FailingChat {
+        async fn invoke(
+            &self,
+            _messages: Vec<Box<dyn BaseMessage>>,
+        ) -> Result<LlmResponse, ApiError> {
+            let chunks = stream::iter(vec![
+                Ok(ChatChunk::Content(String::from("Half an"))),
+                Err(ApiError::Timeout(String::from("The stream stalled"))),
+            ]);
+            Ok(LlmResponse::Stream(chunks.boxed()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_stream_is_not_saved_to_memory() {
+        let prompt = ChatPromptTemplate::from_messages(vec![MessageLike::base_prompt_template(
+            HumanMessagePromptTemplate::new(PromptTemplate::from_template("{{input}}")),
+        )]);
+        let memory = Arc::new(RwLock::new(InMemoryChatHistory {
+            messages: vec![Box::new(AIMessage::new("Hello"))],
+        }));
+        let chain = LLMChatChain::new(prompt, Box::new(FailingChat)).with_memory(memory.clone());
+
+        let Ok(ChainResponse::Stream(mut rx)) = chain.run(&"hello".to_string()).await else {
+            panic!("expected a stream");
+        };
+        assert_eq!(rx.recv().await.unwrap().unwrap(), "Half an");
+        assert!(matches!(rx.recv().await, Some(Err(ApiError::Timeout(_)))));
+        assert!(rx.recv().await.is_none());
+        assert_eq!(memory.read().unwrap().messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_tool_calls_are_an_error() {
+        let prompt = ChatPromptTemplate::from_messages(vec![MessageLike::base_prompt_template(
+            HumanMessagePromptTemplate::new(PromptTemplate::from_template("{{input}}")),
+        )]);
+        let mut result = ChatResult::from_text("");
+        result.generations[0].message = AIMessage::new("").with_tool_calls(vec![ToolCall::new(
+            "call_1",
+            "search",
+            "{\"q\":\"rust\"}",
+        )]);
+        let chat = FakeChatModel::from_results(vec![result]).with_stream();
+        let memory = Arc::new(RwLock::new(InMemoryChatHistory {
+            messages: Vec::new(),
+        }));
+        let chain = LLMChatChain::new(prompt, Box::new(chat)).with_memory(memory.clone());
+
+        let Ok(ChainResponse::Stream(mut rx)) = chain.run(&"hello".to_string()).await else {
+            panic!("expected a stream");
+        };
+        assert!(matches!(
+            rx.recv().await,
+            Some(Err(ApiError::InvalidResponse(_)))
+        ));
+        assert!(rx.recv().await.is_none());
+        assert!(memory.read().unwrap().messages.is_empty());
+    }
+
     struct InMemoryChatHistory {
         messages: Vec<Box<dyn BaseMessage>>,
     }
@@ -239,7 +566,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_llmchain_run_with_string() {
-        let chat_openai = ChatOpenAI::default().with_stream();
+        let chat = FakeChatModel::new(vec!["ARRRGGGG, hola luis, me gusta el chocolate"])
+            .with_stream()
+            .with_chunk_size(5);
         let prompt_template = ChatPromptTemplate::from_messages(vec![
             MessageLike::base_message(SystemMessage::new(
                 "eres un assistente, que siempre responde como pirata diciendo ARRRGGGG",
@@ -256,42 +585,80 @@ mod tests {
         }));
 
         let llm_chain =
-            LLMChatChain::new(prompt_template, Box::new(chat_openai)).with_memory(memory.clone());
-        let result = llm_chain.run(&"luis".to_string()).await;
-
-        match result {
-            Ok(ChainResponse::Text(text)) => {
-                println!("Returned text: {}", text);
-            }
-            Ok(ChainResponse::Stream(mut stream)) => {
-                println!("Returned stream:");
-                while let Some(event_result) = stream.recv().await {
-                    match event_result {
-                        Ok(message) => {
-                            println!("Streamed message: {:#?}", message);
-                        }
-                        Err(err) => {
-                            println!("Error in stream: {}", err);
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                println!("Error returned: {:?}", e);
-            }
+            LLMChatChain::new(prompt_template, Box::new(chat.clone())).with_memory(memory.clone());
+        let Ok(ChainResponse::Stream(mut stream)) = llm_chain.run(&"luis".to_string()).await else {
+            panic!("expected a stream");
+        };
+        let mut text = String::new();
+        while let Some(delta) = stream.recv().await {
+            text.push_str(&delta.unwrap());
         }
+        assert_eq!(text, "ARRRGGGG, hola luis, me gusta el chocolate");
+
+        // The memory comes before the formatted prompt
+        let calls = chat.calls();
+        let contents: Vec<String> = calls[0].iter().map(|m| m.get_content()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                "Siempre tengo que mencionar que me gusta el chocolate",
+                "eres un assistente, que siempre responde como pirata diciendo ARRRGGGG",
+                "Mi nombre es luis",
+            ]
+        );
+
+        let memory = memory.read().unwrap();
+        let saved: Vec<(String, String)> = memory
+            .messages
+            .iter()
+            .map(|m| (m.get_type(), m.get_content()))
+            .collect();
+        assert_eq!(saved.len(), 3);
+        assert_eq!(
+            saved[1],
+            (String::from("user"), String::from("Mi nombre es luis"))
+        );
+        assert_eq!(saved[2].1, text);
+    }
 
-        if let Ok(memory_lock) = memory.read() {
-            println!("Contents of the memory:");
-            for message in memory_lock.messages.iter() {
-                println!(
-                    "Type: {}, Content: {}",
-                    message.get_type(),
-                    message.get_content()
-                );
-            }
-        } else {
-            println!("Failed to acquire a read lock on the memory.");
-        };
+    #[tokio::test]
+    async fn test_trims_the_oldest_memory_to_the_context_window() {
+        let chat = FakeChatModel::new(vec!["Sure"]);
+        let prompt = ChatPromptTemplate::from_messages(vec![MessageLike::base_prompt_template(
+            HumanMessagePromptTemplate::new(PromptTemplate::from_template("{{input}}")),
+        )]);
+        let history: Vec<Box<dyn BaseMessage>> = (0..20)
+            .map(|i| {
+                Box::new(AIMessage::new(&format!("Answer number {} of many", i)))
+                    as Box<dyn BaseMessage>
+            })
+            .collect();
+        let memory = Arc::new(RwLock::new(InMemoryChatHistory { messages: history }));
+
+        // Room for the header, the prompt and the two newest answers
+        let tokenizer = Tokenizer::default();
+        let header: Vec<Box<dyn BaseMessage>> = vec![Box::new(SystemMessage::new("Be brief"))];
+        let prompt_messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Go on"))];
+        let answer = tokenizer.count_message(&AIMessage::new("Answer number 19 of many"));
+        let window = tokenizer.count_conversation(header.iter().chain(&prompt_messages), 3)
+            + 2 * (answer + 3);
+
+        let chain = LLMChatChain::new(prompt, Box::new(chat.clone()))
+            .with_memory(memory)
+            .with_header_prompts(header)
+            .with_trimming(TrimStrategy::DropOldest)
+            .with_context_window(window);
+        chain.call(&String::from("Go on")).await.unwrap();
+
+        let contents: Vec<String> = chat.calls()[0].iter().map(|m| m.get_content()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                "Be brief",
+                "Answer number 18 of many",
+                "Answer number 19 of many",
+                "Go on"
+            ]
+        );
     }
 }
@@ -1,3 +1,4 @@
 pub mod chain_trait;
 // pub mod llm_chain; //depracated
 pub mod llmchat_chain;
+pub mod trim;
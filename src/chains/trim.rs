@@ -0,0 +1,135 @@
+use crate::{
+    schemas::messages::{AIMessage, BaseMessage, HumanMessage, SystemMessage},
+    tokenizer::Tokenizer,
+};
+
+// OpenAI's overhead per message, close enough for the other providers
+pub(crate) const TOKENS_PER_MESSAGE: usize = 3;
+
+/// How `LLMChatChain` makes a conversation fit the context window of its model.
+/// Only the memory is trimmed, oldest messages first: the header and sandwich
+/// prompts and the new prompt are always sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrimStrategy {
+    // Drops the oldest messages until the rest fits
+    DropOldest,
+    // Drops the oldest messages, then keeps the end of the newest dropped one in
+    // the space left
+    TruncateOldest,
+}
+
+// Keeps the newest messages of `memory` that fit in `available` tokens.
+pub(crate) fn trim_memory(
+    memory: Vec<Box<dyn BaseMessage>>,
+    available: usize,
+    strategy: TrimStrategy,
+    tokenizer: &Tokenizer,
+) -> Vec<Box<dyn BaseMessage>> {
+    let mut left = available;
+    let mut kept: Vec<Box<dyn BaseMessage>> = Vec::new();
+    for message in memory.iter().rev() {
+        let tokens = TOKENS_PER_MESSAGE + tokenizer.count_message(message.as_ref());
+        if tokens <= left {
+            left -= tokens;
+            kept.push(message.clone());
+            continue;
+        }
+        if strategy == TrimStrategy::TruncateOldest {
+            if let Some(truncated) = truncate(message.as_ref(), left, tokenizer) {
+                kept.push(truncated);
+            }
+        }
+        break;
+    }
+    kept.reverse();
+
+    // Tool results whose tool call was dropped are rejected by the APIs
+    let orphans = kept
+        .iter()
+        .take_while(|message| message.get_type() == "tool")
+        .count();
+    kept.drain(..orphans);
+    if kept.len() < memory.len() {
+        tracing::debug!(
+            dropped = memory.len() - kept.len(),
+            kept = kept.len(),
+            "Trimmed the memory to fit the context window"
+        );
+    }
+    kept
+}
+
+// The end of the content of `message` that fits in `tokens`. Only plain text
+// messages are truncated; a piece of a tool call or of an image is useless.
+fn truncate(
+    message: &dyn BaseMessage,
+    tokens: usize,
+    tokenizer: &Tokenizer,
+) -> Option<Box<dyn BaseMessage>> {
+    if !message.get_tool_calls().is_empty() || !message.get_content_parts().is_empty() {
+        return None;
+    }
+    let overhead = TOKENS_PER_MESSAGE + tokenizer.count(&message.get_type());
+    let budget = tokens.checked_sub(overhead).filter(|budget| *budget > 0)?;
+    let content = tokenizer.encode(&message.get_content());
+    let tail = tokenizer.decode(&content[content.len().saturating_sub(budget)..])?;
+    // The cut may fall inside a character
+    let tail = tail.trim_start_matches('\u{FFFD}');
+    match message.get_type().as_str() {
+        "user" => Some(Box::new(HumanMessage::new(tail))),
+        "assistant" => Some(Box::new(AIMessage::new(tail))),
+        "system" => Some(Box::new(SystemMessage::new(tail))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::messages::{ToolCall, ToolMessage};
+
+    use super::*;
+
+    fn contents(messages: &[Box<dyn BaseMessage>]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| message.get_content())
+            .collect()
+    }
+
+    #[test]
+    fn test_keeps_the_newest_messages_that_fit() {
+        let tokenizer = Tokenizer::default();
+        let memory: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(HumanMessage::new("one two three four five six seven eight")),
+            Box::new(AIMessage::new("nine ten")),
+            Box::new(HumanMessage::new("eleven")),
+        ];
+        // "eleven" and "nine ten" take 6 tokens each with the overhead, the role
+        // and overhead of the truncated message 4
+        let kept = trim_memory(memory.clone(), 12, TrimStrategy::DropOldest, &tokenizer);
+        assert_eq!(contents(&kept), vec!["nine ten", "eleven"]);
+
+        let kept = trim_memory(memory.clone(), 18, TrimStrategy::TruncateOldest, &tokenizer);
+        assert_eq!(contents(&kept), vec![" seven eight", "nine ten", "eleven"]);
+        assert_eq!(kept[0].get_type(), "user");
+
+        let kept = trim_memory(memory, 100, TrimStrategy::DropOldest, &tokenizer);
+        assert_eq!(kept.len(), 3);
+    }
+
+    #[test]
+    fn test_drops_tool_results_without_their_call() {
+        let tokenizer = Tokenizer::default();
+        let memory: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(AIMessage::new("").with_tool_calls(vec![ToolCall::new(
+                "call_1",
+                "search",
+                "{\"query\": \"the weather in Lima tomorrow\"}",
+            )])),
+            Box::new(ToolMessage::new("Sunny", "call_1")),
+            Box::new(AIMessage::new("It will be sunny")),
+        ];
+        let kept = trim_memory(memory, 20, TrimStrategy::TruncateOldest, &tokenizer);
+        assert_eq!(contents(&kept), vec!["It will be sunny"]);
+    }
+}
@@ -0,0 +1,145 @@
+use super::message_type::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{anthropic_errors::AnthropicError, ApiError},
+    schemas::llm::{ChatChunk, TokenUsage},
+};
+
+#[derive(Serialize, Debug)]
+pub struct ApiRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub max_tokens: u32,
+    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiResponse {
+    pub id: String,
+    pub model: String,
+    pub content: Vec<ContentBlock>,
+    pub stop_reason: Option<String>,
+    pub usage: ApiUsage,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    MessageStart {
+        message: StreamMessage,
+    },
+    ContentBlockDelta {
+        index: u32,
+        delta: ContentDelta,
+    },
+    MessageDelta {
+        delta: MessageDelta,
+        usage: OutputUsage,
+    },
+    MessageStop,
+    Error {
+        error: StreamError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamMessage {
+    pub usage: ApiUsage,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MessageDelta {
+    pub stop_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OutputUsage {
+    pub output_tokens: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentDelta {
+    TextDelta {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamError {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub message: String,
+}
+
+// Turns Anthropic message events into chunks. The prompt token count is only sent
+// in `message_start`, so it is kept until the final usage is known.
+#[derive(Default)]
+pub struct StreamDecoder {
+    input_tokens: u32,
+}
+impl StreamDecoder {
+    pub fn decode(&mut self, data: &str) -> Result<Vec<ChatChunk>, ApiError> {
+        let event: StreamEvent = serde_json::from_str(data).map_err(|_| {
+            ApiError::AnthropicError(AnthropicError::new_generic_error(format!(
+                "Unexpected stream event: {}",
+                data
+            )))
+        })?;
+
+        match event {
+            StreamEvent::MessageStart { message } => {
+                self.input_tokens = message.usage.input_tokens;
+                Ok(Vec::new())
+            }
+            StreamEvent::ContentBlockDelta {
+                delta: ContentDelta::TextDelta { text },
+                ..
+            } => Ok(vec![ChatChunk::Content(text)]),
+            StreamEvent::MessageDelta { delta, usage } => {
+                let mut chunks = Vec::new();
+                if let Some(stop_reason) = delta.stop_reason {
+                    chunks.push(ChatChunk::Finish(stop_reason));
+                }
+                chunks.push(ChatChunk::Usage(TokenUsage::new(
+                    self.input_tokens,
+                    usage.output_tokens,
+                )));
+                Ok(chunks)
+            }
+            StreamEvent::Error { error } => Err(ApiError::AnthropicError(
+                AnthropicError::from_error_type(200, Some(&error.error_type), error.message),
+            )),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
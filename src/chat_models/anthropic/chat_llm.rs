@@ -0,0 +1,457 @@
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::{
+    chat_models::{
+        anthropic::{
+            anthropic_api::{ApiRequest, ApiResponse, ContentBlock, StreamDecoder},
+            message_type::Message,
+        },
+        chat_model_trait::ChatTrait,
+    },
+    client::{
+        stream::{decode_chunks, sse_data, with_idle_timeout},
+        AnthropicConfig,
+    },
+    errors::{anthropic_errors::AnthropicError, ApiError},
+    schemas::{
+        llm::{ChatGeneration, ChatResult, LlmResponse, TokenUsage},
+        messages::{AIMessage, BaseMessage},
+    },
+};
+
+#[derive(Debug)]
+pub enum AnthropicModel {
+    Claude3Haiku,
+    Claude3Sonnet,
+    Claude3Opus,
+    Claude35Sonnet,
+}
+impl AnthropicModel {
+    pub fn as_str(&self) -> &str {
+        match *self {
+            AnthropicModel::Claude3Haiku => "claude-3-haiku-20240307",
+            AnthropicModel::Claude3Sonnet => "claude-3-sonnet-20240229",
+            AnthropicModel::Claude3Opus => "claude-3-opus-20240229",
+            AnthropicModel::Claude35Sonnet => "claude-3-5-sonnet-20240620",
+        }
+    }
+
+    pub fn context_window(&self) -> usize {
+        match *self {
+            AnthropicModel::Claude3Haiku
+            | AnthropicModel::Claude3Sonnet
+            | AnthropicModel::Claude3Opus
+            | AnthropicModel::Claude35Sonnet => 200_000,
+        }
+    }
+}
+
+// The Messages API requires max_tokens on every request.
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+pub struct ChatAnthropic {
+    pub model: AnthropicModel,
+    pub temperature: f32,
+    pub config: AnthropicConfig,
+    pub max_tokens: u32,
+    pub stream: bool,
+    pub top_p: Option<f32>,
+    pub stop: Vec<String>,
+}
+impl ChatAnthropic {
+    pub fn new(model: AnthropicModel, temperature: f32, anthropic_key: String) -> Self {
+        Self {
+            model,
+            temperature,
+            config: AnthropicConfig::new(anthropic_key),
+            max_tokens: DEFAULT_MAX_TOKENS,
+            stream: false,
+            top_p: None,
+            stop: Vec::new(),
+        }
+    }
+
+    pub fn with_model(mut self, model: AnthropicModel) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn with_stream(mut self) -> Self {
+        self.stream = true;
+        self
+    }
+
+    pub fn with_api_key(mut self, anthropic_key: String) -> Self {
+        self.config.api_key = anthropic_key;
+        self
+    }
+
+    pub fn with_config(mut self, config: AnthropicConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    fn api_request(&self, system: Option<String>, messages: Vec<Message>) -> ApiRequest {
+        ApiRequest {
+            model: String::from(self.model.as_str()),
+            messages,
+            system,
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            stop_sequences: self.stop.clone(),
+            stream: if self.stream { Some(true) } else { None },
+        }
+    }
+}
+impl Default for ChatAnthropic {
+    fn default() -> Self {
+        Self {
+            model: AnthropicModel::Claude3Haiku,
+            temperature: 0.0,
+            config: AnthropicConfig::default(),
+            max_tokens: DEFAULT_MAX_TOKENS,
+            stream: false,
+            top_p: None,
+            stop: Vec::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatTrait for ChatAnthropic {
+    async fn invoke(&self, messages: Vec<Box<dyn BaseMessage>>) -> Result<LlmResponse, ApiError> {
+        let (system, messages) = Message::from_base_messages(messages)?;
+        log::debug!("system: {:?}, messages: {:?}", system, messages);
+
+        let api_request = self.api_request(system, messages);
+        let request = self.config.post("messages")?.json(&api_request);
+
+        let request = if self.stream {
+            request
+        } else {
+            self.config.http.with_request_timeout(request)
+        };
+        let response = self.config.send(request).await?;
+        if self.stream {
+            let map_err =
+                |msg: String| ApiError::AnthropicError(AnthropicError::new_generic_error(msg));
+            let mut decoder = StreamDecoder::default();
+            let chunks = decode_chunks(sse_data(response, map_err), move |data| {
+                decoder.decode(data)
+            });
+            return Ok(LlmResponse::Stream(with_idle_timeout(
+                chunks,
+                self.config.http.read_timeout(),
+            )));
+        }
+
+        let api_response: ApiResponse = response.json().await.map_err(|_| {
+            ApiError::AnthropicError(AnthropicError::new_generic_error(String::from(
+                "Error deserializing response or unknown error",
+            )))
+        })?;
+        log::info!(
+            "Token usage: {:?} for model: {}",
+            api_response.usage,
+            api_response.model
+        );
+
+        let text_resp: String = api_response
+            .content
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text.as_str()),
+                ContentBlock::Other => None,
+            })
+            .collect();
+
+        Ok(LlmResponse::Chat(ChatResult {
+            id: Some(api_response.id),
+            model: api_response.model,
+            system_fingerprint: None,
+            generations: vec![ChatGeneration {
+                index: 0,
+                message: AIMessage::new(&text_resp),
+                finish_reason: api_response.stop_reason,
+                logprobs: Vec::new(),
+            }],
+            usage: Some(TokenUsage::new(
+                api_response.usage.input_tokens,
+                api_response.usage.output_tokens,
+            )),
+        }))
+    }
+
+    fn identifying_params(&self) -> Value {
+        serde_json::to_value(self.api_request(None, Vec::new())).unwrap_or_default()
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        Some(self.model.context_window())
+    }
+
+    fn max_tokens(&self) -> Option<u32> {
+        Some(self.max_tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, RwLock},
+        time::Duration,
+    };
+
+    use futures::StreamExt;
+    use mockito::Matcher;
+    use serde_json::json;
+
+    use crate::{
+        chains::{chain_trait::ChainTrait, llmchat_chain::LLMChatChain},
+        client::{HttpConfig, RetryPolicy},
+        prompt::{ChatPromptTemplate, HumanMessagePromptTemplate, MessageLike, PromptTemplate},
+        schemas::{
+            chain::ChainResponse,
+            llm::ChatChunk,
+            memory::BaseChatMessageHistory,
+            messages::{AIMessage, HumanMessage, SystemMessage},
+        },
+    };
+
+    use super::*;
+
+    fn chat_for(server: &mockito::ServerGuard) -> ChatAnthropic {
+        ChatAnthropic::default().with_config(
+            AnthropicConfig::new(String::from("test-key")).with_api_base(&server.url()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_generate_maps_system_and_merges_turns() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/messages")
+            .match_header("x-api-key", "test-key")
+            .match_header("anthropic-version", "2023-06-01")
+            .match_body(Matcher::PartialJson(json!({
+                "model": "claude-3-haiku-20240307",
+                "max_tokens": 1024,
+                "system": "Be brief.\n\nAnswer in English.",
+                "messages": [
+                    {"role": "user", "content": "Hi"},
+                    {"role": "assistant", "content": "Hello!"},
+                    {"role": "user", "content": "My name is Luis\n\nWhat is my name?"}
+                ]
+            })))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "id": "msg_1",
+                    "type": "message",
+                    "role": "assistant",
+                    "model": "claude-3-haiku-20240307",
+                    "content": [
+                        {"type": "text", "text": "Your name "},
+                        {"type": "text", "text": "is Luis."}
+                    ],
+                    "stop_reason": "end_turn",
+                    "usage": {"input_tokens": 20, "output_tokens": 5}
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let messages: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(SystemMessage::new("Be brief.")),
+            Box::new(HumanMessage::new("Hi")),
+            Box::new(AIMessage::new("Hello!")),
+            Box::new(SystemMessage::new("Answer in English.")),
+            Box::new(HumanMessage::new("My name is Luis")),
+            Box::new(HumanMessage::new("What is my name?")),
+        ];
+
+        let response = chat_for(&server).invoke(messages).await.unwrap();
+
+        mock.assert_async().await;
+        match response {
+            LlmResponse::Chat(result) => {
+                assert_eq!(result.text(), "Your name is Luis.");
+                assert_eq!(result.finish_reason(), Some("end_turn"));
+                assert_eq!(result.usage, Some(TokenUsage::new(20, 5)));
+            }
+            _ => panic!("expected a chat result"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_returns_typed_error() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/messages")
+            .with_status(529)
+            .with_body(
+                json!({
+                    "type": "error",
+                    "error": {"type": "overloaded_error", "message": "Overloaded"}
+                })
+                .to_string(),
+            )
+            .expect(2)
+            .create_async()
+            .await;
+
+        // Overloaded errors are retried before being returned
+        let retry = RetryPolicy::new(2).with_initial_backoff(Duration::from_millis(10));
+        let chat = chat_for(&server).with_config(
+            AnthropicConfig::new(String::from("test-key"))
+                .with_api_base(&server.url())
+                .with_http_config(HttpConfig::new().with_retry_policy(retry)),
+        );
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        let result = chat.invoke(messages).await;
+
+        mock.assert_async().await;
+        match result {
+            Err(ApiError::AnthropicError(AnthropicError::Overloaded { code, detail })) => {
+                assert_eq!(code, 529);
+                assert_eq!(detail, "Overloaded");
+            }
+            _ => panic!("expected an overloaded error"),
+        }
+    }
+
+    struct InMemoryChatHistory {
+        messages: Vec<Box<dyn BaseMessage>>,
+    }
+
+    impl BaseChatMessageHistory for InMemoryChatHistory {
+        fn messages(&self) -> Vec<Box<dyn BaseMessage>> {
+            self.messages.clone()
+        }
+
+        fn add_message(&mut self, message: Box<dyn BaseMessage>) {
+            self.messages.push(message);
+        }
+
+        fn clear(&mut self) {
+            self.messages.clear();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_through_chat_chain() {
+        let mut server = mockito::Server::new_async().await;
+        let body = [
+            r#"{"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"claude-3-haiku-20240307","usage":{"input_tokens":10,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":" Luis"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":2}}"#,
+            r#"{"type":"message_stop"}"#,
+        ]
+        .iter()
+        .map(|data| format!("data: {}\n\n", data))
+        .collect::<String>();
+        server
+            .mock("POST", "/messages")
+            .match_body(Matcher::PartialJson(json!({"stream": true})))
+            .with_status(200)
+            .with_header("content-type", "text/event-stream")
+            .with_body(body)
+            .create_async()
+            .await;
+
+        let prompt_template = ChatPromptTemplate::from_messages(vec![
+            MessageLike::base_message(SystemMessage::new("Greet the user")),
+            MessageLike::base_prompt_template(HumanMessagePromptTemplate::new(
+                PromptTemplate::from_template("My name is {{input}}"),
+            )),
+        ]);
+        let memory = Arc::new(RwLock::new(InMemoryChatHistory {
+            messages: Vec::new(),
+        }));
+        let chain = LLMChatChain::new(prompt_template, Box::new(chat_for(&server).with_stream()))
+            .with_memory(memory.clone());
+
+        let mut stream = match chain.run(&"Luis".to_string()).await.unwrap() {
+            ChainResponse::Stream(stream) => stream,
+            ChainResponse::Text(_) => panic!("expected a stream"),
+        };
+        let mut text = String::new();
+        while let Some(chunk) = stream.recv().await {
+            text.push_str(&chunk.unwrap());
+        }
+
+        assert_eq!(text, "Hello Luis");
+        let messages = memory.read().unwrap().messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].get_content(), "Hello Luis");
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_event_is_typed() {
+        let mut server = mockito::Server::new_async().await;
+        let body = [
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hel"}}"#,
+            r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#,
+        ]
+        .iter()
+        .map(|data| format!("data: {}\n\n", data))
+        .collect::<String>();
+        server
+            .mock("POST", "/messages")
+            .with_status(200)
+            .with_header("content-type", "text/event-stream")
+            .with_body(body)
+            .create_async()
+            .await;
+
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        let mut stream = match chat_for(&server)
+            .with_stream()
+            .invoke(messages)
+            .await
+            .unwrap()
+        {
+            LlmResponse::Stream(stream) => stream,
+            _ => panic!("expected a stream"),
+        };
+
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            ChatChunk::Content(String::from("Hel"))
+        );
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(ApiError::AnthropicError(
+                AnthropicError::Overloaded { .. }
+            )))
+        ));
+    }
+}
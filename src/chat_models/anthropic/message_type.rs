@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{anthropic_errors::AnthropicError, ApiError},
+    schemas::messages::{BaseMessage, ContentPart},
+};
+
+// Opens conversations that start with an assistant turn, e.g. once trimming has
+// dropped the first question, because Anthropic requires a user turn first
+const PLACEHOLDER_TURN: &str = "(The conversation continues.)";
+
+// Anthropic accepts either a plain string or a list of content blocks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+impl MessageContent {
+    fn into_blocks(self) -> Vec<ContentBlock> {
+        match self {
+            MessageContent::Text(text) => vec![ContentBlock::Text { text }],
+            MessageContent::Blocks(blocks) => blocks,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text { text: String },
+    Image { source: ImageSource },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageSource {
+    // Always `base64`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub media_type: String,
+    pub data: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Message {
+    pub role: String,
+    pub content: MessageContent,
+}
+impl Message {
+    pub fn new(role: String, content: String) -> Self {
+        Self {
+            role,
+            content: MessageContent::Text(content),
+        }
+    }
+
+    // Text and base64 images are sent as content blocks. Tool calls, tool results,
+    // files and images behind a URL are rejected rather than left out.
+    fn content(message: &dyn BaseMessage) -> Result<MessageContent, ApiError> {
+        if !message.get_tool_calls().is_empty() || message.get_tool_call_id().is_some() {
+            return Err(invalid_request(
+                "Tool calls and tool results are not supported",
+            ));
+        }
+        let parts = message.get_content_parts();
+        if parts.is_empty() {
+            return Ok(MessageContent::Text(message.get_content()));
+        }
+
+        let mut blocks = Vec::new();
+        let content = message.get_content();
+        if !content.is_empty() {
+            blocks.push(ContentBlock::Text { text: content });
+        }
+        for part in parts {
+            match part {
+                ContentPart::Text { text } => blocks.push(ContentBlock::Text { text }),
+                ContentPart::ImageUrl { image_url } => {
+                    let (media_type, data) = image_url
+                        .url
+                        .strip_prefix("data:")
+                        .and_then(|url| url.split_once(";base64,"))
+                        .ok_or_else(|| {
+                            invalid_request("Images must be base64 encoded, not URLs")
+                        })?;
+                    blocks.push(ContentBlock::Image {
+                        source: ImageSource {
+                            kind: String::from("base64"),
+                            media_type: media_type.to_string(),
+                            data: data.to_string(),
+                        },
+                    });
+                }
+                ContentPart::File { .. } => {
+                    return Err(invalid_request("Files are not supported"));
+                }
+            }
+        }
+        Ok(MessageContent::Blocks(blocks))
+    }
+
+    // Anthropic takes the system prompt as a top-level field and expects turns to
+    // alternate between user and assistant, so consecutive messages with the same
+    // role are merged into a single turn and a placeholder user turn opens
+    // conversations that would start with the assistant.
+    pub fn from_base_messages(
+        messages: Vec<Box<dyn BaseMessage>>,
+    ) -> Result<(Option<String>, Vec<Self>), ApiError> {
+        let mut system: Vec<String> = Vec::new();
+        let mut turns: Vec<Self> = Vec::new();
+
+        for message in messages {
+            let role = match message.get_type().as_str() {
+                "system" => {
+                    system.push(message.get_content());
+                    continue;
+                }
+                "assistant" => "assistant",
+                _ => "user",
+            };
+            let content = Self::content(message.as_ref())?;
+
+            match turns.last_mut() {
+                Some(last) if last.role == role => {
+                    last.content = match (last.content.clone(), content) {
+                        (MessageContent::Text(mut text), MessageContent::Text(next)) => {
+                            text.push_str("\n\n");
+                            text.push_str(&next);
+                            MessageContent::Text(text)
+                        }
+                        (previous, next) => {
+                            let mut blocks = previous.into_blocks();
+                            blocks.extend(next.into_blocks());
+                            MessageContent::Blocks(blocks)
+                        }
+                    };
+                }
+                _ => turns.push(Self {
+                    role: role.to_string(),
+                    content,
+                }),
+            }
+        }
+
+        if turns.first().is_some_and(|turn| turn.role == "assistant") {
+            turns.insert(
+                0,
+                Self::new(String::from("user"), String::from(PLACEHOLDER_TURN)),
+            );
+        }
+
+        let system = if system.is_empty() {
+            None
+        } else {
+            Some(system.join("\n\n"))
+        };
+        Ok((system, turns))
+    }
+}
+
+fn invalid_request(detail: &str) -> ApiError {
+    ApiError::AnthropicError(AnthropicError::InvalidRequest {
+        code: 400,
+        detail: String::from(detail),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::schemas::messages::{AIMessage, HumanMessage, SystemMessage, ToolCall, ToolMessage};
+
+    use super::*;
+
+    #[test]
+    fn test_opens_with_a_user_turn() {
+        let messages: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(SystemMessage::new("Be brief.")),
+            Box::new(AIMessage::new("Hello!")),
+            Box::new(HumanMessage::new("What is my name?")),
+        ];
+        let (system, turns) = Message::from_base_messages(messages).unwrap();
+        assert_eq!(system.as_deref(), Some("Be brief."));
+        let roles: Vec<&str> = turns.iter().map(|turn| turn.role.as_str()).collect();
+        assert_eq!(roles, vec!["user", "assistant", "user"]);
+        assert_eq!(
+            turns[0].content,
+            MessageContent::Text(String::from(PLACEHOLDER_TURN))
+        );
+    }
+
+    #[test]
+    fn test_images_are_sent_as_base64_blocks() {
+        let messages: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(HumanMessage::new("Hi")),
+            Box::new(HumanMessage::new("Describe it").with_content_parts(vec![
+                ContentPart::image_base64("image/png", "iVBORw0KGgo=", None),
+                ContentPart::text("Be brief."),
+            ])),
+        ];
+        let (_, turns) = Message::from_base_messages(messages).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&turns).unwrap(),
+            json!([{
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "Hi"},
+                    {"type": "text", "text": "Describe it"},
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo="}},
+                    {"type": "text", "text": "Be brief."}
+                ]
+            }])
+        );
+    }
+
+    #[test]
+    fn test_rejects_what_anthropic_cannot_take() {
+        let messages: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(
+                HumanMessage::new("Describe it").with_image_url("https://example.com/a.png", None),
+            ),
+            Box::new(
+                HumanMessage::new("Sum it up")
+                    .with_content_parts(vec![ContentPart::file_id("file_1")]),
+            ),
+            Box::new(
+                AIMessage::new("").with_tool_calls(vec![ToolCall::new("call_1", "search", "{}")]),
+            ),
+            Box::new(ToolMessage::new("Sunny", "call_1")),
+        ];
+        for message in messages {
+            assert!(matches!(
+                Message::from_base_messages(vec![message]),
+                Err(ApiError::AnthropicError(
+                    AnthropicError::InvalidRequest { .. }
+                ))
+            ));
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod chat_llm;
+pub use chat_llm::AnthropicModel;
+pub use chat_llm::ChatAnthropic;
+pub(crate) mod anthropic_api;
+mod message_type;
@@ -1,14 +1,268 @@
+use std::time::Instant;
+
 use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use serde_json::Value;
+use tracing::{field, Instrument, Span};
 
 use crate::{
     errors::ApiError,
-    schemas::{llm::LlmResponse, messages::BaseMessage},
+    schemas::{
+        callbacks::watch_llm_stream,
+        llm::{ChatChunk, ChatResult, ChatStream, LlmResponse},
+        messages::BaseMessage,
+        run::RunOptions,
+    },
 };
 
+// Number of conversations of a batch sent at the same time by `generate`.
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 8;
+
 #[async_trait]
 pub trait ChatTrait: Send + Sync {
+    // Sends one conversation to the model.
+    async fn invoke(&self, messages: Vec<Box<dyn BaseMessage>>) -> Result<LlmResponse, ApiError>;
+
+    /// The model and the settings that shape its answers, used in cache keys.
+    fn identifying_params(&self) -> Value {
+        Value::Null
+    }
+
+    /// The tokens the model accepts in one request, prompt and answer together,
+    /// when known.
+    fn context_window(&self) -> Option<usize> {
+        None
+    }
+
+    /// The tokens set aside for the answer, when limited.
+    fn max_tokens(&self) -> Option<u32> {
+        None
+    }
+
+    /// Like `invoke`, stopping the request or the stream when the run is
+    /// cancelled, past its deadline or over its budget, and reporting the run
+    /// to the callbacks and to an `llm_call` tracing span. The span of a stream
+    /// lasts until the stream ends.
+    async fn invoke_with_options(
+        &self,
+        messages: Vec<Box<dyn BaseMessage>>,
+        options: &RunOptions,
+    ) -> Result<LlmResponse, ApiError> {
+        options.check()?;
+        let (run_id, _) = options.start_run();
+        // Streams do not report the model, take it from the settings
+        let model = self.identifying_params()["model"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let span = tracing::info_span!(
+            "llm_call",
+            %run_id,
+            model = %model,
+            messages = messages.len(),
+            prompt = %options.redact(|| format_messages(&messages)),
+            completion = field::Empty,
+            prompt_tokens = field::Empty,
+            completion_tokens = field::Empty,
+            latency_ms = field::Empty,
+            error = field::Empty,
+        );
+        let started = Instant::now();
+        options.emit(|handler| handler.on_llm_start(run_id, options.parent_run_id(), &messages));
+        let response = options
+            .guard(self.invoke(messages))
+            .instrument(span.clone())
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                record_error(&span, started, &e);
+                options.emit(|handler| handler.on_llm_error(run_id, &e));
+                return Err(e);
+            }
+        };
+        match response {
+            LlmResponse::Stream(stream) => {
+                let stream = trace_stream(options.guard_stream(stream), span, started, options);
+                if !options.has_callbacks() {
+                    return Ok(LlmResponse::Stream(stream));
+                }
+                Ok(LlmResponse::Stream(watch_llm_stream(
+                    stream,
+                    run_id,
+                    model,
+                    options.clone(),
+                )))
+            }
+            LlmResponse::Chat(result) => {
+                record_result(&span, started, &result, options);
+                options.emit(|handler| handler.on_llm_end(run_id, &result));
+                Ok(LlmResponse::Chat(result))
+            }
+        }
+    }
+
+    /// Sends each conversation as its own request, `DEFAULT_BATCH_CONCURRENCY`
+    /// at a time. Returns one result per conversation, in input order.
     async fn generate(
         &self,
         messages: Vec<Vec<Box<dyn BaseMessage>>>,
-    ) -> Result<LlmResponse, ApiError>;
+    ) -> Vec<Result<LlmResponse, ApiError>> {
+        self.generate_with_concurrency(messages, DEFAULT_BATCH_CONCURRENCY)
+            .await
+    }
+
+    /// Like `generate`, with at most `concurrency` requests in flight.
+    async fn generate_with_concurrency(
+        &self,
+        messages: Vec<Vec<Box<dyn BaseMessage>>>,
+        concurrency: usize,
+    ) -> Vec<Result<LlmResponse, ApiError>> {
+        // The requests only start when polled, so `buffered` bounds how many
+        // run at once.
+        let requests: Vec<_> = messages
+            .into_iter()
+            .map(|conversation| self.invoke(conversation))
+            .collect();
+        stream::iter(requests)
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+}
+
+fn format_messages(messages: &[Box<dyn BaseMessage>]) -> String {
+    messages
+        .iter()
+        .map(|message| format!("{}: {}", message.get_type(), message.get_content()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn record_result(span: &Span, started: Instant, result: &ChatResult, options: &RunOptions) {
+    span.record("latency_ms", started.elapsed().as_millis() as u64);
+    span.record("completion", options.redact(|| result.text()));
+    if let Some(usage) = result.usage {
+        span.record("prompt_tokens", usage.prompt_tokens);
+        span.record("completion_tokens", usage.completion_tokens);
+    }
+}
+
+fn record_error(span: &Span, started: Instant, error: &ApiError) {
+    span.record("latency_ms", started.elapsed().as_millis() as u64);
+    span.record("error", field::display(error));
+    span.in_scope(|| tracing::error!("The model call failed: {}", error));
+}
+
+// Keeps the span of the call open until the stream ends, then records the
+// assembled answer.
+fn trace_stream(
+    inner: ChatStream,
+    span: Span,
+    started: Instant,
+    options: &RunOptions,
+) -> ChatStream {
+    if span.is_disabled() {
+        return inner;
+    }
+    let options = options.clone();
+    stream::unfold(Some((inner, Vec::new())), move |state| {
+        let span = span.clone();
+        let options = options.clone();
+        async move {
+            let (mut inner, mut chunks) = state?;
+            match inner.next().await {
+                Some(Ok(chunk)) => {
+                    chunks.push(chunk.clone());
+                    Some((Ok(chunk), Some((inner, chunks))))
+                }
+                Some(Err(e)) => {
+                    record_error(&span, started, &e);
+                    Some((Err(e), None))
+                }
+                None => {
+                    let chunks: Vec<Result<ChatChunk, ApiError>> =
+                        chunks.into_iter().map(Ok).collect();
+                    if let Ok(result) = ChatResult::from_stream(stream::iter(chunks).boxed()).await
+                    {
+                        record_result(&span, started, &result, &options);
+                    }
+                    None
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use crate::{
+        errors::openai_errors::OpenaiError,
+        schemas::{llm::ChatResult, messages::HumanMessage},
+    };
+
+    use super::*;
+
+    // Echoes the last message after a delay that shrinks with the input, so
+    // later conversations finish first. "fail" returns an error.
+    #[derive(Default)]
+    struct EchoChat {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+    #[async_trait]
+    impl ChatTrait for EchoChat {
+        async fn invoke(
+            &self,
+            messages: Vec<Box<dyn BaseMessage>>,
+        ) -> Result<LlmResponse, ApiError> {
+            let running = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(running, Ordering::SeqCst);
+            let content = messages.last().unwrap().get_content();
+            tokio::time::sleep(Duration::from_millis(50 - 10 * content.len() as u64)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            if content == "fail" {
+                return Err(ApiError::OpenaiError(OpenaiError::new_generic_error(
+                    content,
+                )));
+            }
+            Ok(LlmResponse::Chat(ChatResult::from_text(&content)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_keeps_order_and_per_item_errors() {
+        let chat = EchoChat::default();
+        let batch: Vec<Vec<Box<dyn BaseMessage>>> = ["a", "bb", "fail", "ccc"]
+            .iter()
+            .map(|text| vec![Box::new(HumanMessage::new(text)) as Box<dyn BaseMessage>])
+            .collect();
+
+        let results = chat.generate_with_concurrency(batch, 2).await;
+
+        let texts: Vec<Option<String>> = results
+            .into_iter()
+            .map(|result| match result {
+                Ok(LlmResponse::Chat(result)) => Some(result.text()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                Some(String::from("a")),
+                Some(String::from("bb")),
+                None,
+                Some(String::from("ccc"))
+            ]
+        );
+        assert_eq!(chat.max_in_flight.load(Ordering::SeqCst), 2);
+    }
 }
@@ -0,0 +1,248 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use serde_json::{json, Value};
+
+use crate::{
+    errors::ApiError,
+    schemas::{
+        llm::{ChatChunk, ChatResult, LlmResponse, TokenUsage},
+        messages::BaseMessage,
+        tools::{FunctionCallDelta, ToolCallDelta},
+    },
+};
+
+use super::chat_model_trait::ChatTrait;
+
+type Calls = Arc<Mutex<Vec<Vec<Box<dyn BaseMessage>>>>>;
+
+/// A chat model for tests that plays back scripted responses, cycling through
+/// them, and records the messages of every call. Clones share the script and
+/// the recorded calls, so keep one to inspect the calls after handing the model
+/// to a chain or an agent.
+///
+/// With `with_stream()` the responses are sent as streams: the content word by
+/// word, or in chunks of `with_chunk_size` characters, then the tool calls, the
+/// finish reason and the usage.
+#[derive(Clone)]
+pub struct FakeChatModel {
+    responses: Arc<Vec<ChatResult>>,
+    next: Arc<AtomicUsize>,
+    calls: Calls,
+    stream: bool,
+    chunk_size: Option<usize>,
+    usage: Option<TokenUsage>,
+}
+
+impl FakeChatModel {
+    pub fn new(responses: Vec<&str>) -> Self {
+        Self::from_results(
+            responses
+                .into_iter()
+                .map(|text| {
+                    let mut result = ChatResult::from_text(text);
+                    result.generations[0].finish_reason = Some(String::from("stop"));
+                    result
+                })
+                .collect(),
+        )
+    }
+
+    /// Plays back complete results, e.g. with tool calls or several generations.
+    pub fn from_results(responses: Vec<ChatResult>) -> Self {
+        let responses = responses
+            .into_iter()
+            .map(|mut result| {
+                if result.model.is_empty() {
+                    result.model = String::from("fake");
+                }
+                result
+            })
+            .collect();
+        Self {
+            responses: Arc::new(responses),
+            next: Arc::new(AtomicUsize::new(0)),
+            calls: Arc::new(Mutex::new(Vec::new())),
+            stream: false,
+            chunk_size: None,
+            usage: None,
+        }
+    }
+
+    pub fn with_stream(mut self) -> Self {
+        self.stream = true;
+        self
+    }
+
+    /// Streams the content in chunks of `chunk_size` characters instead of words.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size.max(1));
+        self
+    }
+
+    /// Usage reported by the responses that do not set their own.
+    pub fn with_usage(mut self, usage: TokenUsage) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    /// The messages of every call so far, in order.
+    pub fn calls(&self) -> Vec<Vec<Box<dyn BaseMessage>>> {
+        self.calls
+            .lock()
+            .map(|calls| calls.clone())
+            .unwrap_or_default()
+    }
+
+    fn chunks(&self, result: ChatResult) -> Vec<ChatChunk> {
+        let text = result.text();
+        let mut chunks: Vec<ChatChunk> = match self.chunk_size {
+            Some(size) => text
+                .chars()
+                .collect::<Vec<char>>()
+                .chunks(size)
+                .map(|chunk| ChatChunk::Content(chunk.iter().collect()))
+                .collect(),
+            None => text
+                .split_inclusive(' ')
+                .map(|word| ChatChunk::Content(word.to_string()))
+                .collect(),
+        };
+        for (index, tool_call) in result.tool_calls().into_iter().enumerate() {
+            chunks.push(ChatChunk::ToolCall(ToolCallDelta {
+                index,
+                id: Some(tool_call.id),
+                function: Some(FunctionCallDelta {
+                    name: Some(tool_call.function.name),
+                    arguments: Some(tool_call.function.arguments),
+                }),
+            }));
+        }
+        if let Some(reason) = result.finish_reason() {
+            chunks.push(ChatChunk::Finish(reason.to_string()));
+        }
+        if let Some(usage) = result.usage {
+            chunks.push(ChatChunk::Usage(usage));
+        }
+        chunks
+    }
+}
+
+#[async_trait]
+impl ChatTrait for FakeChatModel {
+    async fn invoke(&self, messages: Vec<Box<dyn BaseMessage>>) -> Result<LlmResponse, ApiError> {
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.push(messages);
+        }
+        if self.responses.is_empty() {
+            return Err(ApiError::InvalidResponse(String::from(
+                "FakeChatModel has no scripted responses",
+            )));
+        }
+        let index = self.next.fetch_add(1, Ordering::SeqCst) % self.responses.len();
+        let mut result = self.responses[index].clone();
+        if result.usage.is_none() {
+            result.usage = self.usage;
+        }
+
+        if self.stream {
+            let chunks = self.chunks(result);
+            return Ok(LlmResponse::Stream(
+                stream::iter(chunks.into_iter().map(Ok)).boxed(),
+            ));
+        }
+        Ok(LlmResponse::Chat(result))
+    }
+
+    fn identifying_params(&self) -> Value {
+        json!({ "model": "fake" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::messages::{AIMessage, HumanMessage, ToolCall};
+
+    use super::*;
+
+    fn messages(text: &str) -> Vec<Box<dyn BaseMessage>> {
+        vec![Box::new(HumanMessage::new(text))]
+    }
+
+    #[tokio::test]
+    async fn test_plays_back_responses_and_records_calls() {
+        let model = FakeChatModel::new(vec!["first", "second"]);
+        let recorder = model.clone();
+        let mut answers = Vec::new();
+        for question in ["a", "b", "c"] {
+            match model.invoke(messages(question)).await {
+                Ok(LlmResponse::Chat(result)) => answers.push(result.text()),
+                _ => panic!("expected a chat result"),
+            }
+        }
+        assert_eq!(answers, vec!["first", "second", "first"]);
+
+        let calls = recorder.calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[1][0].get_content(), "b");
+    }
+
+    #[tokio::test]
+    async fn test_streams_in_configurable_chunks() {
+        let model = FakeChatModel::new(vec!["hello big world"])
+            .with_stream()
+            .with_usage(TokenUsage::new(3, 3));
+        let Ok(LlmResponse::Stream(chat_stream)) = model.invoke(messages("hi")).await else {
+            panic!("expected a stream");
+        };
+        let chunks: Vec<ChatChunk> = chat_stream.map(|chunk| chunk.unwrap()).collect().await;
+        assert_eq!(
+            chunks,
+            vec![
+                ChatChunk::Content(String::from("hello ")),
+                ChatChunk::Content(String::from("big ")),
+                ChatChunk::Content(String::from("world")),
+                ChatChunk::Finish(String::from("stop")),
+                ChatChunk::Usage(TokenUsage::new(3, 3)),
+            ]
+        );
+
+        let mut result = ChatResult::from_text("");
+        result.generations[0].message = AIMessage::new("").with_tool_calls(vec![ToolCall::new(
+            "call_1",
+            "search",
+            "{\"q\":\"rust\"}",
+        )]);
+        let model = FakeChatModel::from_results(vec![result])
+            .with_stream()
+            .with_chunk_size(4);
+        let Ok(LlmResponse::Stream(chat_stream)) = model.invoke(messages("hi")).await else {
+            panic!("expected a stream");
+        };
+        let result = ChatResult::from_stream(chat_stream).await.unwrap();
+        assert_eq!(
+            result.tool_calls()[0].function.arguments,
+            "{\"q\":\"rust\"}"
+        );
+
+        let model = FakeChatModel::new(vec!["abcdefghij"])
+            .with_stream()
+            .with_chunk_size(4);
+        let Ok(LlmResponse::Stream(chat_stream)) = model.invoke(messages("hi")).await else {
+            panic!("expected a stream");
+        };
+        let chunks: Vec<ChatChunk> = chat_stream.map(|chunk| chunk.unwrap()).collect().await;
+        assert_eq!(
+            chunks[..3],
+            [
+                ChatChunk::Content(String::from("abcd")),
+                ChatChunk::Content(String::from("efgh")),
+                ChatChunk::Content(String::from("ij")),
+            ]
+        );
+    }
+}
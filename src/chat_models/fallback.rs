@@ -0,0 +1,269 @@
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use serde_json::Value;
+
+use crate::{
+    errors::{ApiError, ErrorKind},
+    schemas::{
+        llm::{ChatChunk, LlmResponse},
+        messages::BaseMessage,
+    },
+};
+
+use super::chat_model_trait::ChatTrait;
+
+// Errors worth another model by default: the next one may have spare capacity
+// or a larger context window.
+const DEFAULT_FALLBACK_ON: [ErrorKind; 5] = [
+    ErrorKind::RateLimit,
+    ErrorKind::Overloaded,
+    ErrorKind::Server,
+    ErrorKind::Timeout,
+    ErrorKind::ContextLength,
+];
+
+/// Tries its models in order, moving to the next one when a model fails with
+/// one of the `fallback_on` error kinds. The error of the last model is
+/// returned as is.
+///
+/// Streams fall back only when they fail before their first token: chunks such
+/// as usage or a finish reason that come before it are held back until then,
+/// and once a token has been handed out, later errors are part of the stream.
+pub struct FallbackChat {
+    models: Vec<Box<dyn ChatTrait>>,
+    fallback_on: Vec<ErrorKind>,
+}
+
+impl FallbackChat {
+    pub fn new(primary: Box<dyn ChatTrait>) -> Self {
+        Self {
+            models: vec![primary],
+            fallback_on: DEFAULT_FALLBACK_ON.to_vec(),
+        }
+    }
+
+    pub fn with_fallback(mut self, model: Box<dyn ChatTrait>) -> Self {
+        self.models.push(model);
+        self
+    }
+
+    pub fn with_fallback_on(mut self, kinds: Vec<ErrorKind>) -> Self {
+        self.fallback_on = kinds;
+        self
+    }
+
+    fn should_fall_back(&self, error: &ApiError) -> bool {
+        self.fallback_on.contains(&error.kind())
+    }
+}
+
+#[async_trait]
+impl ChatTrait for FallbackChat {
+    async fn invoke(&self, messages: Vec<Box<dyn BaseMessage>>) -> Result<LlmResponse, ApiError> {
+        let last = self.models.len() - 1;
+        for (index, model) in self.models.iter().enumerate() {
+            let error = match model.invoke(messages.clone()).await {
+                Ok(LlmResponse::Stream(mut chat_stream)) if index < last => {
+                    let mut held = Vec::new();
+                    let error = loop {
+                        match chat_stream.next().await {
+                            Some(Ok(chunk)) => {
+                                let token = matches!(chunk, ChatChunk::Content(_));
+                                held.push(Ok(chunk));
+                                if token {
+                                    break None;
+                                }
+                            }
+                            Some(Err(e)) if self.should_fall_back(&e) => break Some(e),
+                            Some(Err(e)) => {
+                                held.push(Err(e));
+                                break None;
+                            }
+                            // A finished stream must not be polled again
+                            None => return Ok(LlmResponse::Stream(stream::iter(held).boxed())),
+                        }
+                    };
+                    match error {
+                        Some(e) => e,
+                        None => {
+                            return Ok(LlmResponse::Stream(
+                                stream::iter(held).chain(chat_stream).boxed(),
+                            ))
+                        }
+                    }
+                }
+                Err(e) if index < last && self.should_fall_back(&e) => e,
+                result => return result,
+            };
+            log::warn!(
+                "Chat model {} of {} failed, falling back to the next one: {}",
+                index + 1,
+                self.models.len(),
+                error
+            );
+        }
+        unreachable!("the last model always returns")
+    }
+
+    fn identifying_params(&self) -> Value {
+        Value::Array(
+            self.models
+                .iter()
+                .map(|model| model.identifying_params())
+                .collect(),
+        )
+    }
+
+    // The smallest window and the largest answer, so a request fits every model
+    // that reports them
+    fn context_window(&self) -> Option<usize> {
+        self.models
+            .iter()
+            .filter_map(|model| model.context_window())
+            .min()
+    }
+
+    fn max_tokens(&self) -> Option<u32> {
+        self.models
+            .iter()
+            .filter_map(|model| model.max_tokens())
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use crate::{
+        errors::openai_errors::OpenaiError,
+        schemas::{
+            llm::{ChatResult, TokenUsage},
+            messages::HumanMessage,
+        },
+    };
+
+    use super::*;
+
+    enum Outcome {
+        Answer(&'static str),
+        Fail(u16),
+        StreamFail(u16),
+        // A usage chunk, then the error
+        StreamFailAfterUsage(u16),
+        Stream(&'static str),
+    }
+
+    struct ScriptedChat {
+        outcome: Outcome,
+        calls: Arc<AtomicUsize>,
+    }
+    impl ScriptedChat {
+        fn boxed(outcome: Outcome, calls: &Arc<AtomicUsize>) -> Box<dyn ChatTrait> {
+            Box::new(Self {
+                outcome,
+                calls: calls.clone(),
+            })
+        }
+    }
+
+    fn error(code: u16) -> ApiError {
+        ApiError::OpenaiError(OpenaiError::from_http_status(code, String::new()))
+    }
+
+    #[async_trait]
+    impl ChatTrait for ScriptedChat {
+        async fn invoke(
+            &self,
+            _messages: Vec<Box<dyn BaseMessage>>,
+        ) -> Result<LlmResponse, ApiError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match self.outcome {
+                Outcome::Answer(text) => Ok(LlmResponse::Chat(ChatResult::from_text(text))),
+                Outcome::Fail(code) => Err(error(code)),
+                Outcome::StreamFail(code) => Ok(LlmResponse::Stream(
+                    stream::iter(vec![Err(error(code))]).boxed(),
+                )),
+                Outcome::StreamFailAfterUsage(code) => Ok(LlmResponse::Stream(
+                    stream::iter(vec![
+                        Ok(ChatChunk::Usage(TokenUsage::new(5, 0))),
+                        Err(error(code)),
+                    ])
+                    .boxed(),
+                )),
+                Outcome::Stream(text) => Ok(LlmResponse::Stream(
+                    stream::iter(vec![
+                        Ok(ChatChunk::Content(text.to_string())),
+                        Err(error(503)),
+                    ])
+                    .boxed(),
+                )),
+            }
+        }
+    }
+
+    fn messages() -> Vec<Box<dyn BaseMessage>> {
+        vec![Box::new(HumanMessage::new("hi"))]
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_on_matching_errors_only() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let chat = FallbackChat::new(ScriptedChat::boxed(Outcome::Fail(503), &calls))
+            .with_fallback(ScriptedChat::boxed(Outcome::Answer("backup"), &calls));
+        match chat.invoke(messages()).await {
+            Ok(LlmResponse::Chat(result)) => assert_eq!(result.text(), "backup"),
+            _ => panic!("expected the fallback answer"),
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Authentication errors are not in the default kinds
+        let calls = Arc::new(AtomicUsize::new(0));
+        let chat = FallbackChat::new(ScriptedChat::boxed(Outcome::Fail(401), &calls))
+            .with_fallback(ScriptedChat::boxed(Outcome::Answer("backup"), &calls));
+        assert!(chat.invoke(messages()).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream_falls_back_before_first_chunk_only() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let chat = FallbackChat::new(ScriptedChat::boxed(Outcome::StreamFail(429), &calls))
+            .with_fallback(ScriptedChat::boxed(Outcome::Stream("backup"), &calls))
+            .with_fallback(ScriptedChat::boxed(Outcome::Answer("unused"), &calls));
+
+        let Ok(LlmResponse::Stream(mut chat_stream)) = chat.invoke(messages()).await else {
+            panic!("expected a stream");
+        };
+        assert_eq!(
+            chat_stream.next().await.unwrap().unwrap(),
+            ChatChunk::Content(String::from("backup"))
+        );
+        // The error after the first chunk is handed to the caller
+        assert!(chat_stream.next().await.unwrap().is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stream_falls_back_after_chunks_without_content() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let chat = FallbackChat::new(ScriptedChat::boxed(
+            Outcome::StreamFailAfterUsage(503),
+            &calls,
+        ))
+        .with_fallback(ScriptedChat::boxed(Outcome::Stream("backup"), &calls));
+
+        let Ok(LlmResponse::Stream(mut chat_stream)) = chat.invoke(messages()).await else {
+            panic!("expected a stream");
+        };
+        // The usage of the failed model is not handed out
+        assert_eq!(
+            chat_stream.next().await.unwrap().unwrap(),
+            ChatChunk::Content(String::from("backup"))
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,2 +1,6 @@
+pub mod anthropic;
 pub mod chat_model_trait;
+pub mod fake;
+pub mod fallback;
+pub mod ollama;
 pub mod openai;
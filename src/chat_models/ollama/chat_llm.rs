@@ -0,0 +1,328 @@
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::{
+    chat_models::{
+        chat_model_trait::ChatTrait,
+        ollama::ollama_api::{
+            response_format_to_value, stream_chunks, ApiRequest, ApiResponse, Message, Options,
+        },
+    },
+    client::{
+        stream::{decode_chunks, json_lines, with_idle_timeout},
+        OllamaConfig,
+    },
+    errors::{ollama_errors::OllamaError, ApiError},
+    schemas::{
+        llm::{ChatGeneration, ChatResult, LlmResponse, ResponseFormat},
+        messages::{AIMessage, BaseMessage},
+    },
+};
+
+pub struct ChatOllama {
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub config: OllamaConfig,
+    pub max_tokens: Option<u32>,
+    pub keep_alive: Option<String>,
+    pub stream: bool,
+    pub top_p: Option<f32>,
+    pub seed: Option<i64>,
+    pub stop: Vec<String>,
+    pub response_format: Option<ResponseFormat>,
+}
+impl ChatOllama {
+    pub fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    pub fn with_stream(mut self) -> Self {
+        self.stream = true;
+        self
+    }
+
+    pub fn with_config(mut self, config: OllamaConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// How long the server keeps the model loaded after the request, e.g. `"10m"`.
+    pub fn with_keep_alive(mut self, keep_alive: &str) -> Self {
+        self.keep_alive = Some(keep_alive.to_string());
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: i64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    pub fn with_response_format(mut self, response_format: ResponseFormat) -> Self {
+        self.response_format = Some(response_format);
+        self
+    }
+
+    fn api_request(&self, messages: Vec<Message>) -> ApiRequest {
+        ApiRequest {
+            model: self.model.clone(),
+            messages,
+            stream: self.stream,
+            options: Options {
+                temperature: self.temperature,
+                num_predict: self.max_tokens,
+                top_p: self.top_p,
+                seed: self.seed,
+                stop: self.stop.clone(),
+            },
+            format: self
+                .response_format
+                .as_ref()
+                .and_then(response_format_to_value),
+            keep_alive: self.keep_alive.clone(),
+        }
+    }
+}
+impl Default for ChatOllama {
+    fn default() -> Self {
+        Self {
+            model: String::from("llama3"),
+            temperature: None,
+            config: OllamaConfig::default(),
+            max_tokens: None,
+            keep_alive: None,
+            stream: false,
+            top_p: None,
+            seed: None,
+            stop: Vec::new(),
+            response_format: None,
+        }
+    }
+}
+
+#[async_trait]
+impl ChatTrait for ChatOllama {
+    async fn invoke(&self, messages: Vec<Box<dyn BaseMessage>>) -> Result<LlmResponse, ApiError> {
+        let api_request = self.api_request(Message::from_base_messages(messages)?);
+        log::debug!("ollama request: {:?}", api_request);
+
+        let mut request = self.config.post("api/chat")?.json(&api_request);
+        if !self.stream {
+            request = self.config.http.with_request_timeout(request);
+        }
+
+        let response = self.config.send(request).await?;
+
+        if self.stream {
+            let map_err = |msg: String| ApiError::OllamaError(OllamaError::new_generic_error(msg));
+            let chunks = decode_chunks(json_lines(response, map_err), stream_chunks);
+            return Ok(LlmResponse::Stream(with_idle_timeout(
+                chunks,
+                self.config.http.read_timeout(),
+            )));
+        }
+
+        let api_response: ApiResponse = response.json().await.map_err(|_| {
+            ApiError::OllamaError(OllamaError::new_generic_error(String::from(
+                "Error deserializing response or unknown error",
+            )))
+        })?;
+        log::info!(
+            "Prompt token count: {:?}, completion token count: {:?} for model: {}",
+            api_response.prompt_eval_count,
+            api_response.eval_count,
+            api_response.model
+        );
+
+        let usage = api_response.usage();
+        let message = api_response.message.ok_or_else(|| {
+            ApiError::OllamaError(OllamaError::new_generic_error(String::from(
+                "No message in response",
+            )))
+        })?;
+        Ok(LlmResponse::Chat(ChatResult {
+            id: None,
+            model: api_response.model,
+            system_fingerprint: None,
+            generations: vec![ChatGeneration {
+                index: 0,
+                message: AIMessage::new(&message.content),
+                finish_reason: api_response.done_reason,
+                logprobs: Vec::new(),
+            }],
+            usage,
+        }))
+    }
+
+    fn identifying_params(&self) -> Value {
+        // How long the model stays loaded does not change the answer
+        let mut api_request = self.api_request(Vec::new());
+        api_request.keep_alive = None;
+        serde_json::to_value(api_request).unwrap_or_default()
+    }
+
+    // The context window depends on how the model was loaded, so it is unknown
+    fn max_tokens(&self) -> Option<u32> {
+        self.max_tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+    use serde_json::json;
+
+    use crate::{
+        chains::{chain_trait::ChainTrait, llmchat_chain::LLMChatChain},
+        prompt::{ChatPromptTemplate, HumanMessagePromptTemplate, MessageLike, PromptTemplate},
+        schemas::{
+            chain::ChainResponse,
+            llm::TokenUsage,
+            messages::{HumanMessage, SystemMessage},
+        },
+    };
+
+    use super::*;
+
+    fn chat_for(server: &mockito::ServerGuard) -> ChatOllama {
+        ChatOllama::new("llama3").with_config(OllamaConfig::new(&server.url()))
+    }
+
+    #[tokio::test]
+    async fn test_generate_text() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/api/chat")
+            .match_body(Matcher::PartialJson(json!({
+                "model": "llama3",
+                "stream": false,
+                "options": {"temperature": 0.5, "seed": 7},
+                "format": "json",
+                "messages": [
+                    {"role": "system", "content": "Be brief."},
+                    {"role": "user", "content": "Hi"}
+                ]
+            })))
+            .with_status(200)
+            .with_body(
+                json!({
+                    "model": "llama3",
+                    "created_at": "2024-05-01T00:00:00Z",
+                    "message": {"role": "assistant", "content": "Hello!"},
+                    "done": true,
+                    "prompt_eval_count": 12,
+                    "eval_count": 3
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let messages: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(SystemMessage::new("Be brief.")),
+            Box::new(HumanMessage::new("Hi")),
+        ];
+        let response = chat_for(&server)
+            .with_temperature(0.5)
+            .with_seed(7)
+            .with_response_format(ResponseFormat::JsonObject)
+            .invoke(messages)
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+        match response {
+            LlmResponse::Chat(result) => {
+                assert_eq!(result.text(), "Hello!");
+                assert_eq!(result.usage, Some(TokenUsage::new(12, 3)));
+            }
+            _ => panic!("expected a chat result"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_model_not_found() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/api/chat")
+            .with_status(404)
+            .with_body(r#"{"error":"model 'llama3' not found, try pulling it first"}"#)
+            .create_async()
+            .await;
+
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        let result = chat_for(&server).invoke(messages).await;
+
+        match result {
+            Err(ApiError::OllamaError(OllamaError::ModelNotFound { detail, .. })) => {
+                assert_eq!(detail, "model 'llama3' not found, try pulling it first")
+            }
+            _ => panic!("expected a model not found error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_through_chat_chain() {
+        let mut server = mockito::Server::new_async().await;
+        let body = [
+            r#"{"model":"llama3","message":{"role":"assistant","content":"Hello"},"done":false}"#,
+            r#"{"model":"llama3","message":{"role":"assistant","content":" Luis"},"done":false}"#,
+            r#"{"model":"llama3","message":{"role":"assistant","content":""},"done":true,"eval_count":2}"#,
+        ]
+        .join("\n");
+        server
+            .mock("POST", "/api/chat")
+            .match_body(Matcher::PartialJson(json!({"stream": true})))
+            .with_status(200)
+            .with_header("content-type", "application/x-ndjson")
+            .with_body(body)
+            .create_async()
+            .await;
+
+        let prompt_template = ChatPromptTemplate::from_messages(vec![
+            MessageLike::base_message(SystemMessage::new("Greet the user")),
+            MessageLike::base_prompt_template(HumanMessagePromptTemplate::new(
+                PromptTemplate::from_template("My name is {{input}}"),
+            )),
+        ]);
+        let chain = LLMChatChain::new(prompt_template, Box::new(chat_for(&server).with_stream()));
+
+        let mut stream = match chain.run(&"Luis".to_string()).await.unwrap() {
+            ChainResponse::Stream(stream) => stream,
+            ChainResponse::Text(_) => panic!("expected a stream"),
+        };
+        let mut text = String::new();
+        while let Some(chunk) = stream.recv().await {
+            text.push_str(&chunk.unwrap());
+        }
+
+        assert_eq!(text, "Hello Luis");
+    }
+}
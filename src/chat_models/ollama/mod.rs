@@ -0,0 +1,3 @@
+pub mod chat_llm;
+pub use chat_llm::ChatOllama;
+pub(crate) mod ollama_api;
@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    errors::{ollama_errors::OllamaError, ApiError},
+    schemas::{
+        llm::{ChatChunk, ResponseFormat, TokenUsage},
+        messages::{BaseMessage, ContentPart},
+    },
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub role: String,
+    pub content: String,
+    // Base64 encoded, without the `data:` prefix
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+impl Message {
+    // Ollama only takes text and base64 images, so tool calls, tool results, files
+    // and images behind a URL are rejected rather than left out of the request.
+    pub fn from_base_message(base: Box<dyn BaseMessage>) -> Result<Self, ApiError> {
+        if !base.get_tool_calls().is_empty() || base.get_tool_call_id().is_some() {
+            return Err(invalid_request(
+                "Tool calls and tool results are not supported",
+            ));
+        }
+        let mut content = base.get_content();
+        let mut images = Vec::new();
+        for part in base.get_content_parts() {
+            match part {
+                ContentPart::Text { text } => {
+                    if !content.is_empty() {
+                        content.push_str("\n\n");
+                    }
+                    content.push_str(&text);
+                }
+                ContentPart::ImageUrl { image_url } => {
+                    let data = image_url
+                        .url
+                        .strip_prefix("data:")
+                        .and_then(|url| url.split_once(";base64,"))
+                        .map(|(_, data)| data.to_string())
+                        .ok_or_else(|| {
+                            invalid_request("Images must be base64 encoded, not URLs")
+                        })?;
+                    images.push(data);
+                }
+                ContentPart::File { .. } => {
+                    return Err(invalid_request("Files are not supported"));
+                }
+            }
+        }
+        Ok(Self {
+            role: base.get_type(),
+            content,
+            images,
+        })
+    }
+
+    pub fn from_base_messages(messages: Vec<Box<dyn BaseMessage>>) -> Result<Vec<Self>, ApiError> {
+        messages.into_iter().map(Self::from_base_message).collect()
+    }
+}
+
+fn invalid_request(detail: &str) -> ApiError {
+    ApiError::OllamaError(OllamaError::InvalidRequest {
+        code: 400,
+        detail: String::from(detail),
+    })
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Options {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+// Ollama takes `"json"` or the JSON schema itself; plain text needs no format.
+pub fn response_format_to_value(response_format: &ResponseFormat) -> Option<Value> {
+    match response_format {
+        ResponseFormat::Text => None,
+        ResponseFormat::JsonObject => Some(json!("json")),
+        ResponseFormat::JsonSchema { schema, .. } => Some(schema.clone()),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+    pub stream: bool,
+    pub options: Options,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<String>,
+}
+
+// Returned once for non-streaming requests, and once per line when streaming;
+// the last line has `done` set and carries the token counts.
+#[derive(Deserialize, Debug)]
+pub struct ApiResponse {
+    pub model: String,
+    pub message: Option<Message>,
+    pub done: bool,
+    pub done_reason: Option<String>,
+    pub prompt_eval_count: Option<u32>,
+    pub eval_count: Option<u32>,
+}
+impl ApiResponse {
+    pub fn usage(&self) -> Option<TokenUsage> {
+        match (self.prompt_eval_count, self.eval_count) {
+            (None, None) => None,
+            (prompt_tokens, completion_tokens) => Some(TokenUsage::new(
+                prompt_tokens.unwrap_or_default(),
+                completion_tokens.unwrap_or_default(),
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamError {
+    pub error: String,
+}
+
+// Turns one line of a streamed chat response into chunks. The last line has
+// `done` set and carries the finish reason and token counts.
+pub fn stream_chunks(line: &str) -> Result<Vec<ChatChunk>, ApiError> {
+    if let Ok(error) = serde_json::from_str::<StreamError>(line) {
+        return Err(ApiError::OllamaError(OllamaError::new_generic_error(
+            error.error,
+        )));
+    }
+    let response: ApiResponse = serde_json::from_str(line).map_err(|_| {
+        ApiError::OllamaError(OllamaError::new_generic_error(format!(
+            "Unexpected stream line: {}",
+            line
+        )))
+    })?;
+
+    let mut chunks = Vec::new();
+    let usage = response.usage();
+    if let Some(message) = response.message.filter(|m| !m.content.is_empty()) {
+        chunks.push(ChatChunk::Content(message.content));
+    }
+    if response.done {
+        chunks.push(ChatChunk::Finish(
+            response.done_reason.unwrap_or_else(|| String::from("stop")),
+        ));
+        if let Some(usage) = usage {
+            chunks.push(ChatChunk::Usage(usage));
+        }
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::schemas::messages::{AIMessage, HumanMessage, ToolCall, ToolMessage};
+
+    use super::*;
+
+    #[test]
+    fn test_images_are_sent_as_base64() {
+        let message = Message::from_base_message(Box::new(
+            HumanMessage::new("Describe it").with_content_parts(vec![
+                ContentPart::image_base64("image/png", "iVBORw0KGgo=", None),
+                ContentPart::text("Be brief."),
+            ]),
+        ))
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            json!({
+                "role": "user",
+                "content": "Describe it\n\nBe brief.",
+                "images": ["iVBORw0KGgo="]
+            })
+        );
+    }
+
+    #[test]
+    fn test_rejects_what_ollama_cannot_take() {
+        let messages: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(
+                HumanMessage::new("Describe it").with_image_url("https://example.com/a.png", None),
+            ),
+            Box::new(
+                HumanMessage::new("Sum it up")
+                    .with_content_parts(vec![ContentPart::file_id("file_1")]),
+            ),
+            Box::new(
+                AIMessage::new("").with_tool_calls(vec![ToolCall::new("call_1", "search", "{}")]),
+            ),
+            Box::new(ToolMessage::new("Sunny", "call_1")),
+        ];
+        for message in messages {
+            assert!(matches!(
+                Message::from_base_message(message),
+                Err(ApiError::OllamaError(OllamaError::InvalidRequest { .. }))
+            ));
+        }
+    }
+}
@@ -1,19 +1,33 @@
-use std::env;
+use std::collections::HashMap;
 
 use async_trait::async_trait;
-use reqwest::Client;
-use reqwest_eventsource::EventSource;
+use futures::StreamExt;
+use serde_json::{json, Value};
 
 use crate::{
     chat_models::{
         chat_model_trait::ChatTrait,
         openai::{
-            message_type::Message,
-            openai_api::{ApiRequest, ApiResponse},
+            message_type::{Message, MessageContent},
+            openai_api::{
+                response_format_to_value, stream_chunks, tool_choice_to_value, ApiRequest,
+                ApiResponse, ApiTool,
+            },
         },
     },
+    client::{
+        rate_limiter::Throttle,
+        stream::{decode_chunks, sse_data, with_idle_timeout},
+        OpenAIApiType, OpenAIConfig, RateLimiter,
+    },
     errors::{openai_errors::OpenaiError, ApiError},
-    schemas::{llm::LlmResponse, messages::BaseMessage},
+    schemas::{
+        llm::{ChatChunk, ChatGeneration, ChatResult, LlmResponse, ResponseFormat},
+        messages::{AIMessage, BaseMessage},
+        tools::{FunctionDefinition, ToolChoice},
+    },
+    tokenizer::count_message_tokens,
+    usage::UsageTracker,
 };
 
 #[derive(Debug)]
@@ -34,23 +48,81 @@ impl ChatModel {
             ChatModel::Gpt4TURBO => "gpt-4-1106-preview",
         }
     }
+
+    // The tokens the model accepts in one request, prompt and answer together
+    pub fn context_window(&self) -> usize {
+        match *self {
+            ChatModel::Gpt3_5Turbo | ChatModel::Gpt3_5Turbo16k => 16_385,
+            ChatModel::GPT3_5TURBO0613 => 4_096,
+            ChatModel::Gpt4 => 8_192,
+            ChatModel::Gpt4TURBO => 128_000,
+        }
+    }
+
+    // Tokens the API adds around every message of a request, for the role and
+    // the separators
+    pub fn tokens_per_message(&self) -> usize {
+        match *self {
+            ChatModel::Gpt3_5Turbo
+            | ChatModel::Gpt3_5Turbo16k
+            | ChatModel::GPT3_5TURBO0613
+            | ChatModel::Gpt4
+            | ChatModel::Gpt4TURBO => 3,
+        }
+    }
 }
 
 pub struct ChatOpenAI {
     pub model: ChatModel,
+    // Sent instead of the name of `model`, e.g. for OpenAI compatible servers
+    pub model_name: Option<String>,
     pub temperature: f32,
-    pub openai_key: String,
+    pub config: OpenAIConfig,
     pub max_tokens: Option<u32>,
     pub stream: bool,
+    pub deployment: Option<String>,
+    pub tools: Vec<FunctionDefinition>,
+    pub tool_choice: Option<ToolChoice>,
+    pub n: Option<u32>,
+    pub top_p: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub stop: Vec<String>,
+    pub seed: Option<i64>,
+    // Bias added to the logits of each token id, between -100 and 100
+    pub logit_bias: HashMap<u32, i32>,
+    pub user: Option<String>,
+    pub logprobs: bool,
+    pub top_logprobs: Option<u32>,
+    pub response_format: Option<ResponseFormat>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub usage_tracker: Option<UsageTracker>,
 }
 impl ChatOpenAI {
     pub fn new(model: ChatModel, temperature: f32, openai_key: String) -> Self {
         Self {
             model,
+            model_name: None,
             temperature,
-            openai_key,
+            config: OpenAIConfig::new(openai_key),
             max_tokens: None,
             stream: false,
+            deployment: None,
+            tools: Vec::new(),
+            tool_choice: None,
+            n: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            stop: Vec::new(),
+            seed: None,
+            logit_bias: HashMap::new(),
+            user: None,
+            logprobs: false,
+            top_logprobs: None,
+            response_format: None,
+            rate_limiter: None,
+            usage_tracker: None,
         }
     }
 
@@ -59,13 +131,26 @@ impl ChatOpenAI {
         self
     }
 
+    /// Model id sent to the API instead of the name of `model`, for models outside
+    /// `ChatModel` such as those served by vLLM or LM Studio. `model` is still
+    /// used to count tokens, but the context window is then unknown.
+    pub fn with_model_name(mut self, model_name: &str) -> Self {
+        self.model_name = Some(model_name.to_string());
+        self
+    }
+
     pub fn with_stream(mut self) -> Self {
         self.stream = true;
         self
     }
 
     pub fn with_api_key(mut self, openai_key: String) -> Self {
-        self.openai_key = openai_key;
+        self.config.api_key = openai_key;
+        self
+    }
+
+    pub fn with_config(mut self, config: OpenAIConfig) -> Self {
+        self.config = config;
         self
     }
 
@@ -78,107 +163,778 @@ impl ChatOpenAI {
         self.max_tokens = Some(max_tokens);
         self
     }
+
+    /// Azure deployment to send requests to. Defaults to the model name.
+    pub fn with_deployment(mut self, deployment: &str) -> Self {
+        self.deployment = Some(deployment.to_string());
+        self
+    }
+
+    pub fn with_tools(mut self, tools: Vec<FunctionDefinition>) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    pub fn with_tool_choice(mut self, tool_choice: ToolChoice) -> Self {
+        self.tool_choice = Some(tool_choice);
+        self
+    }
+
+    /// Number of answers to generate for each request. Streams carry a single
+    /// answer, so requests with `with_stream` and an `n` above 1 fail with an
+    /// invalid request error before being sent.
+    pub fn with_n(mut self, n: u32) -> Self {
+        self.n = Some(n);
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn with_presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.presence_penalty = Some(presence_penalty);
+        self
+    }
+
+    pub fn with_frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.frequency_penalty = Some(frequency_penalty);
+        self
+    }
+
+    /// Up to 4 sequences where the model stops generating.
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: i64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn with_logit_bias(mut self, logit_bias: HashMap<u32, i32>) -> Self {
+        self.logit_bias = logit_bias;
+        self
+    }
+
+    /// An id for the end user, to help the provider detect abuse.
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Returns the log probability of each generated token, along with the
+    /// `top_logprobs` most likely alternatives when set.
+    pub fn with_logprobs(mut self, top_logprobs: Option<u32>) -> Self {
+        self.logprobs = true;
+        self.top_logprobs = top_logprobs;
+        self
+    }
+
+    pub fn with_response_format(mut self, response_format: ResponseFormat) -> Self {
+        self.response_format = Some(response_format);
+        self
+    }
+
+    /// Throttles requests with a limiter that can be shared with other clients.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Records the tokens of every request, and refuses to send more once the
+    /// tracker's budget is exceeded.
+    pub fn with_usage_tracker(mut self, usage_tracker: UsageTracker) -> Self {
+        self.usage_tracker = Some(usage_tracker);
+        self
+    }
+
+    fn deployment(&self) -> &str {
+        self.deployment
+            .as_deref()
+            .unwrap_or_else(|| self.model_name())
+    }
+
+    fn model_name(&self) -> &str {
+        self.model_name
+            .as_deref()
+            .unwrap_or_else(|| self.model.as_str())
+    }
 }
 impl Default for ChatOpenAI {
     fn default() -> Self {
         Self {
             model: ChatModel::Gpt3_5Turbo,
+            model_name: None,
             temperature: 0.0,
-            openai_key: env::var("OPENAI_API_KEY").unwrap_or(String::new()),
+            config: OpenAIConfig::default(),
             max_tokens: None,
             stream: false,
+            deployment: None,
+            tools: Vec::new(),
+            tool_choice: None,
+            n: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            stop: Vec::new(),
+            seed: None,
+            logit_bias: HashMap::new(),
+            user: None,
+            logprobs: false,
+            top_logprobs: None,
+            response_format: None,
+            rate_limiter: None,
+            usage_tracker: None,
         }
     }
 }
 
-#[async_trait]
-impl ChatTrait for ChatOpenAI {
-    async fn generate(
-        &self,
-        messages: Vec<Vec<Box<dyn BaseMessage>>>,
-    ) -> Result<LlmResponse, ApiError> {
-        let flattened_messages: Vec<Message> = messages
-            .into_iter()
-            .flat_map(|inner_messages| Message::from_base_messages(inner_messages))
-            .collect();
-        log::debug!("flattened_messages: {:?}", flattened_messages);
-
-        let client = Client::new();
+impl ChatOpenAI {
+    fn api_request(&self, messages: Vec<Message>) -> ApiRequest {
         let mut api_request = ApiRequest {
-            model: String::from(self.model.as_str()),
-            messages: flattened_messages,
+            model: String::from(self.model_name()),
+            messages,
             temperature: self.temperature,
             max_tokens: self.max_tokens,
+            top_p: self.top_p,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            stop: self.stop.clone(),
+            seed: self.seed,
+            logit_bias: self.logit_bias.clone(),
+            user: self.user.clone(),
+            logprobs: self.logprobs.then_some(true),
+            top_logprobs: self.top_logprobs,
+            response_format: self.response_format.as_ref().map(response_format_to_value),
+            n: self.n,
             stream: None,
+            stream_options: None,
+            tools: if self.tools.is_empty() {
+                None
+            } else {
+                Some(self.tools.iter().map(ApiTool::from).collect())
+            },
+            tool_choice: self.tool_choice.as_ref().map(tool_choice_to_value),
         };
 
-        // Add the 'stream' parameter if streaming is requested
+        // Add the 'stream' parameter if streaming is requested. Usage is only
+        // reported at the end of a stream when asked for, which Azure does not support.
         if self.stream {
             api_request.stream = Some(true);
+            if self.config.api_type == OpenAIApiType::OpenAI {
+                api_request.stream_options = Some(json!({ "include_usage": true }));
+            }
+        }
+        api_request
+    }
+}
+
+#[async_trait]
+impl ChatTrait for ChatOpenAI {
+    async fn invoke(&self, messages: Vec<Box<dyn BaseMessage>>) -> Result<LlmResponse, ApiError> {
+        if self.stream && self.n.unwrap_or(1) > 1 {
+            return Err(ApiError::OpenaiError(OpenaiError::InvalidRequest {
+                code: 400,
+                detail: String::from("Streams carry a single answer, n must be 1"),
+                param: Some(String::from("n")),
+            }));
+        }
+        if let Some(usage_tracker) = &self.usage_tracker {
+            usage_tracker.check_budget()?;
         }
+        // The API counts max_tokens against the limit as well
+        let throttle = Throttle::new(self.rate_limiter.as_ref(), self.deployment(), || {
+            count_message_tokens(&messages, &self.model) as u32
+                + self.max_tokens.unwrap_or_default() * self.n.unwrap_or(1)
+        });
 
-        let request = client
-            .post("https://api.openai.com/v1/chat/completions")
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.openai_key))
+        let messages = Message::from_base_messages(messages);
+        log::debug!("messages: {:?}", messages);
+
+        let api_request = self.api_request(messages);
+        let request = self
+            .config
+            .post("chat/completions", self.deployment())?
             .json(&api_request);
 
+        let request = if self.stream {
+            request
+        } else {
+            self.config.http.with_request_timeout(request)
+        };
+        let response = self.config.send_throttled(request, throttle).await?;
         if self.stream {
-            let es = EventSource::new(request).map_err(|e| {
-                ApiError::OpenaiError(OpenaiError::new_generic_error(format!(
-                    "Error creating EventSource: {}",
-                    e
-                )))
-            })?;
-            return Ok(LlmResponse::Stream(es));
+            let map_err = |msg: String| ApiError::OpenaiError(OpenaiError::new_generic_error(msg));
+            let chunks = decode_chunks(sse_data(response, map_err), stream_chunks);
+            let chunks = with_idle_timeout(chunks, self.config.http.read_timeout());
+            let Some(usage_tracker) = self.usage_tracker.clone() else {
+                return Ok(LlmResponse::Stream(chunks));
+            };
+            // The usage comes in the last chunk, asked for with `include_usage`
+            let model = self.model_name().to_string();
+            return Ok(LlmResponse::Stream(
+                chunks
+                    .inspect(move |chunk| {
+                        if let Ok(ChatChunk::Usage(usage)) = chunk {
+                            usage_tracker.record(&model, *usage);
+                        }
+                    })
+                    .boxed(),
+            ));
         }
 
-        let response = request.send().await.map_err(|e| {
-            ApiError::OpenaiError(OpenaiError::new_generic_error(format!(
-                "Error sending request: {}",
-                e
+        let api_response: ApiResponse = response.json().await.map_err(|_| {
+            ApiError::OpenaiError(OpenaiError::new_generic_error(String::from(
+                "Error deserializing response or unknown error",
             )))
         })?;
-        let status = response.status();
-        match status {
-            reqwest::StatusCode::OK => {
-                let api_response: ApiResponse = response.json().await.map_err(|_| {
-                    ApiError::OpenaiError(OpenaiError::new_generic_error(String::from(
-                        "Error deserializing response or unknown error",
-                    )))
-                })?;
-                log::info!(
-                    "Prompt token count: {:?} for model: {}",
-                    api_response.usage,
-                    self.model.as_str()
-                );
-                let response_message = api_response
-                    .choices
-                    .get(0)
-                    .ok_or_else(|| OpenaiError::ServerError {
-                        code: 500,
-                        detail: String::from("Unexpected API response"),
-                    })
-                    .map_err(|e| ApiError::OpenaiError(e.clone()))?;
-
-                let text_resp = response_message
-                    .message
-                    .get("content")
-                    .ok_or_else(|| OpenaiError::ServerError {
-                        code: 500,
-                        detail: String::from("No content in AI message"),
-                    })
-                    .map_err(|e| ApiError::OpenaiError(e.clone()))?
-                    .clone();
+        log::info!(
+            "Token usage: {:?} for model: {}",
+            api_response.usage,
+            self.model_name()
+        );
+        if api_response.choices.is_empty() {
+            return Err(ApiError::OpenaiError(OpenaiError::ServerError {
+                code: 500,
+                detail: String::from("Unexpected API response"),
+            }));
+        }
 
-                Ok(LlmResponse::Text(text_resp))
-            }
-            _ => {
-                let detail: String = response.text().await.unwrap();
-                let error =
-                    OpenaiError::from_http_status(status.as_u16().clone(), detail.to_string());
-                Err(ApiError::OpenaiError(error))
+        let generations = api_response
+            .choices
+            .into_iter()
+            .map(|choice| ChatGeneration {
+                index: choice.index,
+                message: AIMessage::new(
+                    &choice
+                        .message
+                        .content
+                        .map(MessageContent::into_text)
+                        .unwrap_or_default(),
+                )
+                .with_tool_calls(choice.message.tool_calls.unwrap_or_default()),
+                finish_reason: choice.finish_reason,
+                logprobs: choice
+                    .logprobs
+                    .and_then(|logprobs| logprobs.content)
+                    .unwrap_or_default(),
+            })
+            .collect();
+
+        let result = ChatResult {
+            id: Some(api_response.id),
+            model: api_response
+                .model
+                .unwrap_or_else(|| self.model_name().to_string()),
+            system_fingerprint: api_response.system_fingerprint,
+            generations,
+            usage: api_response.usage,
+        };
+        if let Some(usage_tracker) = &self.usage_tracker {
+            usage_tracker.record(&result.model, result.usage.unwrap_or_default());
+        }
+        Ok(LlmResponse::Chat(result))
+    }
+
+    // The base URL and credentials do not change the answer, so they are left out.
+    fn identifying_params(&self) -> Value {
+        let mut params = serde_json::to_value(self.api_request(Vec::new())).unwrap_or_default();
+        params["deployment"] = json!(self.deployment());
+        params
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        match self.model_name {
+            Some(_) => None,
+            None => Some(self.model.context_window()),
+        }
+    }
+
+    fn max_tokens(&self) -> Option<u32> {
+        self.max_tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+    use serde_json::json;
+
+    use crate::{
+        client::RetryPolicy,
+        schemas::{
+            llm::TokenUsage,
+            messages::{HumanMessage, ToolCall, ToolMessage},
+        },
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_azure_deployment_request_and_error() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/openai/deployments/prod-gpt4/chat/completions")
+            .match_query(Matcher::UrlEncoded(
+                "api-version".into(),
+                "2024-02-01".into(),
+            ))
+            .match_header("api-key", "azure-key")
+            .with_status(404)
+            .with_body(
+                r#"{"error":{"code":"DeploymentNotFound","message":"The API deployment for this resource does not exist."}}"#,
+            )
+            .create_async()
+            .await;
+
+        let chat = ChatOpenAI::default()
+            .with_model(ChatModel::Gpt4)
+            .with_deployment("prod-gpt4")
+            .with_config(OpenAIConfig::azure(
+                &server.url(),
+                String::from("azure-key"),
+            ));
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        let result = chat.invoke(messages).await;
+
+        mock.assert_async().await;
+        match result {
+            Err(ApiError::OpenaiError(OpenaiError::DeploymentNotFound { code, detail })) => {
+                assert_eq!(code, 404);
+                assert_eq!(
+                    detail,
+                    "The API deployment for this resource does not exist."
+                );
             }
+            _ => panic!("expected a deployment not found error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_with_tools_returns_tool_calls() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/chat/completions")
+            .match_body(Matcher::PartialJson(json!({
+                "tools": [{
+                    "type": "function",
+                    "function": {
+                        "name": "get_weather",
+                        "description": "Current weather for a city",
+                        "parameters": {
+                            "type": "object",
+                            "properties": {"city": {"type": "string"}}
+                        }
+                    }
+                }],
+                "tool_choice": {"type": "function", "function": {"name": "get_weather"}},
+                "messages": [
+                    {"role": "user", "content": "Weather in Lima and Cusco?"},
+                    {
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [{
+                            "id": "call_0",
+                            "type": "function",
+                            "function": {"name": "get_weather", "arguments": "{\"city\":\"Lima\"}"}
+                        }]
+                    },
+                    {"role": "tool", "content": "22C", "tool_call_id": "call_0"}
+                ]
+            })))
+            .with_status(200)
+            .with_body(
+                json!({
+                    "id": "chatcmpl-1",
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "choices": [{
+                        "index": 0,
+                        "message": {
+                            "role": "assistant",
+                            "content": null,
+                            "tool_calls": [{
+                                "id": "call_1",
+                                "type": "function",
+                                "function": {"name": "get_weather", "arguments": "{\"city\":\"Cusco\"}"}
+                            }]
+                        },
+                        "finish_reason": "tool_calls"
+                    }],
+                    "usage": {"prompt_tokens": 30, "completion_tokens": 10, "total_tokens": 40}
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let chat = ChatOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base(&server.url()))
+            .with_tools(vec![FunctionDefinition::new(
+                "get_weather",
+                "Current weather for a city",
+                json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+            )])
+            .with_tool_choice(ToolChoice::Function(String::from("get_weather")));
+        let messages: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(HumanMessage::new("Weather in Lima and Cusco?")),
+            Box::new(AIMessage::new("").with_tool_calls(vec![ToolCall::new(
+                "call_0",
+                "get_weather",
+                r#"{"city":"Lima"}"#,
+            )])),
+            Box::new(ToolMessage::new("22C", "call_0")),
+        ];
+        let response = chat.invoke(messages).await.unwrap();
+
+        mock.assert_async().await;
+        match response {
+            LlmResponse::Chat(result) => assert_eq!(
+                result.tool_calls(),
+                vec![ToolCall::new(
+                    "call_1",
+                    "get_weather",
+                    r#"{"city":"Cusco"}"#
+                )]
+            ),
+            _ => panic!("expected tool calls"),
         }
     }
+
+    #[tokio::test]
+    async fn test_custom_model_name_is_sent() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/chat/completions")
+            .match_body(Matcher::PartialJson(
+                json!({"model": "meta-llama/Meta-Llama-3-8B-Instruct"}),
+            ))
+            .with_status(200)
+            .with_body(
+                json!({
+                    "id": "chatcmpl-1",
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "choices": [
+                        {"index": 0, "message": {"role": "assistant", "content": "Hi"}, "finish_reason": "stop"}
+                    ]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let chat = ChatOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base(&server.url()))
+            .with_model_name("meta-llama/Meta-Llama-3-8B-Instruct");
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        let result = match chat.invoke(messages).await.unwrap() {
+            LlmResponse::Chat(result) => result,
+            _ => panic!("expected a chat result"),
+        };
+
+        mock.assert_async().await;
+        assert_eq!(result.model, "meta-llama/Meta-Llama-3-8B-Instruct");
+        assert_eq!(chat.context_window(), None);
+    }
+
+    #[tokio::test]
+    async fn test_usage_tracker_records_and_enforces_the_budget() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/chat/completions")
+            .with_status(200)
+            .with_body(
+                json!({
+                    "id": "chatcmpl-1",
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "model": "gpt-3.5-turbo-0125",
+                    "choices": [
+                        {"index": 0, "message": {"role": "assistant", "content": "Hi"}, "finish_reason": "stop"}
+                    ],
+                    "usage": {"prompt_tokens": 8, "completion_tokens": 6, "total_tokens": 14}
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+
+        let tracker = UsageTracker::new().with_token_budget(10);
+        let chat = ChatOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base(&server.url()))
+            .with_usage_tracker(tracker.clone());
+        let messages = || -> Vec<Box<dyn BaseMessage>> { vec![Box::new(HumanMessage::new("Hi"))] };
+        chat.invoke(messages()).await.unwrap();
+
+        assert_eq!(tracker.usage()["gpt-3.5-turbo-0125"].total_tokens(), 14);
+        assert!(matches!(
+            chat.invoke(messages()).await,
+            Err(ApiError::BudgetExceeded(_))
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_returns_all_choices_and_usage() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/chat/completions")
+            .match_body(Matcher::PartialJson(json!({"n": 2})))
+            .with_status(200)
+            .with_body(
+                json!({
+                    "id": "chatcmpl-2",
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "model": "gpt-3.5-turbo-0125",
+                    "system_fingerprint": "fp_123",
+                    "choices": [
+                        {"index": 0, "message": {"role": "assistant", "content": "Hi"}, "finish_reason": "stop"},
+                        {"index": 1, "message": {"role": "assistant", "content": "Hello th"}, "finish_reason": "length"}
+                    ],
+                    "usage": {"prompt_tokens": 8, "completion_tokens": 6, "total_tokens": 14}
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let chat = ChatOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base(&server.url()))
+            .with_n(2);
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        let result = match chat.invoke(messages).await.unwrap() {
+            LlmResponse::Chat(result) => result,
+            _ => panic!("expected a chat result"),
+        };
+
+        assert_eq!(result.id.as_deref(), Some("chatcmpl-2"));
+        assert_eq!(result.model, "gpt-3.5-turbo-0125");
+        assert_eq!(result.system_fingerprint.as_deref(), Some("fp_123"));
+        assert_eq!(result.usage, Some(TokenUsage::new(8, 6)));
+        assert_eq!(result.text(), "Hi");
+        assert!(!result.is_truncated());
+        assert_eq!(result.generations[1].message.content, "Hello th");
+        assert!(result.generations[1].is_truncated());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_learns_from_headers() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/chat/completions")
+            .with_status(200)
+            .with_header("x-ratelimit-limit-requests", "600")
+            .with_header("x-ratelimit-remaining-requests", "0")
+            .with_body(
+                json!({
+                    "id": "chatcmpl-4",
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hi"}, "finish_reason": "stop"}]
+                })
+                .to_string(),
+            )
+            .expect(2)
+            .create_async()
+            .await;
+
+        let limiter = RateLimiter::from_headers();
+        let chat = ChatOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base(&server.url()))
+            .with_rate_limiter(limiter.clone());
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        chat.invoke(messages.clone()).await.unwrap();
+
+        // The server said no requests are left, so the next one waits for the
+        // refill of 600 requests per minute
+        let start = std::time::Instant::now();
+        chat.invoke(messages).await.unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(90));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_several_answers_in_a_stream() {
+        let chat = ChatOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base("http://127.0.0.1:1"))
+            .with_stream()
+            .with_n(2);
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        let result = chat.invoke(messages).await;
+        assert!(matches!(
+            result,
+            Err(ApiError::OpenaiError(OpenaiError::InvalidRequest { param: Some(ref param), .. })) if param == "n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_learns_from_429_and_throttles_retries() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/chat/completions")
+            .with_status(429)
+            .with_header("retry-after-ms", "0")
+            .with_header("x-ratelimit-limit-requests", "600")
+            .with_header("x-ratelimit-remaining-requests", "0")
+            .with_body(r#"{"error": {"message": "Rate limit reached", "type": "requests"}}"#)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let limiter = RateLimiter::from_headers();
+        let config = OpenAIConfig::new(String::from("key"))
+            .with_api_base(&server.url())
+            .with_retry_policy(RetryPolicy::new(2));
+        let chat = ChatOpenAI::default()
+            .with_config(config)
+            .with_rate_limiter(limiter);
+
+        // The retry waits for the limiter, not for the zero Retry-After
+        let start = std::time::Instant::now();
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        assert!(chat.invoke(messages).await.is_err());
+        assert!(start.elapsed() >= std::time::Duration::from_millis(90));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_sampling_params_and_logprobs() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/chat/completions")
+            .match_body(Matcher::PartialJson(json!({
+                "top_p": 0.5,
+                "frequency_penalty": 1.0,
+                "stop": ["END"],
+                "seed": 42,
+                "logit_bias": {"50256": -100},
+                "user": "user-1",
+                "logprobs": true,
+                "top_logprobs": 1,
+                "response_format": {
+                    "type": "json_schema",
+                    "json_schema": {"name": "answer", "schema": {"type": "object"}, "strict": true}
+                }
+            })))
+            .with_status(200)
+            .with_body(
+                json!({
+                    "id": "chatcmpl-3",
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "choices": [{
+                        "index": 0,
+                        "message": {"role": "assistant", "content": "{}"},
+                        "finish_reason": "stop",
+                        "logprobs": {"content": [{
+                            "token": "{}",
+                            "logprob": -0.25,
+                            "bytes": [123, 125],
+                            "top_logprobs": [{"token": "{}", "logprob": -0.25, "bytes": [123, 125]}]
+                        }]}
+                    }]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let chat = ChatOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base(&server.url()))
+            .with_top_p(0.5)
+            .with_frequency_penalty(1.0)
+            .with_stop(vec![String::from("END")])
+            .with_seed(42)
+            .with_logit_bias(HashMap::from([(50256, -100)]))
+            .with_user("user-1")
+            .with_logprobs(Some(1))
+            .with_response_format(ResponseFormat::json_schema(
+                "answer",
+                json!({"type": "object"}),
+            ));
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        let result = match chat.invoke(messages).await.unwrap() {
+            LlmResponse::Chat(result) => result,
+            _ => panic!("expected a chat result"),
+        };
+
+        mock.assert_async().await;
+        let logprobs = &result.generations[0].logprobs;
+        assert_eq!(logprobs.len(), 1);
+        assert_eq!(logprobs[0].logprob, -0.25);
+        assert_eq!(logprobs[0].top_logprobs[0].token, "{}");
+    }
+
+    #[tokio::test]
+    async fn test_stream_yields_typed_chunks() {
+        let mut server = mockito::Server::new_async().await;
+        let body = [
+            r#"{"choices":[{"index":0,"delta":{"role":"assistant","content":""},"finish_reason":null}],"usage":null}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":"Let me check"},"finish_reason":null}],"usage":null}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather","arguments":""}}]},"finish_reason":null}],"usage":null}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"city\":\"Lima\"}"}}]},"finish_reason":null}],"usage":null}"#,
+            r#"{"choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}],"usage":null}"#,
+            r#"{"choices":[],"usage":{"prompt_tokens":9,"completion_tokens":7,"total_tokens":16}}"#,
+            "[DONE]",
+        ]
+        .iter()
+        .map(|data| format!("data: {}\n\n", data))
+        .collect::<String>();
+        server
+            .mock("POST", "/chat/completions")
+            .match_body(Matcher::PartialJson(json!({
+                "stream": true,
+                "stream_options": {"include_usage": true}
+            })))
+            .with_status(200)
+            .with_header("content-type", "text/event-stream")
+            .with_body(body)
+            .create_async()
+            .await;
+
+        let chat = ChatOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base(&server.url()))
+            .with_stream();
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Lima?"))];
+        let stream = match chat.invoke(messages).await.unwrap() {
+            LlmResponse::Stream(stream) => stream,
+            _ => panic!("expected a stream"),
+        };
+        let result = ChatResult::from_stream(stream).await.unwrap();
+
+        assert_eq!(result.text(), "Let me check");
+        assert_eq!(
+            result.tool_calls(),
+            vec![ToolCall::new("call_1", "get_weather", r#"{"city":"Lima"}"#)]
+        );
+        assert_eq!(result.finish_reason(), Some("tool_calls"));
+        assert_eq!(result.usage, Some(TokenUsage::new(9, 7)));
+    }
+
+    #[tokio::test]
+    async fn test_stream_returns_typed_error_before_streaming() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/chat/completions")
+            .with_status(401)
+            .with_body(r#"{"error":{"message":"Incorrect API key provided: sk-..."}}"#)
+            .create_async()
+            .await;
+
+        let chat = ChatOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base(&server.url()))
+            .with_stream();
+        let messages: Vec<Box<dyn BaseMessage>> = vec![Box::new(HumanMessage::new("Hi"))];
+        let result = chat.invoke(messages).await;
+
+        assert!(matches!(
+            result,
+            Err(ApiError::OpenaiError(OpenaiError::IncorrectApiKey { .. }))
+        ));
+    }
 }
@@ -1,31 +1,116 @@
 use serde::{Deserialize, Serialize};
 
-use crate::schemas::messages::BaseMessage;
+use crate::schemas::messages::{BaseMessage, ContentPart, ToolCall};
+
+// OpenAI accepts either a plain string or a list of typed parts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+impl MessageContent {
+    pub fn into_text(self) -> String {
+        match self {
+            MessageContent::Text(text) => text,
+            MessageContent::Parts(parts) => parts
+                .into_iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text),
+                    _ => None,
+                })
+                .collect::<Vec<String>>()
+                .join(""),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
-    pub content: String,
+    pub content: Option<MessageContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 impl Message {
     pub fn new(role: String, content: String) -> Self {
-        Self { role, content }
+        Self {
+            role,
+            content: Some(MessageContent::Text(content)),
+            tool_calls: None,
+            tool_call_id: None,
+        }
     }
 
     pub fn from_base_message(base: Box<dyn BaseMessage>) -> Self {
+        let tool_calls = base.get_tool_calls();
+        let content = base.get_content();
+        let content_parts = base.get_content_parts();
         Message {
             role: base.get_type(),
-            content: base.get_content(),
+            // An assistant message that only calls tools has no content
+            content: if content.is_empty() && !tool_calls.is_empty() {
+                None
+            } else if content_parts.is_empty() {
+                Some(MessageContent::Text(content))
+            } else {
+                let mut parts = Vec::with_capacity(content_parts.len() + 1);
+                if !content.is_empty() {
+                    parts.push(ContentPart::Text { text: content });
+                }
+                parts.extend(content_parts);
+                Some(MessageContent::Parts(parts))
+            },
+            tool_calls: if tool_calls.is_empty() {
+                None
+            } else {
+                Some(tool_calls)
+            },
+            tool_call_id: base.get_tool_call_id(),
         }
     }
 
     pub fn from_base_messages(messages: Vec<Box<dyn BaseMessage>>) -> Vec<Self> {
-        messages
-            .into_iter()
-            .map(|base| Self {
-                role: base.get_type(),
-                content: base.get_content(),
+        messages.into_iter().map(Self::from_base_message).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::schemas::messages::{HumanMessage, ImageDetail};
+
+    use super::*;
+
+    #[test]
+    fn test_human_message_with_image_serializes_as_parts() {
+        let message = Message::from_base_message(Box::new(
+            HumanMessage::new("Describe it")
+                .with_image_url("https://example.com/a.png", Some(ImageDetail::Auto)),
+        ));
+
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            json!({
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "Describe it"},
+                    {"type": "image_url", "image_url": {"url": "https://example.com/a.png", "detail": "auto"}}
+                ]
             })
-            .collect()
+        );
+    }
+
+    #[test]
+    fn test_text_message_serializes_as_string() {
+        let message = Message::from_base_message(Box::new(HumanMessage::new("Hi")));
+
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            json!({"role": "user", "content": "Hi"})
+        );
     }
 }
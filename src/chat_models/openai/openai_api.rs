@@ -1,6 +1,16 @@
+use std::collections::HashMap;
+
 use super::message_type::Message;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::{json, Value};
+
+use crate::{
+    errors::{openai_errors::OpenaiError, ApiError},
+    schemas::{
+        llm::{ChatChunk, ResponseFormat, TokenLogprob, TokenUsage},
+        tools::{FunctionDefinition, ToolCallDelta, ToolChoice},
+    },
+};
 
 #[derive(Serialize, Debug)]
 pub struct ApiRequest {
@@ -9,7 +19,77 @@ pub struct ApiRequest {
     pub max_tokens: Option<u32>,
     pub temperature: f32,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub logit_bias: HashMap<u32, i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<ApiTool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<Value>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiTool {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: FunctionDefinition,
+}
+impl From<&FunctionDefinition> for ApiTool {
+    fn from(function: &FunctionDefinition) -> Self {
+        Self {
+            tool_type: String::from("function"),
+            function: function.clone(),
+        }
+    }
+}
+
+pub fn tool_choice_to_value(tool_choice: &ToolChoice) -> Value {
+    match tool_choice {
+        ToolChoice::Auto => json!("auto"),
+        ToolChoice::None => json!("none"),
+        ToolChoice::Required => json!("required"),
+        ToolChoice::Function(name) => json!({
+            "type": "function",
+            "function": { "name": name }
+        }),
+    }
+}
+
+pub fn response_format_to_value(response_format: &ResponseFormat) -> Value {
+    match response_format {
+        ResponseFormat::Text => json!({ "type": "text" }),
+        ResponseFormat::JsonObject => json!({ "type": "json_object" }),
+        ResponseFormat::JsonSchema {
+            name,
+            schema,
+            strict,
+        } => json!({
+            "type": "json_schema",
+            "json_schema": { "name": name, "schema": schema, "strict": strict }
+        }),
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -17,20 +97,74 @@ pub struct ApiResponse {
     pub id: String,
     pub object: String,
     pub created: u64,
+    pub model: Option<String>,
+    pub system_fingerprint: Option<String>,
     pub choices: Vec<ApiChoice>,
-    pub usage: ApiUsage,
+    pub usage: Option<TokenUsage>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct ApiChoice {
-    pub index: u8,
-    pub message: HashMap<String, String>,
-    pub finish_reason: String,
+    pub index: u32,
+    pub message: Message,
+    pub finish_reason: Option<String>,
+    pub logprobs: Option<ApiLogprobs>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiLogprobs {
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogprob>>,
 }
 
 #[derive(Deserialize, Debug)]
-pub struct ApiUsage {
-    prompt_tokens: u32,
-    completion_tokens: u32,
-    total_tokens: u32,
+pub struct StreamResponse {
+    pub choices: Vec<StreamChoice>,
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamChoice {
+    pub index: u32,
+    pub delta: Option<StreamDelta>,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamDelta {
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+// Turns the data of one server-sent event into chunks. `ChatOpenAI` refuses to
+// stream more than one choice, so only the first one is read; the final `[DONE]`
+// event carries nothing.
+pub fn stream_chunks(data: &str) -> Result<Vec<ChatChunk>, ApiError> {
+    if data == "[DONE]" {
+        return Ok(Vec::new());
+    }
+    let response: StreamResponse = serde_json::from_str(data).map_err(|_| {
+        ApiError::OpenaiError(OpenaiError::from_stream_event(data).unwrap_or_else(|| {
+            OpenaiError::new_generic_error(format!("Unexpected stream event: {}", data))
+        }))
+    })?;
+
+    let mut chunks = Vec::new();
+    for choice in response.choices.into_iter().filter(|c| c.index == 0) {
+        if let Some(delta) = choice.delta {
+            if let Some(content) = delta.content.filter(|c| !c.is_empty()) {
+                chunks.push(ChatChunk::Content(content));
+            }
+            for tool_call in delta.tool_calls.unwrap_or_default() {
+                chunks.push(ChatChunk::ToolCall(tool_call));
+            }
+        }
+        if let Some(finish_reason) = choice.finish_reason {
+            chunks.push(ChatChunk::Finish(finish_reason));
+        }
+    }
+    if let Some(usage) = response.usage {
+        chunks.push(ChatChunk::Usage(usage));
+    }
+    Ok(chunks)
 }
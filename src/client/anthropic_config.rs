@@ -0,0 +1,89 @@
+use std::env;
+
+use reqwest::{RequestBuilder, Response};
+
+use crate::errors::{anthropic_errors::AnthropicError, ApiError};
+
+use super::HttpConfig;
+
+pub const ANTHROPIC_API_BASE: &str = "https://api.anthropic.com/v1";
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+#[derive(Clone, Debug)]
+pub struct AnthropicConfig {
+    pub api_key: String,
+    pub api_base: String,
+    pub version: String,
+    pub http: HttpConfig,
+}
+
+impl AnthropicConfig {
+    pub fn new(api_key: String) -> Self {
+        Self {
+            api_key,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    pub fn with_api_base(mut self, api_base: &str) -> Self {
+        self.api_base = api_base.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    pub fn with_http_config(mut self, http: HttpConfig) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.api_base, path.trim_start_matches('/'))
+    }
+
+    pub(crate) fn post(&self, path: &str) -> Result<RequestBuilder, ApiError> {
+        let client = self.http.client().map_err(|e| {
+            ApiError::AnthropicError(AnthropicError::new_generic_error(format!(
+                "Error building HTTP client: {}",
+                e
+            )))
+        })?;
+
+        Ok(client
+            .post(self.url(path))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", &self.version))
+    }
+
+    // Sends with retries, turning error statuses into typed errors.
+    pub(crate) async fn send(&self, request: RequestBuilder) -> Result<Response, ApiError> {
+        self.http
+            .send(
+                request,
+                |code, body| ApiError::AnthropicError(AnthropicError::from_http_status(code, body)),
+                |msg| ApiError::AnthropicError(AnthropicError::new_generic_error(msg)),
+            )
+            .await
+    }
+}
+
+impl Default for AnthropicConfig {
+    fn default() -> Self {
+        Self {
+            api_key: env::var("ANTHROPIC_API_KEY").unwrap_or_default(),
+            api_base: env::var("ANTHROPIC_API_BASE")
+                .map(|base| base.trim_end_matches('/').to_string())
+                .unwrap_or_else(|_| ANTHROPIC_API_BASE.to_string()),
+            version: String::from(ANTHROPIC_VERSION),
+            http: HttpConfig::default(),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use reqwest::{Request, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+// Request headers that carry credentials. Their values are scrubbed from
+// everything written to a cassette.
+const SECRET_HEADERS: [&str; 3] = ["authorization", "api-key", "x-api-key"];
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CassetteMode {
+    // Sends the requests and appends every exchange to the cassette file
+    Record,
+    // Answers from the cassette file without touching the network
+    Replay,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Interaction {
+    request: RecordedRequest,
+    response: RecordedResponse,
+    // Set once replayed, so identical requests get the recorded responses in order
+    #[serde(skip)]
+    replayed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    url: String,
+    // The JSON body when there is one, so replays ignore key order and spacing
+    body: Value,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    // The full body, including every event of a streamed response
+    body: String,
+}
+
+/// Records the HTTP exchanges of a client to a JSON file, or replays them later
+/// without network access. Set it on any provider config through
+/// `HttpConfig::with_cassette`.
+///
+/// Replays match on the method, the URL and the body, compared as JSON. The
+/// values of the authentication headers are scrubbed from recordings and the
+/// request headers are not recorded at all.
+///
+/// Streamed responses are read to the end before being handed out while
+/// recording, and replayed from memory as one burst of events.
+#[derive(Clone, Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    interactions: Arc<Mutex<Vec<Interaction>>>,
+}
+
+impl Cassette {
+    /// Records to `path`, replacing any previous recording.
+    pub fn record(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            mode: CassetteMode::Record,
+            interactions: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn replay(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = std::fs::read(path.as_ref())?;
+        let interactions = serde_json::from_slice(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            mode: CassetteMode::Replay,
+            interactions: Arc::new(Mutex::new(interactions)),
+        })
+    }
+
+    /// Replays `path` if it exists, records it otherwise. Delete the file to
+    /// record it again.
+    pub fn auto(path: impl AsRef<Path>) -> io::Result<Self> {
+        if path.as_ref().exists() {
+            Self::replay(path)
+        } else {
+            Ok(Self::record(path))
+        }
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// Sends the request or replays it. The outer error is a failure of the
+    /// cassette itself, e.g. a request that was never recorded.
+    pub(crate) async fn send(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<reqwest::Result<Response>, String> {
+        let (client, request) = request.build_split();
+        let request = request.map_err(|e| format!("Invalid request: {}", e))?;
+        let secrets = secrets(&request);
+        let recorded = RecordedRequest {
+            method: request.method().to_string(),
+            url: scrub(request.url().as_str(), &secrets),
+            body: normalize_body(&request, &secrets),
+        };
+
+        match self.mode {
+            CassetteMode::Replay => self.replay_request(&recorded).await.map(Ok),
+            CassetteMode::Record => {
+                let response = match client.execute(request).await {
+                    Ok(response) => response,
+                    Err(e) => return Ok(Err(e)),
+                };
+                let status = response.status().as_u16();
+                let headers: Vec<(String, String)> = response
+                    .headers()
+                    .iter()
+                    .filter(|(name, _)| *name != "set-cookie")
+                    .filter_map(|(name, value)| {
+                        Some((name.to_string(), value.to_str().ok()?.to_string()))
+                    })
+                    .collect();
+                let body = match response.bytes().await {
+                    Ok(body) => body,
+                    Err(e) => return Ok(Err(e)),
+                };
+                let recorded_response = RecordedResponse {
+                    status,
+                    headers: headers
+                        .iter()
+                        .map(|(name, value)| (name.clone(), scrub(value, &secrets)))
+                        .collect(),
+                    body: scrub(&String::from_utf8_lossy(&body), &secrets),
+                };
+                self.save(Interaction {
+                    request: recorded,
+                    response: recorded_response,
+                    replayed: false,
+                })
+                .await;
+                // The caller gets the response as received, not the scrubbed one
+                Ok(Ok(to_response(status, &headers, body.to_vec())?))
+            }
+        }
+    }
+
+    async fn replay_request(&self, request: &RecordedRequest) -> Result<Response, String> {
+        let mut interactions = self.interactions.lock().await;
+        let matching: Vec<usize> = interactions
+            .iter()
+            .enumerate()
+            .filter(|(_, interaction)| interaction.request == *request)
+            .map(|(index, _)| index)
+            .collect();
+        // Identical requests get the next recorded response, then the last one again
+        let index = matching
+            .iter()
+            .find(|index| !interactions[**index].replayed)
+            .or(matching.last())
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "No recorded response for {} {} in {}",
+                    request.method,
+                    request.url,
+                    self.path.display()
+                )
+            })?;
+        interactions[index].replayed = true;
+        let response = &interactions[index].response;
+        to_response(
+            response.status,
+            &response.headers,
+            response.body.clone().into_bytes(),
+        )
+    }
+
+    // Rewrites the whole file, so it is valid JSON after every exchange.
+    async fn save(&self, interaction: Interaction) {
+        let mut interactions = self.interactions.lock().await;
+        interactions.push(interaction);
+        let result = async {
+            if let Some(dir) = self.path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            let contents = serde_json::to_string_pretty(&*interactions)?;
+            tokio::fs::write(&self.path, contents).await
+        }
+        .await;
+        if let Err(e) = result {
+            log::error!("Could not write cassette {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn secrets(request: &Request) -> Vec<String> {
+    SECRET_HEADERS
+        .iter()
+        .filter_map(|name| request.headers().get(*name)?.to_str().ok())
+        .map(|value| value.trim_start_matches("Bearer ").to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+fn scrub(text: &str, secrets: &[String]) -> String {
+    secrets.iter().fold(text.to_string(), |text, secret| {
+        text.replace(secret, REDACTED)
+    })
+}
+
+fn normalize_body(request: &Request, secrets: &[String]) -> Value {
+    let Some(body) = request.body().and_then(|body| body.as_bytes()) else {
+        return Value::Null;
+    };
+    let body = scrub(&String::from_utf8_lossy(body), secrets);
+    serde_json::from_str(&body).unwrap_or(Value::String(body))
+}
+
+fn to_response(
+    status: u16,
+    headers: &[(String, String)],
+    body: Vec<u8>,
+) -> Result<Response, String> {
+    let mut builder = http::Response::builder().status(status);
+    for (name, value) in headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    builder
+        .body(body)
+        .map(Response::from)
+        .map_err(|e| format!("Invalid recorded response: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use crate::{
+        chat_models::{chat_model_trait::ChatTrait, openai::chat_llm::ChatOpenAI},
+        client::OpenAIConfig,
+        errors::ApiError,
+        schemas::{
+            llm::{ChatChunk, LlmResponse},
+            messages::{BaseMessage, HumanMessage},
+        },
+    };
+
+    use super::*;
+
+    fn cassette_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("llm_rust_{}_{}.json", name, std::process::id()))
+    }
+
+    fn chat(url: &str, cassette: Cassette) -> ChatOpenAI {
+        ChatOpenAI::default().with_stream().with_config(
+            OpenAIConfig::new(String::from("sk-secret-key"))
+                .with_api_base(url)
+                .with_cassette(cassette),
+        )
+    }
+
+    fn messages() -> Vec<Box<dyn BaseMessage>> {
+        vec![Box::new(HumanMessage::new("hi"))]
+    }
+
+    async fn collect(chat: &ChatOpenAI) -> Result<Vec<ChatChunk>, ApiError> {
+        let LlmResponse::Stream(chat_stream) = chat.invoke(messages()).await? else {
+            panic!("expected a stream");
+        };
+        chat_stream.collect::<Vec<_>>().await.into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn test_records_and_replays_a_stream() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/chat/completions")
+            .with_header("content-type", "text/event-stream")
+            .with_body(concat!(
+                "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+                "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"}}]}\n\n",
+                "data: [DONE]\n\n",
+            ))
+            .expect(1)
+            .create_async()
+            .await;
+        let path = cassette_path("stream");
+
+        let recorded = collect(&chat(&server.url(), Cassette::record(&path)))
+            .await
+            .unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("sk-secret-key"));
+
+        let replayed = collect(&chat(&server.url(), Cassette::auto(&path).unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(recorded, replayed);
+        assert_eq!(replayed[0], ChatChunk::Content(String::from("Hel")));
+        mock.assert_async().await;
+
+        // A request that was not recorded fails instead of reaching the network
+        let other = chat(&server.url(), Cassette::replay(&path).unwrap()).with_max_tokens(5);
+        assert!(collect(&other).await.is_err());
+        mock.assert_async().await;
+        let _ = std::fs::remove_file(&path);
+    }
+}
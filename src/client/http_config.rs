@@ -0,0 +1,411 @@
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client, Proxy, RequestBuilder, Response,
+};
+
+use crate::errors::ApiError;
+
+use super::{
+    cassette::Cassette,
+    rate_limiter::Throttle,
+    retry::{self, RetryPolicy},
+};
+
+/// Transport settings shared by every provider client.
+///
+/// The underlying `reqwest::Client` is built lazily on first use and cached, so
+/// cloning an `HttpConfig` (or a provider config that embeds one) and handing it to
+/// several models makes them share a single connection pool.
+#[derive(Clone, Debug, Default)]
+pub struct HttpConfig {
+    headers: HeaderMap,
+    proxy: Option<String>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    pool_idle_timeout: Option<Duration>,
+    pool_max_idle_per_host: Option<usize>,
+    retry: RetryPolicy,
+    cassette: Option<Cassette>,
+    // Set by `with_client`, and kept when the other settings change
+    custom_client: Option<Client>,
+    client: Arc<OnceLock<Client>>,
+}
+
+impl HttpConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a header sent with every request. Invalid header names or values are
+    /// logged and ignored.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                self.headers.insert(name, value);
+            }
+            _ => log::warn!("Ignoring invalid default header: {}", name),
+        }
+        self.reset_client("default headers")
+    }
+
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers.extend(headers);
+        self.reset_client("default headers")
+    }
+
+    /// Routes all requests through the given proxy URL, e.g. `http://localhost:8080`.
+    pub fn with_proxy(mut self, proxy: &str) -> Self {
+        self.proxy = Some(proxy.to_string());
+        self.reset_client("the proxy")
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self.reset_client("the connect timeout")
+    }
+
+    /// Maximum time to wait for a complete non-streaming response. For streaming
+    /// responses it is the longest allowed gap between two chunks.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(timeout);
+        self.reset_client("the pool settings")
+    }
+
+    pub fn with_pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = Some(max);
+        self.reset_client("the pool settings")
+    }
+
+    /// Requests are not retried unless a policy with more than one attempt is set.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Records the requests to a cassette, or replays them from it.
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Uses an already configured `reqwest::Client` instead of building one. Default
+    /// headers, proxy, connect timeout and pool settings on this config are ignored
+    /// in that case, before or after this call, and a warning is logged for them.
+    /// The read timeout, retries and cassette still apply.
+    pub fn with_client(mut self, client: Client) -> Self {
+        if !self.headers.is_empty()
+            || self.proxy.is_some()
+            || self.connect_timeout.is_some()
+            || self.pool_idle_timeout.is_some()
+            || self.pool_max_idle_per_host.is_some()
+        {
+            log::warn!("The custom HTTP client ignores the headers, proxy, connect timeout and pool settings of the config");
+        }
+        self.custom_client = Some(client);
+        self.client = Arc::new(OnceLock::new());
+        self
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Returns the shared client, building it on first use.
+    pub fn client(&self) -> Result<Client, reqwest::Error> {
+        if let Some(client) = &self.custom_client {
+            return Ok(client.clone());
+        }
+        if let Some(client) = self.client.get() {
+            return Ok(client.clone());
+        }
+        let client = self.build_client()?;
+        Ok(self.client.get_or_init(|| client).clone())
+    }
+
+    /// Applies the read timeout to a request whose body is consumed in one go.
+    pub(crate) fn with_request_timeout(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.read_timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+
+    /// Sends the request, retrying it as the retry policy allows. Only successful
+    /// responses are returned; error statuses are turned into errors by
+    /// `map_status` and transport failures by `map_err`.
+    pub(crate) async fn send(
+        &self,
+        request: RequestBuilder,
+        map_status: impl Fn(u16, String) -> ApiError,
+        map_err: impl Fn(String) -> ApiError,
+    ) -> Result<Response, ApiError> {
+        self.send_throttled(request, None, map_status, map_err)
+            .await
+    }
+
+    /// Like `send`, taking the budget of `throttle` before every attempt and
+    /// updating it from the rate limit headers of every response, errors included.
+    pub(crate) async fn send_throttled(
+        &self,
+        request: RequestBuilder,
+        throttle: Option<Throttle<'_>>,
+        map_status: impl Fn(u16, String) -> ApiError,
+        map_err: impl Fn(String) -> ApiError,
+    ) -> Result<Response, ApiError> {
+        let mut attempt = 1;
+        loop {
+            if let Some(throttle) = throttle {
+                throttle
+                    .limiter
+                    .acquire(throttle.model, throttle.tokens)
+                    .await;
+            }
+            // Requests with a JSON body can always be cloned
+            let current = request
+                .try_clone()
+                .ok_or_else(|| map_err(String::from("The request cannot be cloned")))?;
+
+            let sent = match &self.cassette {
+                Some(cassette) => cassette.send(current).await.map_err(&map_err)?,
+                None => current.send().await,
+            };
+            if let (Some(throttle), Ok(response)) = (throttle, &sent) {
+                throttle.limiter.observe(throttle.model, response.headers());
+            }
+            let (error, retryable, retry_after) = match sent {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => {
+                    let code = response.status().as_u16();
+                    let retry_after = retry::retry_after(code, response.headers());
+                    let body = response.text().await.unwrap_or_default();
+                    let error = map_status(code, body);
+                    let retryable = error.is_retryable();
+                    (error, retryable, retry_after)
+                }
+                Err(e) if e.is_timeout() => (
+                    ApiError::Timeout(format!("Error sending request: {}", e)),
+                    true,
+                    None,
+                ),
+                Err(e) => {
+                    let retryable = e.is_connect();
+                    (
+                        map_err(format!("Error sending request: {}", e)),
+                        retryable,
+                        None,
+                    )
+                }
+            };
+
+            if !retryable || attempt >= self.retry.max_attempts {
+                return Err(error);
+            }
+            let Some(delay) = self.retry.delay(attempt, retry_after) else {
+                return Err(error);
+            };
+            log::warn!(
+                "Attempt {} of {} failed, retrying in {:?}: {}",
+                attempt,
+                self.retry.max_attempts,
+                delay,
+                error
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    fn build_client(&self) -> Result<Client, reqwest::Error> {
+        let mut builder = Client::builder().default_headers(self.headers.clone());
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        builder.build()
+    }
+
+    // Drops the cached client so the next request builds one with `setting`
+    fn reset_client(mut self, setting: &str) -> Self {
+        if self.custom_client.is_some() {
+            log::warn!(
+                "The custom HTTP client ignores {} set on the config",
+                setting
+            );
+        }
+        self.client = Arc::new(OnceLock::new());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::openai_errors::OpenaiError;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_custom_client_is_kept_by_later_settings() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/")
+            .match_header("x-custom", "1")
+            .with_status(200)
+            .create_async()
+            .await;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-custom", HeaderValue::from_static("1"));
+        let client = Client::builder().default_headers(headers).build().unwrap();
+
+        let config = HttpConfig::new()
+            .with_client(client)
+            .with_header("x-test", "1");
+        let response = config
+            .client()
+            .unwrap()
+            .get(server.url())
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn test_clones_share_client() {
+        let config = HttpConfig::new().with_connect_timeout(Duration::from_secs(5));
+        let cloned = config.clone();
+        config.client().unwrap();
+
+        assert!(cloned.client.get().is_some());
+    }
+
+    #[test]
+    fn test_builder_resets_client() {
+        let config = HttpConfig::new();
+        config.client().unwrap();
+        let config = config.with_header("x-test", "1");
+
+        assert!(config.client.get().is_none());
+    }
+
+    #[test]
+    fn test_invalid_proxy_fails_to_build() {
+        let config = HttpConfig::new().with_proxy("not a url");
+
+        assert!(config.client().is_err());
+    }
+
+    async fn openai_send(config: &HttpConfig, url: &str) -> Result<Response, ApiError> {
+        let request = config.client().unwrap().post(url).body("{}");
+        config
+            .send(
+                request,
+                |code, body| ApiError::OpenaiError(OpenaiError::from_http_status(code, body)),
+                |msg| ApiError::OpenaiError(OpenaiError::new_generic_error(msg)),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_rate_limits_until_success() {
+        let mut server = mockito::Server::new_async().await;
+        let limited = server
+            .mock("POST", "/")
+            .with_status(429)
+            .with_header("retry-after-ms", "10")
+            .with_body("Rate limit reached for requests")
+            .expect(2)
+            .create_async()
+            .await;
+        let ok = server
+            .mock("POST", "/")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let config = HttpConfig::new().with_retry_policy(RetryPolicy::new(3));
+        let response = openai_send(&config, &server.url()).await.unwrap();
+
+        assert_eq!(response.status(), 200);
+        limited.assert_async().await;
+        ok.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_send_does_not_retry_by_default() {
+        let mut server = mockito::Server::new_async().await;
+        let failing = server
+            .mock("POST", "/")
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let result = openai_send(&HttpConfig::new(), &server.url()).await;
+
+        assert!(result.is_err());
+        failing.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_send_does_not_retry_quota_errors() {
+        let mut server = mockito::Server::new_async().await;
+        let quota = server
+            .mock("POST", "/")
+            .with_status(429)
+            .with_body("You exceeded your current quota")
+            .expect(1)
+            .create_async()
+            .await;
+
+        let config = HttpConfig::new().with_retry_policy(RetryPolicy::new(3));
+        let result = openai_send(&config, &server.url()).await;
+
+        assert!(matches!(
+            result,
+            Err(ApiError::OpenaiError(OpenaiError::QuotaExceeded { .. }))
+        ));
+        quota.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_connection_errors() {
+        // Nothing listens on port 9 of localhost, so every attempt is refused
+        let config = HttpConfig::new()
+            .with_retry_policy(RetryPolicy::new(2).with_initial_backoff(Duration::from_millis(10)));
+        let start = std::time::Instant::now();
+        let result = openai_send(&config, "http://127.0.0.1:9").await;
+
+        assert!(matches!(
+            result,
+            Err(ApiError::OpenaiError(OpenaiError::GenericError(_)))
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(5));
+    }
+}
@@ -0,0 +1,15 @@
+pub mod anthropic_config;
+pub mod cassette;
+pub mod http_config;
+pub mod ollama_config;
+pub mod openai_config;
+pub mod rate_limiter;
+pub mod retry;
+pub(crate) mod stream;
+pub use anthropic_config::AnthropicConfig;
+pub use cassette::{Cassette, CassetteMode};
+pub use http_config::HttpConfig;
+pub use ollama_config::OllamaConfig;
+pub use openai_config::{OpenAIApiType, OpenAIConfig};
+pub use rate_limiter::{RateLimit, RateLimiter};
+pub use retry::RetryPolicy;
@@ -0,0 +1,90 @@
+use std::env;
+
+use reqwest::{RequestBuilder, Response};
+
+use crate::errors::{ollama_errors::OllamaError, ApiError};
+
+use super::HttpConfig;
+
+pub const OLLAMA_API_BASE: &str = "http://localhost:11434";
+
+#[derive(Clone, Debug)]
+pub struct OllamaConfig {
+    pub api_base: String,
+    pub http: HttpConfig,
+}
+
+impl OllamaConfig {
+    pub fn new(api_base: &str) -> Self {
+        Self::default().with_api_base(api_base)
+    }
+
+    pub fn with_api_base(mut self, api_base: &str) -> Self {
+        self.api_base = normalize_api_base(api_base);
+        self
+    }
+
+    pub fn with_http_config(mut self, http: HttpConfig) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.api_base, path.trim_start_matches('/'))
+    }
+
+    pub(crate) fn post(&self, path: &str) -> Result<RequestBuilder, ApiError> {
+        let client = self.http.client().map_err(|e| {
+            ApiError::OllamaError(OllamaError::new_generic_error(format!(
+                "Error building HTTP client: {}",
+                e
+            )))
+        })?;
+
+        Ok(client.post(self.url(path)))
+    }
+
+    // Sends with retries, turning error statuses into typed errors.
+    pub(crate) async fn send(&self, request: RequestBuilder) -> Result<Response, ApiError> {
+        self.http
+            .send(
+                request,
+                |code, body| ApiError::OllamaError(OllamaError::from_http_status(code, body)),
+                |msg| ApiError::OllamaError(OllamaError::new_generic_error(msg)),
+            )
+            .await
+    }
+}
+
+// OLLAMA_HOST is usually set without a scheme, e.g. `0.0.0.0:11434`.
+fn normalize_api_base(api_base: &str) -> String {
+    let api_base = api_base.trim_end_matches('/');
+    if api_base.contains("://") {
+        api_base.to_string()
+    } else {
+        format!("http://{}", api_base)
+    }
+}
+
+impl Default for OllamaConfig {
+    fn default() -> Self {
+        Self {
+            api_base: env::var("OLLAMA_HOST")
+                .map(|host| normalize_api_base(&host))
+                .unwrap_or_else(|_| OLLAMA_API_BASE.to_string()),
+            http: HttpConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_base_without_scheme() {
+        let config = OllamaConfig::new("127.0.0.1:11434/");
+
+        assert_eq!(config.url("/api/chat"), "http://127.0.0.1:11434/api/chat");
+    }
+}
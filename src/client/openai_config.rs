@@ -0,0 +1,247 @@
+use std::{env, time::Duration};
+
+use reqwest::{RequestBuilder, Response};
+
+use crate::errors::{openai_errors::OpenaiError, ApiError};
+
+use super::{rate_limiter::Throttle, Cassette, HttpConfig, RetryPolicy};
+
+pub const OPENAI_API_BASE: &str = "https://api.openai.com/v1";
+pub const AZURE_API_VERSION: &str = "2024-02-01";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OpenAIApiType {
+    OpenAI,
+    // Requests go to `{api_base}/openai/deployments/{deployment}/...`, where
+    // `api_base` is the resource endpoint, e.g. `https://my-resource.openai.azure.com`.
+    Azure { api_version: String },
+}
+
+/// Connection settings for the OpenAI API or any server exposing an
+/// OpenAI-compatible API (vLLM, LM Studio, a local mock...).
+#[derive(Clone, Debug)]
+pub struct OpenAIConfig {
+    pub api_key: String,
+    pub api_base: String,
+    pub organization: Option<String>,
+    pub project: Option<String>,
+    pub api_type: OpenAIApiType,
+    pub http: HttpConfig,
+}
+
+impl OpenAIConfig {
+    pub fn new(api_key: String) -> Self {
+        Self {
+            api_key,
+            ..Default::default()
+        }
+    }
+
+    pub fn azure(endpoint: &str, api_key: String) -> Self {
+        Self::new(api_key)
+            .with_api_base(endpoint)
+            .with_azure_api_version(AZURE_API_VERSION)
+    }
+
+    /// Reads AZURE_OPENAI_ENDPOINT, AZURE_OPENAI_API_KEY and, optionally,
+    /// OPENAI_API_VERSION.
+    pub fn azure_from_env() -> Self {
+        let config = Self::azure(
+            &env::var("AZURE_OPENAI_ENDPOINT").unwrap_or_default(),
+            env::var("AZURE_OPENAI_API_KEY").unwrap_or_default(),
+        );
+        match env::var("OPENAI_API_VERSION") {
+            Ok(api_version) => config.with_azure_api_version(&api_version),
+            Err(_) => config,
+        }
+    }
+
+    pub fn with_azure_api_version(mut self, api_version: &str) -> Self {
+        self.api_type = OpenAIApiType::Azure {
+            api_version: api_version.to_string(),
+        };
+        self
+    }
+
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    pub fn with_api_base(mut self, api_base: &str) -> Self {
+        self.api_base = api_base.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_organization(mut self, organization: &str) -> Self {
+        self.organization = Some(organization.to_string());
+        self
+    }
+
+    pub fn with_project(mut self, project: &str) -> Self {
+        self.project = Some(project.to_string());
+        self
+    }
+
+    pub fn with_http_config(mut self, http: HttpConfig) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.http = self.http.with_header(name, value);
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: &str) -> Self {
+        self.http = self.http.with_proxy(proxy);
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.http = self.http.with_connect_timeout(timeout);
+        self
+    }
+
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.http = self.http.with_read_timeout(timeout);
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.http = self.http.with_retry_policy(retry);
+        self
+    }
+
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.http = self.http.with_cassette(cassette);
+        self
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.api_base, path.trim_start_matches('/'))
+    }
+
+    /// Builds an authenticated POST request to `path`. On Azure the request is
+    /// routed to `deployment`; on OpenAI the deployment is ignored.
+    pub(crate) fn post(&self, path: &str, deployment: &str) -> Result<RequestBuilder, ApiError> {
+        let client = self.http.client().map_err(|e| {
+            ApiError::OpenaiError(OpenaiError::new_generic_error(format!(
+                "Error building HTTP client: {}",
+                e
+            )))
+        })?;
+
+        if let OpenAIApiType::Azure { api_version } = &self.api_type {
+            let url = self.url(&format!("openai/deployments/{}/{}", deployment, path));
+            return Ok(client
+                .post(url)
+                .query(&[("api-version", api_version)])
+                .header("api-key", &self.api_key));
+        }
+
+        let mut request = client.post(self.url(path)).bearer_auth(&self.api_key);
+        if let Some(organization) = &self.organization {
+            request = request.header("OpenAI-Organization", organization);
+        }
+        if let Some(project) = &self.project {
+            request = request.header("OpenAI-Project", project);
+        }
+        Ok(request)
+    }
+
+    pub(crate) fn error_from_status(&self, code: u16, body: String) -> OpenaiError {
+        match self.api_type {
+            OpenAIApiType::OpenAI => OpenaiError::from_http_status(code, body),
+            OpenAIApiType::Azure { .. } => OpenaiError::from_azure_http_status(code, body),
+        }
+    }
+
+    // Sends with retries, turning error statuses into typed errors.
+    pub(crate) async fn send(&self, request: RequestBuilder) -> Result<Response, ApiError> {
+        self.send_throttled(request, None).await
+    }
+
+    // Like `send`, within the budget of a rate limiter.
+    pub(crate) async fn send_throttled(
+        &self,
+        request: RequestBuilder,
+        throttle: Option<Throttle<'_>>,
+    ) -> Result<Response, ApiError> {
+        self.http
+            .send_throttled(
+                request,
+                throttle,
+                |code, body| ApiError::OpenaiError(self.error_from_status(code, body)),
+                |msg| ApiError::OpenaiError(OpenaiError::new_generic_error(msg)),
+            )
+            .await
+    }
+}
+
+impl Default for OpenAIConfig {
+    fn default() -> Self {
+        Self {
+            api_key: env::var("OPENAI_API_KEY").unwrap_or_default(),
+            api_base: env::var("OPENAI_API_BASE")
+                .map(|base| base.trim_end_matches('/').to_string())
+                .unwrap_or_else(|_| OPENAI_API_BASE.to_string()),
+            organization: env::var("OPENAI_ORG_ID").ok(),
+            project: env::var("OPENAI_PROJECT_ID").ok(),
+            api_type: OpenAIApiType::OpenAI,
+            http: HttpConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_joins_base_and_path() {
+        let config =
+            OpenAIConfig::new(String::from("key")).with_api_base("http://localhost:8000/v1/");
+
+        assert_eq!(
+            config.url("/chat/completions"),
+            "http://localhost:8000/v1/chat/completions"
+        );
+    }
+
+    #[test]
+    fn test_post_sets_auth_and_organization_headers() {
+        let config = OpenAIConfig::new(String::from("key"))
+            .with_organization("org-1")
+            .with_project("proj-1");
+
+        let request = config
+            .post("embeddings", "text-embedding-ada-002")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(request.headers()["authorization"], "Bearer key");
+        assert_eq!(request.headers()["openai-organization"], "org-1");
+        assert_eq!(request.headers()["openai-project"], "proj-1");
+    }
+
+    #[test]
+    fn test_azure_post_uses_deployment_url_and_api_key() {
+        let config = OpenAIConfig::azure("https://res.openai.azure.com/", String::from("key"))
+            .with_azure_api_version("2024-06-01");
+
+        let request = config
+            .post("chat/completions", "my-gpt4")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            request.url().as_str(),
+            "https://res.openai.azure.com/openai/deployments/my-gpt4/chat/completions?api-version=2024-06-01"
+        );
+        assert_eq!(request.headers()["api-key"], "key");
+        assert!(request.headers().get("authorization").is_none());
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::header::HeaderMap;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateLimit {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+impl RateLimit {
+    pub fn new(requests_per_minute: u32, tokens_per_minute: u32) -> Self {
+        Self {
+            requests_per_minute: Some(requests_per_minute),
+            tokens_per_minute: Some(tokens_per_minute),
+        }
+    }
+
+    pub fn requests_per_minute(requests_per_minute: u32) -> Self {
+        Self {
+            requests_per_minute: Some(requests_per_minute),
+            tokens_per_minute: None,
+        }
+    }
+
+    pub fn tokens_per_minute(tokens_per_minute: u32) -> Self {
+        Self {
+            requests_per_minute: None,
+            tokens_per_minute: Some(tokens_per_minute),
+        }
+    }
+}
+
+/// Client-side throttling of requests and tokens per minute, tracked per model.
+///
+/// Clones share their state, so one limiter can be attached to every client and
+/// executor of a process. Besides the configured limits, the limiter follows the
+/// `x-ratelimit-*` headers returned by the API: models without a configured
+/// limit adopt the one reported by the server, and the remaining budget is
+/// lowered to what the server says is left.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    default_limit: RateLimit,
+    model_limits: HashMap<String, RateLimit>,
+    state: Arc<Mutex<HashMap<String, ModelState>>>,
+}
+
+impl RateLimiter {
+    pub fn new(default_limit: RateLimit) -> Self {
+        Self {
+            default_limit,
+            ..Default::default()
+        }
+    }
+
+    /// Only learns the limits from the response headers.
+    pub fn from_headers() -> Self {
+        Self::default()
+    }
+
+    pub fn with_model_limit(mut self, model: &str, limit: RateLimit) -> Self {
+        self.model_limits.insert(model.to_string(), limit);
+        self
+    }
+
+    /// Waits until `model` has budget for one request of `tokens` estimated
+    /// tokens, then takes it.
+    pub async fn acquire(&self, model: &str, tokens: u32) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                let limit = self.limit(model);
+                state
+                    .entry(model.to_string())
+                    .or_insert_with(|| ModelState::new(limit))
+                    .try_acquire(tokens, Instant::now())
+            };
+            match wait {
+                None => return,
+                Some(wait) => {
+                    log::debug!("Rate limit reached for {}, waiting {:?}", model, wait);
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+    }
+
+    /// Updates the budget of `model` from the `x-ratelimit-*` headers of a response.
+    pub fn observe(&self, model: &str, headers: &HeaderMap) {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<f64>().ok())
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let limit = self.limit(model);
+        let model_state = state
+            .entry(model.to_string())
+            .or_insert_with(|| ModelState::new(limit));
+        let now = Instant::now();
+        model_state.requests.learn(
+            header("x-ratelimit-limit-requests"),
+            header("x-ratelimit-remaining-requests"),
+            now,
+        );
+        model_state.tokens.learn(
+            header("x-ratelimit-limit-tokens"),
+            header("x-ratelimit-remaining-tokens"),
+            now,
+        );
+    }
+
+    fn limit(&self, model: &str) -> RateLimit {
+        self.model_limits
+            .get(model)
+            .copied()
+            .unwrap_or(self.default_limit)
+    }
+}
+
+// What one request takes from a `RateLimiter`, on every attempt
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Throttle<'a> {
+    pub limiter: &'a RateLimiter,
+    pub model: &'a str,
+    pub tokens: u32,
+}
+impl<'a> Throttle<'a> {
+    // Counts the tokens only when there is a limiter
+    pub(crate) fn new(
+        limiter: Option<&'a RateLimiter>,
+        model: &'a str,
+        tokens: impl FnOnce() -> u32,
+    ) -> Option<Self> {
+        limiter.map(|limiter| Self {
+            limiter,
+            model,
+            tokens: tokens(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ModelState {
+    requests: Bucket,
+    tokens: Bucket,
+}
+impl ModelState {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            requests: Bucket::new(limit.requests_per_minute),
+            tokens: Bucket::new(limit.tokens_per_minute),
+        }
+    }
+
+    // Takes the budget and returns None, or returns how long to wait for it.
+    fn try_acquire(&mut self, tokens: u32, now: Instant) -> Option<Duration> {
+        let wait = self
+            .requests
+            .wait_for(1.0, now)
+            .max(self.tokens.wait_for(tokens as f64, now));
+        if wait > Duration::ZERO {
+            return Some(wait);
+        }
+        self.requests.take(1.0);
+        self.tokens.take(tokens as f64);
+        None
+    }
+}
+
+// A token bucket holding a minute worth of budget and refilled continuously.
+// Without a capacity it never limits.
+#[derive(Debug)]
+struct Bucket {
+    capacity: Option<f64>,
+    available: f64,
+    updated: Instant,
+}
+impl Bucket {
+    fn new(per_minute: Option<u32>) -> Self {
+        let capacity = per_minute.map(f64::from);
+        Self {
+            capacity,
+            available: capacity.unwrap_or_default(),
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(capacity) = self.capacity {
+            let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+            self.available = (self.available + elapsed * capacity / 60.0).min(capacity);
+        }
+        self.updated = now;
+    }
+
+    fn wait_for(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let Some(capacity) = self.capacity else {
+            return Duration::ZERO;
+        };
+        // A request larger than the whole budget goes through once it is full
+        let missing = amount.min(capacity) - self.available;
+        if missing <= 0.0 || capacity <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing * 60.0 / capacity)
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        if self.capacity.is_some() {
+            self.available -= amount;
+        }
+    }
+
+    fn learn(&mut self, limit: Option<f64>, remaining: Option<f64>, now: Instant) {
+        self.refill(now);
+        if self.capacity.is_none() {
+            if let Some(limit) = limit {
+                self.capacity = Some(limit);
+                self.available = remaining.unwrap_or(limit);
+                return;
+            }
+        }
+        if let (Some(capacity), Some(remaining)) = (self.capacity, remaining) {
+            self.available = self.available.min(remaining.min(capacity));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_waits_for_requests_and_tokens() {
+        let start = Instant::now();
+        let mut state = ModelState::new(RateLimit::new(2, 600));
+
+        assert_eq!(state.try_acquire(100, start), None);
+        assert_eq!(state.try_acquire(100, start), None);
+        // Out of requests: one comes back every 30 seconds
+        assert_eq!(state.try_acquire(100, start), Some(Duration::from_secs(30)));
+
+        let later = start + Duration::from_secs(30);
+        assert_eq!(state.try_acquire(100, later), None);
+        // A minute in, the tokens are back to the full 600
+        let minute = start + Duration::from_secs(60);
+        assert_eq!(state.try_acquire(600, minute), None);
+        // 450 tokens come back in 45 seconds, longer than a request takes
+        assert_eq!(
+            state.try_acquire(450, minute),
+            Some(Duration::from_secs(45))
+        );
+    }
+
+    #[test]
+    fn test_learns_from_headers() {
+        let limiter = RateLimiter::from_headers();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-limit-requests", HeaderValue::from_static("60"));
+        headers.insert(
+            "x-ratelimit-remaining-requests",
+            HeaderValue::from_static("0"),
+        );
+
+        limiter.observe("gpt-4", &headers);
+
+        let mut state = limiter.state.lock().unwrap();
+        let wait = state
+            .get_mut("gpt-4")
+            .unwrap()
+            .try_acquire(10, Instant::now())
+            .unwrap();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        // Tokens are still unlimited, no header reported them
+        assert!(state.get("gpt-4").unwrap().tokens.capacity.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_clones_share_budget() {
+        let limiter = RateLimiter::new(RateLimit::requests_per_minute(600));
+        let other = limiter.clone();
+        for _ in 0..600 {
+            limiter.acquire("gpt-4", 0).await;
+        }
+
+        let start = Instant::now();
+        other.acquire("gpt-4", 0).await;
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+}
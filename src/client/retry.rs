@@ -0,0 +1,234 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use reqwest::header::HeaderMap;
+
+/// How failed requests are retried. Rate limits, overloaded or failing servers
+/// and transient network errors are retried with jittered exponential backoff;
+/// quota, authentication and invalid request errors never are. The default
+/// policy makes a single attempt; `RetryPolicy::new(3)` opts in to retries.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // Including the first attempt, so 1 disables retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    // Longest wait requested by the server that is honored. The error is
+    // returned right away when Retry-After asks to wait longer; a longer
+    // x-ratelimit-reset-* falls back to the backoff.
+    pub max_retry_after: Duration,
+}
+
+// The wait the server asked for before the next attempt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ServerWait {
+    // `retry-after-ms` or `Retry-After`
+    RetryAfter(Duration),
+    // The `x-ratelimit-reset-*` headers of a rate limit: the time until the whole
+    // window resets, often minutes, so only a hint
+    Reset(Duration),
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            ..Default::default()
+        }
+    }
+
+    pub fn none() -> Self {
+        Self::new(1)
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn with_max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.max_retry_after = max_retry_after;
+        self
+    }
+
+    // The wait before the next attempt, after `attempt` attempts failed, or None
+    // when Retry-After asked to wait for longer than `max_retry_after`.
+    pub(crate) fn delay(&self, attempt: u32, wait: Option<ServerWait>) -> Option<Duration> {
+        match wait {
+            Some(ServerWait::RetryAfter(wait)) if wait > self.max_retry_after => None,
+            Some(ServerWait::RetryAfter(wait)) => Some(wait),
+            Some(ServerWait::Reset(wait)) if wait <= self.max_retry_after => Some(wait),
+            Some(ServerWait::Reset(_)) | None => Some(self.backoff(attempt)),
+        }
+    }
+
+    // Exponential backoff with "equal jitter": half of the delay is fixed and the
+    // other half random, so concurrent clients spread out without retrying at once.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(32) as i32;
+        let backoff = self
+            .initial_backoff
+            .mul_f64(self.multiplier.max(1.0).powi(exponent))
+            .min(self.max_backoff);
+        let half = backoff / 2;
+        half + half.mul_f64(random_fraction())
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            max_retry_after: Duration::from_secs(60),
+        }
+    }
+}
+
+// A number in [0, 1), good enough for jitter without pulling in a RNG crate.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The wait requested by the server: `retry-after-ms`, `Retry-After` in seconds
+/// or, for rate limits (429), the longest of the `x-ratelimit-reset-*` headers.
+pub(crate) fn retry_after(status: u16, headers: &HeaderMap) -> Option<ServerWait> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(ms) = header("retry-after-ms").and_then(|ms| ms.trim().parse::<f64>().ok()) {
+        let wait = Duration::from_secs_f64(ms.max(0.0) / 1000.0);
+        return Some(ServerWait::RetryAfter(wait));
+    }
+    if let Some(secs) = header("retry-after").and_then(|secs| secs.trim().parse::<f64>().ok()) {
+        let wait = Duration::from_secs_f64(secs.max(0.0));
+        return Some(ServerWait::RetryAfter(wait));
+    }
+    if status != 429 {
+        return None;
+    }
+    ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"]
+        .iter()
+        .filter_map(|name| header(name).and_then(parse_reset))
+        .max()
+        .map(ServerWait::Reset)
+}
+
+// OpenAI sends resets as durations such as "1s", "6m0s", "20ms" or "1h2m3.5s".
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_len] {
+            "h" => number * 3600.0,
+            "m" => number * 60.0,
+            "s" => number,
+            "ms" => number / 1000.0,
+            _ => return None,
+        };
+        total += Duration::from_secs_f64(seconds);
+        rest = &rest[unit_len..];
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_retry_after_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-ratelimit-reset-requests",
+            HeaderValue::from_static("1m30s"),
+        );
+        headers.insert(
+            "x-ratelimit-reset-tokens",
+            HeaderValue::from_static("250ms"),
+        );
+        assert_eq!(
+            retry_after(429, &headers),
+            Some(ServerWait::Reset(Duration::from_secs(90)))
+        );
+        // The resets only describe the rate limit windows
+        assert_eq!(retry_after(500, &headers), None);
+
+        headers.insert("retry-after", HeaderValue::from_static("2"));
+        assert_eq!(
+            retry_after(503, &headers),
+            Some(ServerWait::RetryAfter(Duration::from_secs(2)))
+        );
+
+        headers.insert("retry-after-ms", HeaderValue::from_static("150"));
+        assert_eq!(
+            retry_after(429, &headers),
+            Some(ServerWait::RetryAfter(Duration::from_millis(150)))
+        );
+    }
+
+    #[test]
+    fn test_long_reset_falls_back_to_backoff() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("6m0s"));
+        let wait = retry_after(429, &headers);
+        assert_eq!(wait, Some(ServerWait::Reset(Duration::from_secs(360))));
+
+        let policy = RetryPolicy::default().with_initial_backoff(Duration::from_secs(1));
+        let delay = policy.delay(1, wait).unwrap();
+        assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_backoff_grows_with_jitter_and_cap() {
+        let policy = RetryPolicy::default()
+            .with_initial_backoff(Duration::from_secs(1))
+            .with_max_backoff(Duration::from_secs(3));
+
+        let first = policy.delay(1, None).unwrap();
+        assert!(first >= Duration::from_millis(500) && first <= Duration::from_secs(1));
+        let second = policy.delay(2, None).unwrap();
+        assert!(second >= Duration::from_secs(1) && second <= Duration::from_secs(2));
+        let capped = policy.delay(10, None).unwrap();
+        assert!(capped >= Duration::from_millis(1500) && capped <= Duration::from_secs(3));
+
+        let wait = |secs| Some(ServerWait::RetryAfter(Duration::from_secs(secs)));
+        assert_eq!(policy.delay(1, wait(5)), Some(Duration::from_secs(5)));
+        assert_eq!(policy.delay(1, wait(600)), None);
+    }
+}
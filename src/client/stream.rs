@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use eventsource_stream::Eventsource;
+use futures::{stream, stream::BoxStream, StreamExt};
+use reqwest::Response;
+
+use crate::{errors::ApiError, schemas::llm::ChatChunk};
+
+/// Yields the `data` field of every server-sent event in the response body.
+/// Transport and framing errors are turned into library errors with `map_err`.
+pub(crate) fn sse_data(
+    response: Response,
+    map_err: fn(String) -> ApiError,
+) -> BoxStream<'static, Result<String, ApiError>> {
+    response
+        .bytes_stream()
+        .eventsource()
+        .map(move |event| match event {
+            Ok(event) => Ok(event.data),
+            Err(e) => Err(map_err(format!("Error reading event stream: {}", e))),
+        })
+        .boxed()
+}
+
+/// Splits a newline-delimited JSON response body into its lines, skipping blank ones.
+pub(crate) fn json_lines(
+    response: Response,
+    map_err: fn(String) -> ApiError,
+) -> BoxStream<'static, Result<String, ApiError>> {
+    let state = (response.bytes_stream().boxed(), Vec::<u8>::new());
+
+    stream::unfold(Some(state), move |state| async move {
+        let (mut bytes, mut buffer) = state?;
+        loop {
+            if let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line).trim().to_string();
+                if line.is_empty() {
+                    continue;
+                }
+                return Some((Ok(line), Some((bytes, buffer))));
+            }
+
+            match bytes.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    return Some((Err(map_err(format!("Error reading stream: {}", e))), None))
+                }
+                None => {
+                    let line = String::from_utf8_lossy(&buffer).trim().to_string();
+                    if line.is_empty() {
+                        return None;
+                    }
+                    return Some((Ok(line), None));
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Decodes every payload into zero or more chunks with a provider specific parser.
+pub(crate) fn decode_chunks<F>(
+    payloads: BoxStream<'static, Result<String, ApiError>>,
+    mut decode: F,
+) -> BoxStream<'static, Result<ChatChunk, ApiError>>
+where
+    F: FnMut(&str) -> Result<Vec<ChatChunk>, ApiError> + Send + 'static,
+{
+    payloads
+        .flat_map(move |payload| {
+            let chunks: Vec<Result<ChatChunk, ApiError>> =
+                match payload.and_then(|data| decode(&data)) {
+                    Ok(chunks) => chunks.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                };
+            stream::iter(chunks)
+        })
+        .boxed()
+}
+
+/// Ends the stream with an error when no item arrives within `timeout`.
+pub(crate) fn with_idle_timeout<T: Send + 'static>(
+    inner: BoxStream<'static, Result<T, ApiError>>,
+    timeout: Option<Duration>,
+) -> BoxStream<'static, Result<T, ApiError>> {
+    let Some(timeout) = timeout else {
+        return inner;
+    };
+
+    stream::unfold(Some(inner), move |inner| async move {
+        let mut inner = inner?;
+        match tokio::time::timeout(timeout, inner.next()).await {
+            Ok(Some(item)) => Some((item, Some(inner))),
+            Ok(None) => None,
+            Err(_) => Some((
+                Err(ApiError::Timeout(format!(
+                    "No data received from the stream for {:?}",
+                    timeout
+                ))),
+                None,
+            )),
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_idle_timeout_ends_stalled_stream() {
+        let stalled = stream::iter(vec![Ok(1)]).chain(stream::pending()).boxed();
+        let mut timed = with_idle_timeout(stalled, Some(Duration::from_millis(20)));
+
+        assert!(matches!(timed.next().await, Some(Ok(1))));
+        assert!(matches!(
+            timed.next().await,
+            Some(Err(ApiError::Timeout(_)))
+        ));
+        assert!(timed.next().await.is_none());
+    }
+}
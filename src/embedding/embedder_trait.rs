@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use serde_json::Value;
 
 use crate::errors::ApiError;
 
@@ -6,4 +7,9 @@ use crate::errors::ApiError;
 pub trait Embedder: Send + Sync {
     async fn embed_documents(&self, documents: Vec<String>) -> Result<Vec<Vec<f64>>, ApiError>;
     async fn embed_query(&self, text: &str) -> Result<Vec<f64>, ApiError>;
+
+    /// The model and the settings that shape the embeddings, used in cache keys.
+    fn identifying_params(&self) -> Value {
+        Value::Null
+    }
 }
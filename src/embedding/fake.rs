@@ -0,0 +1,105 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{embedding::embedder_trait::Embedder, errors::ApiError};
+
+/// An embedder for tests that derives a unit vector from a hash of each text:
+/// the same text always gets the same embedding, on every run and platform.
+/// Records the texts of every call. Clones share the recorded texts.
+#[derive(Clone)]
+pub struct FakeEmbedder {
+    dimensions: usize,
+    calls: Arc<Mutex<Vec<Vec<String>>>>,
+}
+
+impl FakeEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        Self {
+            dimensions,
+            calls: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// The texts of every call so far, one entry per call.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls
+            .lock()
+            .map(|calls| calls.clone())
+            .unwrap_or_default()
+    }
+
+    fn embed(&self, text: &str) -> Vec<f64> {
+        let mut values = Vec::with_capacity(self.dimensions);
+        let mut block: u64 = 0;
+        while values.len() < self.dimensions {
+            let digest = Sha256::new()
+                .chain_update(block.to_le_bytes())
+                .chain_update(text.as_bytes())
+                .finalize();
+            for bytes in digest.chunks(4).take(self.dimensions - values.len()) {
+                let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                values.push(value as f64 / u32::MAX as f64 * 2.0 - 1.0);
+            }
+            block += 1;
+        }
+
+        let norm = values.iter().map(|value| value * value).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            values.iter_mut().for_each(|value| *value /= norm);
+        }
+        values
+    }
+
+    fn record(&self, texts: Vec<String>) {
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.push(texts);
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for FakeEmbedder {
+    async fn embed_documents(&self, documents: Vec<String>) -> Result<Vec<Vec<f64>>, ApiError> {
+        let embeddings = documents.iter().map(|text| self.embed(text)).collect();
+        self.record(documents);
+        Ok(embeddings)
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Vec<f64>, ApiError> {
+        self.record(vec![text.to_string()]);
+        Ok(self.embed(text))
+    }
+
+    fn identifying_params(&self) -> Value {
+        json!({ "model": "fake", "dimensions": self.dimensions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_embeddings_are_deterministic_unit_vectors() {
+        let embedder = FakeEmbedder::new(20);
+        let documents = embedder
+            .embed_documents(vec![String::from("cat"), String::from("dog")])
+            .await
+            .unwrap();
+        let query = embedder.embed_query("cat").await.unwrap();
+
+        assert_eq!(documents[0], query);
+        assert_ne!(documents[0], documents[1]);
+        assert_eq!(query.len(), 20);
+        let norm = query.iter().map(|value| value * value).sum::<f64>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-9);
+        assert_eq!(
+            FakeEmbedder::new(20).embed_query("cat").await.unwrap(),
+            query
+        );
+        assert_eq!(embedder.calls().len(), 2);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::tokenizer::Tokenizer;
+
 pub fn batch_texts(texts: Vec<String>, batch_size: usize) -> Vec<Vec<String>> {
     let mut batched_texts: Vec<Vec<String>> = Vec::with_capacity(texts.len());
 
@@ -21,3 +23,37 @@ pub fn batch_texts(texts: Vec<String>, batch_size: usize) -> Vec<Vec<String>> {
 
     batched_texts
 }
+
+// Like `batch_texts`, but splits every text into pieces of at most `max_tokens`
+// cl100k_base tokens, e.g. to stay under the input limit of an embedding model.
+pub fn batch_texts_by_tokens(texts: Vec<String>, max_tokens: usize) -> Vec<Vec<String>> {
+    let tokenizer = Tokenizer::cl100k_base();
+    texts
+        .iter()
+        .map(|text| tokenizer.split(text, max_tokens))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_texts_by_tokens() {
+        let tokenizer = Tokenizer::cl100k_base();
+        let long = "one two three four five six seven eight nine ten".repeat(3);
+        let texts = vec![String::from("Hi"), long.clone(), String::from("Bye")];
+
+        let batches = batch_texts_by_tokens(texts, 8);
+
+        // One batch per text, in order, and the long text is split inside its own
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[0], vec!["Hi"]);
+        assert_eq!(batches[2], vec!["Bye"]);
+        assert!(batches[1].len() > 1);
+        assert_eq!(batches[1].concat(), long);
+        for piece in batches.iter().flatten() {
+            assert!(tokenizer.count(piece) <= 8);
+        }
+    }
+}
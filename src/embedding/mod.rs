@@ -1,3 +1,5 @@
 pub mod embedder_trait;
+pub mod fake;
 pub mod helpers;
+pub mod ollama;
 pub mod openai;
@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    client::OllamaConfig,
+    embedding::embedder_trait::Embedder,
+    errors::{ollama_errors::OllamaError, ApiError},
+};
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    embedding: Vec<f64>,
+}
+
+#[derive(Debug)]
+pub struct OllamaEmbedder {
+    pub model: String,
+    pub config: OllamaConfig,
+}
+impl OllamaEmbedder {
+    pub fn new(model: &str) -> Self {
+        OllamaEmbedder {
+            model: model.to_string(),
+            config: OllamaConfig::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: OllamaConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    async fn request_embedding(&self, prompt: &str) -> Result<Vec<f64>, ApiError> {
+        let request = self.config.post("api/embeddings")?.json(&json!({
+            "model": &self.model,
+            "prompt": prompt,
+        }));
+
+        let res = self
+            .config
+            .send(self.config.http.with_request_timeout(request))
+            .await
+            .inspect_err(|e| log::error!("Error from Ollama: {}", e))?;
+
+        let data: EmbeddingResponse = res.json().await.map_err(|e| {
+            log::error!("Could not parse response: {}", e);
+            ApiError::OllamaError(OllamaError::new_generic_error(String::from(
+                "Could not parse response",
+            )))
+        })?;
+        Ok(data.embedding)
+    }
+}
+
+impl Default for OllamaEmbedder {
+    fn default() -> Self {
+        OllamaEmbedder::new("nomic-embed-text")
+    }
+}
+
+#[async_trait]
+impl Embedder for OllamaEmbedder {
+    // The endpoint embeds one prompt per request, so documents are sent one by one
+    // to avoid flooding a local server.
+    async fn embed_documents(&self, documents: Vec<String>) -> Result<Vec<Vec<f64>>, ApiError> {
+        let mut embeddings = Vec::with_capacity(documents.len());
+        for document in &documents {
+            embeddings.push(self.request_embedding(document).await?);
+        }
+        Ok(embeddings)
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Vec<f64>, ApiError> {
+        self.request_embedding(text).await
+    }
+
+    fn identifying_params(&self) -> Value {
+        json!({ "model": &self.model })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_embed_documents_in_order() {
+        let mut server = mockito::Server::new_async().await;
+        for (prompt, embedding) in [("first", "[1.0,0.0]"), ("second", "[0.0,1.0]")] {
+            server
+                .mock("POST", "/api/embeddings")
+                .match_body(Matcher::PartialJson(json!({
+                    "model": "nomic-embed-text",
+                    "prompt": prompt
+                })))
+                .with_status(200)
+                .with_body(format!(r#"{{"embedding":{}}}"#, embedding))
+                .create_async()
+                .await;
+        }
+
+        let embedder = OllamaEmbedder::default().with_config(OllamaConfig::new(&server.url()));
+        let embeddings = embedder
+            .embed_documents(vec![String::from("first"), String::from("second")])
+            .await
+            .unwrap();
+
+        assert_eq!(embeddings, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+    }
+}
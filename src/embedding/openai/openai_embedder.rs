@@ -1,13 +1,13 @@
-use std::env;
-
 use async_trait::async_trait;
-use reqwest::{Client, Url};
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 
 use crate::{
+    client::{rate_limiter::Throttle, OpenAIConfig, RateLimiter},
     embedding::embedder_trait::Embedder,
     errors::{openai_errors::OpenaiError, ApiError},
+    tokenizer::count_tokens,
+    usage::UsageTracker,
 };
 #[derive(Debug, Deserialize)]
 struct EmbeddingResponse {
@@ -40,22 +40,89 @@ struct UsageData {
 #[derive(Debug)]
 pub struct OpenAiEmbedder {
     pub model: String,
-    pub openai_key: String,
+    pub config: OpenAIConfig,
+    pub deployment: Option<String>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub usage_tracker: Option<UsageTracker>,
 }
 impl OpenAiEmbedder {
     pub fn new(openai_key: String) -> Self {
         OpenAiEmbedder {
             model: String::from("text-embedding-ada-002"),
-            openai_key,
+            config: OpenAIConfig::new(openai_key),
+            deployment: None,
+            rate_limiter: None,
+            usage_tracker: None,
         }
     }
+
+    pub fn with_config(mut self, config: OpenAIConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Azure deployment to send requests to. Defaults to the model name.
+    pub fn with_deployment(mut self, deployment: &str) -> Self {
+        self.deployment = Some(deployment.to_string());
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Records the tokens of every request, and refuses to send more once the
+    /// tracker's budget is exceeded.
+    pub fn with_usage_tracker(mut self, usage_tracker: UsageTracker) -> Self {
+        self.usage_tracker = Some(usage_tracker);
+        self
+    }
+
+    async fn request_embeddings(
+        &self,
+        input: Value,
+        tokens: impl FnOnce() -> u32,
+    ) -> Result<EmbeddingResponse, ApiError> {
+        let deployment = self.deployment.as_deref().unwrap_or(&self.model);
+        if let Some(usage_tracker) = &self.usage_tracker {
+            usage_tracker.check_budget()?;
+        }
+        let throttle = Throttle::new(self.rate_limiter.as_ref(), deployment, tokens);
+
+        let request = self.config.post("embeddings", deployment)?.json(&json!({
+            "input": input,
+            "model": &self.model,
+        }));
+
+        let res = self
+            .config
+            .send_throttled(self.config.http.with_request_timeout(request), throttle)
+            .await
+            .inspect_err(|e| log::error!("Error from OPENAI: {}", e))?;
+
+        let response: EmbeddingResponse = res.json().await.map_err(|e| {
+            log::error!("Could not parse response: {}", e);
+            ApiError::OpenaiError(OpenaiError::from_http_status(
+                500,
+                "Could not parse response".to_string(),
+            ))
+        })?;
+        if let Some(usage_tracker) = &self.usage_tracker {
+            usage_tracker.record_embedding(&self.model, response.usage.prompt_tokens as u64);
+        }
+        Ok(response)
+    }
 }
 
 impl Default for OpenAiEmbedder {
     fn default() -> Self {
         OpenAiEmbedder {
             model: String::from("text-embedding-ada-002"),
-            openai_key: env::var("OPENAI_API_KEY").unwrap_or(String::new()),
+            config: OpenAIConfig::default(),
+            deployment: None,
+            rate_limiter: None,
+            usage_tracker: None,
         }
     }
 }
@@ -63,87 +130,28 @@ impl Default for OpenAiEmbedder {
 #[async_trait]
 impl Embedder for OpenAiEmbedder {
     async fn embed_documents(&self, documents: Vec<String>) -> Result<Vec<Vec<f64>>, ApiError> {
-        let client = Client::new();
-        let url = Url::parse("https://api.openai.com/v1/embeddings").map_err(|e| {
-            log::error!("Could not parse URL: {}", e);
-            ApiError::OpenaiError(OpenaiError::from_http_status(
-                500,
-                "Could not parse URL".to_string(),
-            ))
-        })?;
-
-        let res = client
-            .post(url)
-            .bearer_auth(&self.openai_key)
-            .json(&json!({
-                "input": documents,
-                "model": &self.model,
-            }))
-            .send()
-            .await
-            .map_err(|e| {
-                log::error!("Could not send request: {}", e);
-                ApiError::OpenaiError(OpenaiError::from_http_status(
-                    500,
-                    "Could not send request".to_string(),
-                ))
-            })?;
-        if res.status() != 200 {
-            return Err(ApiError::OpenaiError(OpenaiError::from_http_status(
-                res.status().as_u16(),
-                res.text().await.unwrap_or("".to_string()),
-            )));
-        }
-
-        let data: EmbeddingResponse = res.json().await.map_err(|e| {
-            log::error!("Could not parse response: {}", e);
-            ApiError::OpenaiError(OpenaiError::from_http_status(
-                500,
-                "Could not parse response".to_string(),
-            ))
-        })?;
+        let data = self
+            .request_embeddings(json!(documents), || {
+                documents
+                    .iter()
+                    .map(|document| count_tokens(document) as u32)
+                    .sum()
+            })
+            .await?;
         Ok(data.extract_all_embeddings())
     }
 
     async fn embed_query(&self, text: &str) -> Result<Vec<f64>, ApiError> {
-        let client = Client::new();
-        let url = Url::parse("https://api.openai.com/v1/embeddings").map_err(|_| {
-            ApiError::OpenaiError(OpenaiError::from_http_status(
-                500,
-                "Could not parse URL".to_string(),
-            ))
-        })?;
-
-        let res = client
-            .post(url)
-            .bearer_auth(&self.openai_key)
-            .json(&json!({
-                "input": text,
-                "model": &self.model,
-            }))
-            .send()
-            .await
-            .map_err(|_| {
-                ApiError::OpenaiError(OpenaiError::from_http_status(
-                    500,
-                    "Could not send request".to_string(),
-                ))
-            })?;
-
-        if res.status() != 200 {
-            log::error!("Error from OPENAI: {}", &res.status());
-            return Err(ApiError::OpenaiError(OpenaiError::from_http_status(
-                res.status().as_u16(),
-                res.text().await.unwrap_or("".to_string()),
-            )));
-        }
-        let data: EmbeddingResponse = res.json().await.map_err(|e| {
-            log::error!("Could not parse response: {}", e);
-            ApiError::OpenaiError(OpenaiError::from_http_status(
-                500,
-                "Could not parse response".to_string(),
-            ))
-        })?;
+        let data = self
+            .request_embeddings(json!(text), || count_tokens(text) as u32)
+            .await?;
         Ok(data.extract_embedding())
     }
+
+    fn identifying_params(&self) -> Value {
+        json!({
+            "model": &self.model,
+            "deployment": self.deployment.as_deref().unwrap_or(&self.model),
+        })
+    }
 }
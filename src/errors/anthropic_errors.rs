@@ -0,0 +1,155 @@
+use serde::Deserialize;
+
+use super::ErrorKind;
+
+#[derive(Debug, Clone)]
+pub enum AnthropicError {
+    InvalidRequest { code: u16, detail: String },
+    Authentication { code: u16, detail: String },
+    PermissionDenied { code: u16, detail: String },
+    NotFound { code: u16, detail: String },
+    RequestTooLarge { code: u16, detail: String },
+    RateLimitExceeded { code: u16, detail: String },
+    ServerError { code: u16, detail: String },
+    Overloaded { code: u16, detail: String },
+    UnknownError { code: u16, detail: String },
+    GenericError(String),
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    #[serde(rename = "type")]
+    error_type: String,
+    message: String,
+}
+
+impl AnthropicError {
+    pub fn new_generic_error(msg: String) -> Self {
+        AnthropicError::GenericError(msg)
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AnthropicError::RateLimitExceeded { .. }
+            | AnthropicError::ServerError { .. }
+            | AnthropicError::Overloaded { .. } => true,
+            AnthropicError::UnknownError { code, .. } => super::is_retryable_status(*code),
+            _ => false,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            AnthropicError::InvalidRequest { detail, .. }
+                if detail.contains("prompt is too long") =>
+            {
+                ErrorKind::ContextLength
+            }
+            AnthropicError::InvalidRequest { .. } | AnthropicError::RequestTooLarge { .. } => {
+                ErrorKind::InvalidRequest
+            }
+            AnthropicError::Authentication { .. } | AnthropicError::PermissionDenied { .. } => {
+                ErrorKind::Authentication
+            }
+            AnthropicError::NotFound { .. } => ErrorKind::NotFound,
+            AnthropicError::RateLimitExceeded { .. } => ErrorKind::RateLimit,
+            AnthropicError::ServerError { .. } => ErrorKind::Server,
+            AnthropicError::Overloaded { .. } => ErrorKind::Overloaded,
+            AnthropicError::UnknownError { code, .. } => super::kind_from_status(*code),
+            AnthropicError::GenericError(_) => ErrorKind::Other,
+        }
+    }
+
+    // Anthropic returns `{"type":"error","error":{"type":...,"message":...}}`; the
+    // error type is more precise than the status code, so prefer it when present.
+    pub fn from_http_status(code: u16, body: String) -> Self {
+        match serde_json::from_str::<ErrorResponse>(&body) {
+            Ok(response) => Self::from_error_type(
+                code,
+                Some(&response.error.error_type),
+                response.error.message,
+            ),
+            Err(_) => Self::from_error_type(code, None, body),
+        }
+    }
+
+    // Also used for errors sent as events in the middle of a stream, which arrive
+    // with a 200 status.
+    pub fn from_error_type(code: u16, error_type: Option<&str>, detail: String) -> Self {
+        match (error_type, code) {
+            (Some("invalid_request_error"), _) | (None, 400) => {
+                AnthropicError::InvalidRequest { code, detail }
+            }
+            (Some("authentication_error"), _) | (None, 401) => {
+                AnthropicError::Authentication { code, detail }
+            }
+            (Some("permission_error"), _) | (None, 403) => {
+                AnthropicError::PermissionDenied { code, detail }
+            }
+            (Some("not_found_error"), _) | (None, 404) => AnthropicError::NotFound { code, detail },
+            (Some("request_too_large"), _) | (None, 413) => {
+                AnthropicError::RequestTooLarge { code, detail }
+            }
+            (Some("rate_limit_error"), _) | (None, 429) => {
+                AnthropicError::RateLimitExceeded { code, detail }
+            }
+            (Some("api_error"), _) | (None, 500) => AnthropicError::ServerError { code, detail },
+            (Some("overloaded_error"), _) | (None, 529) => {
+                AnthropicError::Overloaded { code, detail }
+            }
+            _ => AnthropicError::UnknownError { code, detail },
+        }
+    }
+}
+
+impl std::fmt::Display for AnthropicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnthropicError::InvalidRequest { code, detail } => {
+                write!(f, "Error code {}: Invalid request - {}", code, detail)
+            }
+            AnthropicError::Authentication { code, detail } => {
+                write!(f, "Error code {}: Invalid API key - {}", code, detail)
+            }
+            AnthropicError::PermissionDenied { code, detail } => {
+                write!(f, "Error code {}: Permission denied - {}", code, detail)
+            }
+            AnthropicError::NotFound { code, detail } => {
+                write!(f, "Error code {}: Resource not found - {}", code, detail)
+            }
+            AnthropicError::RequestTooLarge { code, detail } => {
+                write!(f, "Error code {}: Request too large - {}", code, detail)
+            }
+            AnthropicError::RateLimitExceeded { code, detail } => {
+                write!(f, "Error code {}: Rate limit reached - {}", code, detail)
+            }
+            AnthropicError::ServerError { code, detail } => {
+                write!(
+                    f,
+                    "Error code {}: The server had an error while processing your request - {}",
+                    code, detail
+                )
+            }
+            AnthropicError::Overloaded { code, detail } => {
+                write!(
+                    f,
+                    "Error code {}: The API is temporarily overloaded, please try again later - {}",
+                    code, detail
+                )
+            }
+            AnthropicError::UnknownError { code, detail } => {
+                write!(f, "Error code {}: Unknown error - {}", code, detail)
+            }
+            AnthropicError::GenericError(msg) => {
+                write!(f, "An error occurred with the Anthropic API: {}", msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AnthropicError {}
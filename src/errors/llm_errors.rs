@@ -0,0 +1,143 @@
+use std::fmt;
+
+use super::{ApiError, PromptError};
+
+/// Any error, boxed so it can cross tasks. Returned by tools and custom parsers.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The error of chains, agents, tools and prompt templates. The failure of the
+/// model request itself is kept as an `ApiError` in `Api` or `Stream`.
+#[derive(Debug)]
+pub enum LLMError {
+    Api(ApiError),
+    // A stream that failed after it started
+    Stream(ApiError),
+    Prompt(PromptError),
+    OutputParsing { output: String, source: BoxError },
+    ToolNotFound(String),
+    ToolFailed { tool: String, source: BoxError },
+    MaxIterations(i32),
+    // The chat history could not be read or written, e.g. a poisoned lock
+    Memory(String),
+    // The model answered with tool calls where only text can be returned
+    UnexpectedToolCalls,
+    InvalidMessage(String),
+    Serialization(serde_json::Error),
+    InvalidConfiguration(String),
+}
+
+impl LLMError {
+    pub fn output_parsing(output: &str, source: impl Into<BoxError>) -> Self {
+        LLMError::OutputParsing {
+            output: output.to_string(),
+            source: source.into(),
+        }
+    }
+
+    /// The model error behind this one, if any.
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            LLMError::Api(err) | LLMError::Stream(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LLMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LLMError::Api(err) => write!(f, "{}", err),
+            LLMError::Stream(err) => write!(f, "The stream failed: {}", err),
+            LLMError::Prompt(err) => write!(f, "Prompt error: {}", err),
+            LLMError::OutputParsing { source, .. } => {
+                write!(f, "Could not parse the model output: {}", source)
+            }
+            LLMError::ToolNotFound(tool) => write!(f, "Tool not found: {}", tool),
+            LLMError::ToolFailed { tool, source } => write!(f, "Tool {} failed: {}", tool, source),
+            LLMError::MaxIterations(max) => write!(f, "Max iterations reached ({})", max),
+            LLMError::Memory(msg) => write!(f, "Memory error: {}", msg),
+            LLMError::UnexpectedToolCalls => {
+                write!(f, "The model requested tool calls, use call() to get them")
+            }
+            LLMError::InvalidMessage(msg) => write!(f, "Invalid message: {}", msg),
+            LLMError::Serialization(err) => write!(f, "Serialization error: {}", err),
+            LLMError::InvalidConfiguration(msg) => write!(f, "Invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LLMError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LLMError::Api(err) | LLMError::Stream(err) => Some(err),
+            LLMError::Prompt(err) => Some(err),
+            LLMError::OutputParsing { source, .. } | LLMError::ToolFailed { source, .. } => {
+                Some(source.as_ref())
+            }
+            LLMError::Serialization(err) => Some(err),
+            LLMError::ToolNotFound(_)
+            | LLMError::MaxIterations(_)
+            | LLMError::Memory(_)
+            | LLMError::UnexpectedToolCalls
+            | LLMError::InvalidMessage(_)
+            | LLMError::InvalidConfiguration(_) => None,
+        }
+    }
+}
+
+impl From<ApiError> for LLMError {
+    fn from(err: ApiError) -> Self {
+        match err {
+            ApiError::PromptError(err) => LLMError::Prompt(err),
+            err => LLMError::Api(err),
+        }
+    }
+}
+
+impl From<PromptError> for LLMError {
+    fn from(err: PromptError) -> Self {
+        LLMError::Prompt(err)
+    }
+}
+
+impl From<serde_json::Error> for LLMError {
+    fn from(err: serde_json::Error) -> Self {
+        LLMError::Serialization(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::errors::openai_errors::OpenaiError;
+
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+
+    #[test]
+    fn test_errors_cross_tasks_and_keep_sources() {
+        assert_send_sync::<LLMError>();
+
+        let err = LLMError::ToolFailed {
+            tool: String::from("search"),
+            source: Box::new(LLMError::from(ApiError::OpenaiError(
+                OpenaiError::from_http_status(503, String::new()),
+            ))),
+        };
+        let mut chain = Vec::new();
+        let mut current: Option<&(dyn Error + 'static)> = Some(&err);
+        while let Some(err) = current {
+            chain.push(err.to_string());
+            current = err.source();
+        }
+        assert_eq!(chain.len(), 4);
+        assert!(chain[0].starts_with("Tool search failed"));
+
+        let err = LLMError::from(ApiError::PromptError(PromptError::DataNotProvided(
+            String::from("input"),
+        )));
+        assert!(matches!(err, LLMError::Prompt(_)));
+    }
+}
@@ -1,23 +1,115 @@
-use self::{aws_errors::AWSError, openai_errors::OpenaiError};
+use self::{
+    anthropic_errors::AnthropicError, aws_errors::AWSError, ollama_errors::OllamaError,
+    openai_errors::OpenaiError,
+};
 
+pub mod anthropic_errors;
 pub mod aws_errors;
+pub mod llm_errors;
+pub mod ollama_errors;
 pub mod openai_errors;
 pub mod prompt_errors;
+pub use llm_errors::{BoxError, LLMError};
 pub use prompt_errors::PromptError;
 
 #[derive(Debug)]
 pub enum ApiError {
     OpenaiError(OpenaiError),
+    AnthropicError(AnthropicError),
+    OllamaError(OllamaError),
     AWSError(AWSError),
     PromptError(PromptError),
+    // A request or a stream that did not answer in time, for any provider
+    Timeout(String),
+    // The run was cancelled through its `CancellationToken`
+    Cancelled,
+    // A response that does not match the request, e.g. fewer embeddings than inputs
+    InvalidResponse(String),
+    // The spend or the tokens of a `UsageTracker` went past its budget
+    BudgetExceeded(String),
+}
+
+/// A provider-neutral classification of errors, to decide how to react to them
+/// without matching on every provider's error type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    RateLimit,
+    Quota,
+    Overloaded,
+    Server,
+    Timeout,
+    ContextLength,
+    Authentication,
+    InvalidRequest,
+    NotFound,
+    ContentPolicy,
+    Cancelled,
+    Other,
+}
+
+impl ApiError {
+    // True for failures that may go away on their own: rate limits, overloaded
+    // or failing servers. Quota, authentication and invalid requests are final.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::OpenaiError(err) => err.is_retryable(),
+            ApiError::AnthropicError(err) => err.is_retryable(),
+            ApiError::OllamaError(err) => err.is_retryable(),
+            ApiError::Timeout(_) => true,
+            ApiError::Cancelled
+            | ApiError::AWSError(_)
+            | ApiError::PromptError(_)
+            | ApiError::InvalidResponse(_)
+            | ApiError::BudgetExceeded(_) => false,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ApiError::OpenaiError(err) => err.kind(),
+            ApiError::AnthropicError(err) => err.kind(),
+            ApiError::OllamaError(err) => err.kind(),
+            ApiError::Timeout(_) => ErrorKind::Timeout,
+            ApiError::Cancelled => ErrorKind::Cancelled,
+            ApiError::BudgetExceeded(_) => ErrorKind::Quota,
+            ApiError::AWSError(_) | ApiError::PromptError(_) | ApiError::InvalidResponse(_) => {
+                ErrorKind::Other
+            }
+        }
+    }
+}
+
+// The kind of an error status that is not mapped to a more precise variant.
+fn kind_from_status(code: u16) -> ErrorKind {
+    match code {
+        400 | 413 | 422 => ErrorKind::InvalidRequest,
+        401 | 403 => ErrorKind::Authentication,
+        404 => ErrorKind::NotFound,
+        408 => ErrorKind::Timeout,
+        429 => ErrorKind::RateLimit,
+        503 | 529 => ErrorKind::Overloaded,
+        500..=599 => ErrorKind::Server,
+        _ => ErrorKind::Other,
+    }
+}
+
+// Statuses that are not mapped to a variant but are worth retrying.
+fn is_retryable_status(code: u16) -> bool {
+    matches!(code, 408 | 409 | 429 | 500..=599)
 }
 
 impl std::fmt::Display for ApiError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ApiError::OpenaiError(err) => write!(f, "OpenAI error: {}", err),
+            ApiError::AnthropicError(err) => write!(f, "Anthropic error: {}", err),
+            ApiError::OllamaError(err) => write!(f, "Ollama error: {}", err),
             ApiError::AWSError(err) => write!(f, "AWS error: {}", err),
             ApiError::PromptError(err) => write!(f, "Prompt error: {}", err),
+            ApiError::Timeout(msg) => write!(f, "Timeout: {}", msg),
+            ApiError::Cancelled => write!(f, "The run was cancelled"),
+            ApiError::InvalidResponse(msg) => write!(f, "Invalid response: {}", msg),
+            ApiError::BudgetExceeded(msg) => write!(f, "Budget exceeded: {}", msg),
         }
     }
 }
@@ -26,8 +118,14 @@ impl std::error::Error for ApiError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             ApiError::OpenaiError(err) => Some(err),
+            ApiError::AnthropicError(err) => Some(err),
+            ApiError::OllamaError(err) => Some(err),
             ApiError::AWSError(err) => Some(err),
             ApiError::PromptError(err) => Some(err),
+            ApiError::Timeout(_)
+            | ApiError::Cancelled
+            | ApiError::InvalidResponse(_)
+            | ApiError::BudgetExceeded(_) => None,
         }
     }
 }
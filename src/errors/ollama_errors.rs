@@ -0,0 +1,88 @@
+use serde::Deserialize;
+
+use super::ErrorKind;
+
+#[derive(Debug, Clone)]
+pub enum OllamaError {
+    InvalidRequest { code: u16, detail: String },
+    ModelNotFound { code: u16, detail: String },
+    ServerError { code: u16, detail: String },
+    UnknownError { code: u16, detail: String },
+    GenericError(String),
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl OllamaError {
+    pub fn new_generic_error(msg: String) -> Self {
+        OllamaError::GenericError(msg)
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            OllamaError::ServerError { .. } => true,
+            OllamaError::UnknownError { code, .. } => super::is_retryable_status(*code),
+            _ => false,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            OllamaError::InvalidRequest { .. } => ErrorKind::InvalidRequest,
+            OllamaError::ModelNotFound { .. } => ErrorKind::NotFound,
+            OllamaError::ServerError { .. } => ErrorKind::Server,
+            OllamaError::UnknownError { code, .. } => super::kind_from_status(*code),
+            OllamaError::GenericError(_) => ErrorKind::Other,
+        }
+    }
+
+    // Ollama reports failures as `{"error":"..."}`.
+    pub fn from_http_status(code: u16, body: String) -> Self {
+        let detail = match serde_json::from_str::<ErrorResponse>(&body) {
+            Ok(response) => response.error,
+            Err(_) => body,
+        };
+
+        match code {
+            400 => OllamaError::InvalidRequest { code, detail },
+            404 => OllamaError::ModelNotFound { code, detail },
+            500..=599 => OllamaError::ServerError { code, detail },
+            _ => OllamaError::UnknownError { code, detail },
+        }
+    }
+}
+
+impl std::fmt::Display for OllamaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OllamaError::InvalidRequest { code, detail } => {
+                write!(f, "Error code {}: Invalid request - {}", code, detail)
+            }
+            OllamaError::ModelNotFound { code, detail } => {
+                write!(
+                    f,
+                    "Error code {}: Model not found, make sure it has been pulled - {}",
+                    code, detail
+                )
+            }
+            OllamaError::ServerError { code, detail } => {
+                write!(
+                    f,
+                    "Error code {}: The server had an error while processing your request - {}",
+                    code, detail
+                )
+            }
+            OllamaError::UnknownError { code, detail } => {
+                write!(f, "Error code {}: Unknown error - {}", code, detail)
+            }
+            OllamaError::GenericError(msg) => {
+                write!(f, "An error occurred with the Ollama API: {}", msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OllamaError {}
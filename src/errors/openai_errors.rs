@@ -1,22 +1,187 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::ErrorKind;
+
 #[derive(Debug, Clone)]
 pub enum OpenaiError {
-    InvalidAuthentication { code: u16, detail: String },
-    IncorrectApiKey { code: u16, detail: String },
-    NoOrganizationMembership { code: u16, detail: String },
-    RateLimitExceeded { code: u16, detail: String },
-    QuotaExceeded { code: u16, detail: String },
-    ServerError { code: u16, detail: String },
-    EngineOverloaded { code: u16, detail: String },
-    UnknownError { code: u16, detail: String },
+    InvalidAuthentication {
+        code: u16,
+        detail: String,
+    },
+    IncorrectApiKey {
+        code: u16,
+        detail: String,
+    },
+    NoOrganizationMembership {
+        code: u16,
+        detail: String,
+    },
+    RateLimitExceeded {
+        code: u16,
+        detail: String,
+    },
+    QuotaExceeded {
+        code: u16,
+        detail: String,
+    },
+    ServerError {
+        code: u16,
+        detail: String,
+    },
+    EngineOverloaded {
+        code: u16,
+        detail: String,
+    },
+    DeploymentNotFound {
+        code: u16,
+        detail: String,
+    },
+    ModelNotFound {
+        code: u16,
+        detail: String,
+    },
+    ContentPolicy {
+        code: u16,
+        detail: String,
+    },
+    ContextLengthExceeded {
+        code: u16,
+        detail: String,
+    },
+    // `param` names the request field that was rejected, when the API tells
+    InvalidRequest {
+        code: u16,
+        detail: String,
+        param: Option<String>,
+    },
+    UnknownError {
+        code: u16,
+        detail: String,
+    },
     GenericError(String),
 }
 
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+// OpenAI and Azure send `code` as a string, but some proxies send a number.
+#[derive(Deserialize)]
+struct ErrorBody {
+    message: Option<String>,
+    #[serde(rename = "type")]
+    error_type: Option<String>,
+    param: Option<String>,
+    code: Option<Value>,
+}
+
+impl ErrorBody {
+    fn code(&self) -> Option<String> {
+        match self.code.as_ref()? {
+            Value::String(code) => Some(code.clone()),
+            Value::Null => None,
+            code => Some(code.to_string()),
+        }
+    }
+}
+
 impl OpenaiError {
     pub fn new_generic_error(msg: String) -> Self {
         OpenaiError::GenericError(msg)
     }
 
-    pub fn from_http_status(code: u16, detail: String) -> Self {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            OpenaiError::RateLimitExceeded { .. }
+            | OpenaiError::ServerError { .. }
+            | OpenaiError::EngineOverloaded { .. } => true,
+            OpenaiError::UnknownError { code, .. } => super::is_retryable_status(*code),
+            _ => false,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            OpenaiError::InvalidAuthentication { .. }
+            | OpenaiError::IncorrectApiKey { .. }
+            | OpenaiError::NoOrganizationMembership { .. } => ErrorKind::Authentication,
+            OpenaiError::RateLimitExceeded { .. } => ErrorKind::RateLimit,
+            OpenaiError::QuotaExceeded { .. } => ErrorKind::Quota,
+            OpenaiError::ServerError { .. } => ErrorKind::Server,
+            OpenaiError::EngineOverloaded { .. } => ErrorKind::Overloaded,
+            OpenaiError::DeploymentNotFound { .. } | OpenaiError::ModelNotFound { .. } => {
+                ErrorKind::NotFound
+            }
+            OpenaiError::ContentPolicy { .. } => ErrorKind::ContentPolicy,
+            OpenaiError::ContextLengthExceeded { .. } => ErrorKind::ContextLength,
+            OpenaiError::InvalidRequest { .. } => ErrorKind::InvalidRequest,
+            OpenaiError::UnknownError { code, .. } => super::kind_from_status(*code),
+            OpenaiError::GenericError(_) => ErrorKind::Other,
+        }
+    }
+
+    // OpenAI describes failures as `{"error":{"message","type","param","code"}}`.
+    // The error code and type are more precise than the status, so they are used
+    // when the body has them.
+    pub fn from_http_status(code: u16, body: String) -> Self {
+        match serde_json::from_str::<ErrorResponse>(&body) {
+            Ok(ErrorResponse { error }) => {
+                let error_code = error.code();
+                let detail = error.message.unwrap_or(body);
+                Self::from_error(
+                    code,
+                    error_code.as_deref(),
+                    error.error_type.as_deref(),
+                    error.param,
+                    detail,
+                )
+            }
+            Err(_) => Self::from_error(code, None, None, None, body),
+        }
+    }
+
+    // Also used for Azure and for errors sent in the middle of a stream.
+    fn from_error(
+        code: u16,
+        error_code: Option<&str>,
+        error_type: Option<&str>,
+        param: Option<String>,
+        detail: String,
+    ) -> Self {
+        if detail.contains("maximum context length") {
+            return OpenaiError::ContextLengthExceeded { code, detail };
+        }
+        match (error_code, error_type) {
+            (Some("context_length_exceeded" | "string_above_max_length"), _) => {
+                OpenaiError::ContextLengthExceeded { code, detail }
+            }
+            (Some("content_policy_violation" | "content_filter"), _) => {
+                OpenaiError::ContentPolicy { code, detail }
+            }
+            (Some("insufficient_quota"), _) | (_, Some("insufficient_quota")) => {
+                OpenaiError::QuotaExceeded { code, detail }
+            }
+            (Some("rate_limit_exceeded"), _) => OpenaiError::RateLimitExceeded { code, detail },
+            (Some("invalid_api_key"), _) => OpenaiError::IncorrectApiKey { code, detail },
+            (Some("model_not_found"), _) => OpenaiError::ModelNotFound { code, detail },
+            (Some("engine_overloaded"), _) | (_, Some("engine_overloaded")) => {
+                OpenaiError::EngineOverloaded { code, detail }
+            }
+            (_, Some("server_error")) => OpenaiError::ServerError { code, detail },
+            (_, Some("invalid_request_error")) if matches!(code, 400 | 413 | 422) => {
+                OpenaiError::InvalidRequest {
+                    code,
+                    detail,
+                    param,
+                }
+            }
+            _ => Self::from_status(code, detail),
+        }
+    }
+
+    fn from_status(code: u16, detail: String) -> Self {
         match code {
             401 => {
                 // Choose appropriate error type based on detail
@@ -41,6 +206,47 @@ impl OpenaiError {
             _ => OpenaiError::UnknownError { code, detail }, // For other error codes not explicitly handled
         }
     }
+
+    // Azure wraps failures as `{"error":{"code":...,"message":...}}`, except for
+    // gateway errors such as a bad key, which come as `{"statusCode":...,"message":...}`.
+    pub fn from_azure_http_status(code: u16, body: String) -> Self {
+        let json: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+        let error = json.get("error").unwrap_or(&json);
+        let field = |name: &str| error.get(name).and_then(Value::as_str).map(String::from);
+        let error_code = field("code");
+        let error_type = field("type");
+        let param = field("param");
+        let detail = field("message").unwrap_or(body);
+
+        match (error_code.as_deref(), code) {
+            (Some("DeploymentNotFound"), _) | (_, 404) => {
+                OpenaiError::DeploymentNotFound { code, detail }
+            }
+            (_, 401) => OpenaiError::InvalidAuthentication { code, detail },
+            (_, 429) => OpenaiError::RateLimitExceeded { code, detail },
+            _ => Self::from_error(
+                code,
+                error_code.as_deref(),
+                error_type.as_deref(),
+                param,
+                detail,
+            ),
+        }
+    }
+
+    /// The error sent as a server-sent event in the middle of a stream, if `data`
+    /// is one. Those come with a 200 status, so the error type decides.
+    pub fn from_stream_event(data: &str) -> Option<Self> {
+        let ErrorResponse { error } = serde_json::from_str(data).ok()?;
+        let error_code = error.code();
+        Some(Self::from_error(
+            200,
+            error_code.as_deref(),
+            error.error_type.as_deref(),
+            error.param,
+            error.message.unwrap_or_else(|| data.to_string()),
+        ))
+    }
 }
 
 impl std::fmt::Display for OpenaiError {
@@ -87,14 +293,145 @@ impl std::fmt::Display for OpenaiError {
             OpenaiError::EngineOverloaded { code, detail } => {
                 write!(f, "Error code {}: The engine is currently overloaded, please try again later - {}", code, detail)
             }
+            OpenaiError::DeploymentNotFound { code, detail } => {
+                write!(
+                    f,
+                    "Error code {}: The deployment does not exist - {}",
+                    code, detail
+                )
+            }
+            OpenaiError::ModelNotFound { code, detail } => {
+                write!(
+                    f,
+                    "Error code {}: The model does not exist - {}",
+                    code, detail
+                )
+            }
+            OpenaiError::ContextLengthExceeded { code, detail } => {
+                write!(
+                    f,
+                    "Error code {}: The request exceeds the context length of the model - {}",
+                    code, detail
+                )
+            }
+            OpenaiError::InvalidRequest {
+                code,
+                detail,
+                param: Some(param),
+            } => {
+                write!(
+                    f,
+                    "Error code {}: Invalid request, parameter {} - {}",
+                    code, param, detail
+                )
+            }
+            OpenaiError::InvalidRequest { code, detail, .. } => {
+                write!(f, "Error code {}: Invalid request - {}", code, detail)
+            }
+            OpenaiError::ContentPolicy { code, detail } => {
+                write!(
+                    f,
+                    "Error code {}: The request was rejected by the content policy - {}",
+                    code, detail
+                )
+            }
             OpenaiError::UnknownError { code, detail } => {
                 write!(f, "Error code {}: Unknown error - {}", code, detail)
             }
-            OpenaiError::GenericError(_) => {
-                write!(f, "An unknown error occurred with the OpenAI API.")
+            OpenaiError::GenericError(msg) => {
+                write!(f, "An error occurred with the OpenAI API: {}", msg)
             }
         }
     }
 }
 
 impl std::error::Error for OpenaiError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(message: &str, error_type: &str, param: Option<&str>, code: Option<&str>) -> String {
+        serde_json::json!({
+            "error": {"message": message, "type": error_type, "param": param, "code": code}
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_parses_error_bodies() {
+        let quota = OpenaiError::from_http_status(
+            429,
+            body(
+                "Out of credits",
+                "insufficient_quota",
+                None,
+                Some("insufficient_quota"),
+            ),
+        );
+        assert!(
+            matches!(quota, OpenaiError::QuotaExceeded { ref detail, .. } if detail == "Out of credits")
+        );
+        let rate_limit = OpenaiError::from_http_status(
+            429,
+            body("Slow down", "requests", None, Some("rate_limit_exceeded")),
+        );
+        assert!(matches!(rate_limit, OpenaiError::RateLimitExceeded { .. }));
+
+        let too_long = OpenaiError::from_http_status(
+            400,
+            body(
+                "This model's maximum context length is 8192 tokens.",
+                "invalid_request_error",
+                Some("messages"),
+                Some("context_length_exceeded"),
+            ),
+        );
+        assert_eq!(too_long.kind(), ErrorKind::ContextLength);
+
+        let invalid = OpenaiError::from_http_status(
+            400,
+            body(
+                "Invalid value",
+                "invalid_request_error",
+                Some("temperature"),
+                None,
+            ),
+        );
+        assert!(
+            matches!(invalid, OpenaiError::InvalidRequest { param: Some(ref param), .. } if param == "temperature")
+        );
+
+        let blocked = OpenaiError::from_http_status(
+            400,
+            body(
+                "Your request was rejected by our safety system.",
+                "invalid_request_error",
+                None,
+                Some("content_policy_violation"),
+            ),
+        );
+        assert_eq!(blocked.kind(), ErrorKind::ContentPolicy);
+
+        // Bodies that are not JSON still go by status
+        let overloaded = OpenaiError::from_http_status(503, String::from("Service Unavailable"));
+        assert!(matches!(overloaded, OpenaiError::EngineOverloaded { .. }));
+
+        let generic = OpenaiError::new_generic_error(String::from("Unexpected stream event: ping"));
+        assert!(generic
+            .to_string()
+            .ends_with("Unexpected stream event: ping"));
+    }
+
+    #[test]
+    fn test_parses_stream_error_events() {
+        let error = OpenaiError::from_stream_event(&body(
+            "The server had an error",
+            "server_error",
+            None,
+            None,
+        ));
+        assert!(matches!(error, Some(OpenaiError::ServerError { .. })));
+        assert!(OpenaiError::from_stream_event(r#"{"choices":[]}"#).is_none());
+    }
+}
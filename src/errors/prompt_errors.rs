@@ -4,6 +4,7 @@ use core::fmt;
 pub enum PromptError {
     RenderError(String),
     DataNotProvided(String),
+    InvalidVariable(String),
 }
 
 impl fmt::Display for PromptError {
@@ -11,6 +12,13 @@ impl fmt::Display for PromptError {
         match self {
             PromptError::RenderError(err) => write!(f, "Render Error: {}", err),
             PromptError::DataNotProvided(err) => write!(f, "Data Not Provided: {}", err),
+            PromptError::InvalidVariable(err) => write!(f, "Invalid Variable: {}", err),
         }
     }
 }
+
+impl From<handlebars::RenderError> for PromptError {
+    fn from(err: handlebars::RenderError) -> Self {
+        PromptError::RenderError(err.to_string())
+    }
+}
@@ -1,11 +1,16 @@
 #![allow(dead_code)]
 pub mod agents;
 pub mod ai_helpers;
+pub mod cache;
 pub mod chains;
 pub mod chat_models;
+pub mod client;
 pub mod embedding;
 pub mod errors;
 pub mod llm;
 pub mod prompt;
 pub mod schemas;
+pub mod tokenizer;
 pub mod tools;
+pub mod tracer;
+pub mod usage;
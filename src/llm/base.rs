@@ -1,8 +1,14 @@
 use async_trait::async_trait;
+use serde_json::Value;
 
 use crate::errors::ApiError;
 
 #[async_trait]
 pub trait BaseLLM: Send + Sync {
     async fn generate(&self, prompt: String) -> Result<String, ApiError>;
+
+    /// The model and the settings that shape its answers, used in cache keys.
+    fn identifying_params(&self) -> Value {
+        Value::Null
+    }
 }
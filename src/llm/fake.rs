@@ -0,0 +1,52 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use async_trait::async_trait;
+
+use crate::{errors::ApiError, llm::base::BaseLLM};
+
+/// A completion model for tests that plays back scripted responses, cycling
+/// through them, and records every prompt. Clones share the script and the
+/// recorded prompts.
+#[derive(Clone)]
+pub struct FakeLLM {
+    responses: Arc<Vec<String>>,
+    next: Arc<AtomicUsize>,
+    prompts: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeLLM {
+    pub fn new(responses: Vec<&str>) -> Self {
+        Self {
+            responses: Arc::new(responses.into_iter().map(String::from).collect()),
+            next: Arc::new(AtomicUsize::new(0)),
+            prompts: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// The prompts of every call so far, in order.
+    pub fn prompts(&self) -> Vec<String> {
+        self.prompts
+            .lock()
+            .map(|prompts| prompts.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl BaseLLM for FakeLLM {
+    async fn generate(&self, prompt: String) -> Result<String, ApiError> {
+        if let Ok(mut prompts) = self.prompts.lock() {
+            prompts.push(prompt);
+        }
+        if self.responses.is_empty() {
+            return Err(ApiError::InvalidResponse(String::from(
+                "FakeLLM has no scripted responses",
+            )));
+        }
+        let index = self.next.fetch_add(1, Ordering::SeqCst) % self.responses.len();
+        Ok(self.responses[index].clone())
+    }
+}
@@ -1,2 +1,4 @@
 pub mod base;
+pub mod fake;
+pub mod ollama;
 pub mod openai;
@@ -0,0 +1,2 @@
+pub mod ollama_api;
+pub use ollama_api::LLMOllama;
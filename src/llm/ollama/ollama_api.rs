@@ -0,0 +1,145 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    client::OllamaConfig,
+    errors::{ollama_errors::OllamaError, ApiError},
+    llm::base::BaseLLM,
+};
+
+pub struct LLMOllama {
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub config: OllamaConfig,
+    pub stop_sequence: Option<String>,
+    pub max_tokens: Option<u32>,
+}
+impl LLMOllama {
+    pub fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    pub fn with_config(mut self, config: OllamaConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_stop_sequence(mut self, stop_sequence: String) -> Self {
+        self.stop_sequence = Some(stop_sequence);
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    fn payload(&self, prompt: &str) -> Value {
+        let mut options = json!({});
+        if let Some(temperature) = self.temperature {
+            options["temperature"] = json!(temperature);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            options["num_predict"] = json!(max_tokens);
+        }
+        if let Some(stop_sequence) = &self.stop_sequence {
+            options["stop"] = json!([stop_sequence]);
+        }
+        json!({
+            "model": self.model,
+            "prompt": prompt,
+            "stream": false,
+            "options": options,
+        })
+    }
+}
+
+impl Default for LLMOllama {
+    fn default() -> Self {
+        Self {
+            model: String::from("llama3"),
+            temperature: None,
+            config: OllamaConfig::default(),
+            stop_sequence: None,
+            max_tokens: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GenerateResponse {
+    response: String,
+}
+
+#[async_trait]
+impl BaseLLM for LLMOllama {
+    async fn generate(&self, prompt: String) -> Result<String, ApiError> {
+        let payload = self.payload(&prompt);
+
+        let request = self.config.post("api/generate")?.json(&payload);
+        let response = self
+            .config
+            .send(self.config.http.with_request_timeout(request))
+            .await?;
+
+        let result: GenerateResponse = response.json().await.map_err(|_| {
+            ApiError::OllamaError(OllamaError::new_generic_error(String::from(
+                "Failed to deserialize JSON",
+            )))
+        })?;
+        Ok(result.response)
+    }
+
+    fn identifying_params(&self) -> Value {
+        self.payload("")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_generate() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/api/generate")
+            .match_body(Matcher::PartialJson(json!({
+                "model": "mistral",
+                "prompt": "Why is the sky blue?",
+                "stream": false,
+                "options": {"stop": ["\n"]}
+            })))
+            .with_status(200)
+            .with_body(r#"{"model":"mistral","response":"Rayleigh scattering.","done":true}"#)
+            .create_async()
+            .await;
+
+        let llm = LLMOllama::new("mistral")
+            .with_config(OllamaConfig::new(&server.url()))
+            .with_stop_sequence(String::from("\n"));
+        let text = llm
+            .generate(String::from("Why is the sky blue?"))
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+        assert_eq!(text, "Rayleigh scattering.");
+    }
+}
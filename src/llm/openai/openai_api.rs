@@ -1,13 +1,16 @@
-use std::env;
+use std::collections::HashMap;
 
 use async_trait::async_trait;
-use reqwest::Client;
-use serde::Deserialize;
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
 use crate::{
+    client::{rate_limiter::Throttle, OpenAIConfig, RateLimiter},
     errors::{openai_errors::OpenaiError, ApiError},
     llm::base::BaseLLM,
+    schemas::llm::TokenUsage,
+    tokenizer::count_tokens,
+    usage::UsageTracker,
 };
 
 #[derive(Debug)]
@@ -27,19 +30,43 @@ impl LLMModel {
 
 pub struct LLMOpenAI {
     pub model: LLMModel,
-    pub temperature: u32,
-    pub openai_key: String,
-    pub stop_sequence: Option<String>,
+    // Sent instead of the name of `model`, e.g. for OpenAI compatible servers
+    pub model_name: Option<String>,
+    pub temperature: f32,
+    pub config: OpenAIConfig,
+    pub stop: Vec<String>,
     pub max_tokens: u32,
+    pub deployment: Option<String>,
+    pub top_p: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub seed: Option<i64>,
+    pub logit_bias: HashMap<u32, i32>,
+    pub user: Option<String>,
+    // Number of most likely tokens to return the log probabilities of, up to 5
+    pub logprobs: Option<u32>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub usage_tracker: Option<UsageTracker>,
 }
 impl LLMOpenAI {
-    pub fn new(model: LLMModel, temperature: u32, openai_key: String, max_tokens: u32) -> Self {
+    pub fn new(model: LLMModel, temperature: f32, openai_key: String, max_tokens: u32) -> Self {
         Self {
             model,
+            model_name: None,
             temperature,
-            openai_key,
-            stop_sequence: None,
+            config: OpenAIConfig::new(openai_key),
+            stop: Vec::new(),
             max_tokens,
+            deployment: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            seed: None,
+            logit_bias: HashMap::new(),
+            user: None,
+            logprobs: None,
+            rate_limiter: None,
+            usage_tracker: None,
         }
     }
 
@@ -48,17 +75,35 @@ impl LLMOpenAI {
         self
     }
 
+    /// Model id sent to the API instead of the name of `model`, for models outside
+    /// `LLMModel` such as those served by vLLM or LM Studio.
+    pub fn with_model_name(mut self, model_name: &str) -> Self {
+        self.model_name = Some(model_name.to_string());
+        self
+    }
+
     pub fn with_api_key(mut self, openai_key: String) -> Self {
-        self.openai_key = openai_key;
+        self.config.api_key = openai_key;
+        self
+    }
+
+    pub fn with_config(mut self, config: OpenAIConfig) -> Self {
+        self.config = config;
         self
     }
 
     pub fn with_stop_sequence(mut self, stop_sequence: String) -> Self {
-        self.stop_sequence = Some(stop_sequence);
+        self.stop = vec![stop_sequence];
+        self
+    }
+
+    /// Up to 4 sequences where the model stops generating.
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
         self
     }
 
-    pub fn with_temperature(mut self, temperature: u32) -> Self {
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
         self.temperature = temperature;
         self
     }
@@ -67,23 +112,143 @@ impl LLMOpenAI {
         self.max_tokens = max_tokens;
         self
     }
+
+    /// Azure deployment to send requests to. Defaults to the model name.
+    pub fn with_deployment(mut self, deployment: &str) -> Self {
+        self.deployment = Some(deployment.to_string());
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn with_presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.presence_penalty = Some(presence_penalty);
+        self
+    }
+
+    pub fn with_frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.frequency_penalty = Some(frequency_penalty);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: i64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn with_logit_bias(mut self, logit_bias: HashMap<u32, i32>) -> Self {
+        self.logit_bias = logit_bias;
+        self
+    }
+
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    pub fn with_logprobs(mut self, logprobs: u32) -> Self {
+        self.logprobs = Some(logprobs);
+        self
+    }
+
+    /// Throttles requests with a limiter that can be shared with other clients.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Records the tokens of every request, and refuses to send more once the
+    /// tracker's budget is exceeded.
+    pub fn with_usage_tracker(mut self, usage_tracker: UsageTracker) -> Self {
+        self.usage_tracker = Some(usage_tracker);
+        self
+    }
+
+    fn deployment(&self) -> &str {
+        self.deployment
+            .as_deref()
+            .unwrap_or_else(|| self.model_name())
+    }
+
+    fn model_name(&self) -> &str {
+        self.model_name
+            .as_deref()
+            .unwrap_or_else(|| self.model.as_str())
+    }
+
+    fn completion_request(&self, prompt: String) -> CompletionRequest<'_> {
+        CompletionRequest {
+            model: self.model_name(),
+            prompt,
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            stop: &self.stop,
+            top_p: self.top_p,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            seed: self.seed,
+            logit_bias: &self.logit_bias,
+            user: self.user.as_deref(),
+            logprobs: self.logprobs,
+        }
+    }
 }
 
 impl Default for LLMOpenAI {
     fn default() -> Self {
         Self {
             model: LLMModel::TextDavinci003,
-            temperature: 0,
-            openai_key: env::var("OPENAI_API_KEY").unwrap_or(String::new()),
-            stop_sequence: Some(String::from("\n")),
+            model_name: None,
+            temperature: 0.0,
+            config: OpenAIConfig::default(),
+            stop: vec![String::from("\n")],
             max_tokens: 1334,
+            deployment: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            seed: None,
+            logit_bias: HashMap::new(),
+            user: None,
+            logprobs: None,
+            rate_limiter: None,
+            usage_tracker: None,
         }
     }
 }
 
+#[derive(Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    prompt: String,
+    temperature: f32,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    logit_bias: &'a HashMap<u32, i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<u32>,
+}
+
 #[derive(Deserialize)]
 struct CompletionResponse {
+    model: Option<String>,
     choices: Vec<Choice>,
+    usage: Option<TokenUsage>,
 }
 
 #[derive(Deserialize)]
@@ -94,58 +259,112 @@ struct Choice {
 #[async_trait]
 impl BaseLLM for LLMOpenAI {
     async fn generate(&self, prompt: String) -> Result<String, ApiError> {
-        let client = Client::new();
-        let url = format!(
-            "https://api.openai.com/v1/engines/{}/completions",
-            self.model.as_str()
-        );
-        let payload = json!({
-            "prompt": prompt,
-            "temperature": self.temperature,
-            "stop": self.stop_sequence,
-            "max_tokens":self.max_tokens,
+        if let Some(usage_tracker) = &self.usage_tracker {
+            usage_tracker.check_budget()?;
+        }
+        // The API counts max_tokens against the limit as well
+        let throttle = Throttle::new(self.rate_limiter.as_ref(), self.deployment(), || {
+            count_tokens(&prompt) as u32 + self.max_tokens
         });
 
-        let response = match client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", self.openai_key))
-            .json(&payload)
-            .send()
-            .await
-        {
-            Ok(resp) => resp,
-            Err(e) => {
+        let payload = self.completion_request(prompt);
+
+        let request = self
+            .config
+            .post("completions", self.deployment())?
+            .json(&payload);
+        let response = self
+            .config
+            .send_throttled(self.config.http.with_request_timeout(request), throttle)
+            .await?;
+
+        let result: CompletionResponse = match response.json().await {
+            Ok(result) => result,
+            Err(_) => {
                 return Err(ApiError::OpenaiError(OpenaiError::new_generic_error(
-                    format!("Unknown error: {}", e),
+                    String::from("Failed to deserialize JSON"),
                 )))
             }
         };
 
-        if response.status().is_success() {
-            let result: CompletionResponse = match response.json().await {
-                Ok(result) => result,
-                Err(_) => {
-                    return Err(ApiError::OpenaiError(OpenaiError::new_generic_error(
-                        String::from("Failed to deserialize JSON"),
-                    )))
-                }
-            };
-
-            match result.choices.get(0) {
-                Some(choice) => Ok(choice.text.clone()),
-                None => Err(ApiError::OpenaiError(OpenaiError::new_generic_error(
-                    String::from("No choices returned"),
-                ))),
-            }
-        } else {
-            let code = response.status().as_u16();
-            let detail = match response.text().await {
-                Ok(detail) => detail,
-                Err(_) => String::from("Unknown error"),
-            };
-            Err(ApiError::OpenaiError(OpenaiError::from_http_status(
-                code, detail,
-            )))
+        if let Some(usage_tracker) = &self.usage_tracker {
+            let model = result.model.as_deref().unwrap_or_else(|| self.model_name());
+            usage_tracker.record(model, result.usage.unwrap_or_default());
+        }
+
+        match result.choices.first() {
+            Some(choice) => Ok(choice.text.clone()),
+            None => Err(ApiError::OpenaiError(OpenaiError::new_generic_error(
+                String::from("No choices returned"),
+            ))),
         }
     }
+
+    fn identifying_params(&self) -> Value {
+        let mut params =
+            serde_json::to_value(self.completion_request(String::new())).unwrap_or_default();
+        params["deployment"] = json!(self.deployment());
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_custom_model_name_is_sent() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/completions")
+            .match_body(Matcher::PartialJson(
+                json!({"model": "mistral-7b-instruct"}),
+            ))
+            .with_status(200)
+            .with_body(json!({"choices": [{"text": "Hello"}]}).to_string())
+            .create_async()
+            .await;
+
+        let llm = LLMOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base(&server.url()))
+            .with_model_name("mistral-7b-instruct");
+        let text = llm.generate(String::from("Hi")).await.unwrap();
+
+        mock.assert_async().await;
+        assert_eq!(text, "Hello");
+    }
+
+    #[tokio::test]
+    async fn test_usage_tracker_records_and_enforces_the_budget() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/completions")
+            .with_status(200)
+            .with_body(
+                json!({
+                    "model": "davinci-002",
+                    "choices": [{"text": "Hello"}],
+                    "usage": {"prompt_tokens": 5, "completion_tokens": 7, "total_tokens": 12}
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+
+        let tracker = UsageTracker::new().with_token_budget(10);
+        let llm = LLMOpenAI::default()
+            .with_config(OpenAIConfig::new(String::from("key")).with_api_base(&server.url()))
+            .with_usage_tracker(tracker.clone());
+        llm.generate(String::from("Hi")).await.unwrap();
+
+        assert_eq!(tracker.usage()["davinci-002"].total_tokens(), 12);
+        assert!(matches!(
+            llm.generate(String::from("Hi")).await,
+            Err(ApiError::BudgetExceeded(_))
+        ));
+        mock.assert_async().await;
+    }
 }
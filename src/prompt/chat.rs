@@ -1,12 +1,15 @@
 use serde_json::Value;
-use std::{collections::HashMap, error::Error};
-
-use crate::schemas::{
-    messages::{
-        is_base_message, message_from_map, AIMessage, BaseMessage, ChatMessage, HumanMessage,
-        SystemMessage,
+use std::collections::HashMap;
+
+use crate::{
+    errors::PromptError,
+    schemas::{
+        messages::{
+            is_base_message, message_from_map, AIMessage, BaseMessage, ChatMessage, HumanMessage,
+            ImageDetail, SystemMessage,
+        },
+        prompt::PromptValue,
     },
-    prompt::PromptValue,
 };
 
 use super::{BasePromptTemplate, PromptTemplate, TemplateArgs};
@@ -22,22 +25,19 @@ impl MessagesPlaceholder {
     }
 }
 impl BaseMessagePromptTemplate for MessagesPlaceholder {
-    fn format(&self, _args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, Box<dyn Error>> {
+    fn format(&self, _args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, PromptError> {
         unimplemented!()
     }
 
     fn format_messages(
         &self,
         args: &dyn TemplateArgs,
-    ) -> Result<Vec<Box<dyn BaseMessage>>, Box<dyn Error>> {
+    ) -> Result<Vec<Box<dyn BaseMessage>>, PromptError> {
         // Retrieve the variable from args
         let map = args.to_map(&self.input_variables())?;
-        let value = map.get(&self.variable_name).ok_or_else(|| {
-            Box::<dyn Error>::from(format!(
-                "Variable '{}' not found in provided arguments",
-                self.variable_name
-            ))
-        })?;
+        let value = map
+            .get(&self.variable_name)
+            .ok_or_else(|| PromptError::DataNotProvided(self.variable_name.clone()))?;
 
         if let Value::Array(values) = value {
             let mut messages = Vec::new();
@@ -49,9 +49,12 @@ impl BaseMessagePromptTemplate for MessagesPlaceholder {
                         .iter()
                         .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_string())))
                         .collect();
-                    messages.push(message_from_map(msg_map).map_err(|e| e.to_string())?)
+                    messages.push(
+                        message_from_map(msg_map)
+                            .map_err(|e| PromptError::InvalidVariable(e.to_string()))?,
+                    )
                 } else {
-                    return Err(Box::<dyn Error>::from(format!(
+                    return Err(PromptError::InvalidVariable(format!(
                         "Variable '{}' should be a list of base messages, got {:?}",
                         self.variable_name, v
                     )));
@@ -59,7 +62,7 @@ impl BaseMessagePromptTemplate for MessagesPlaceholder {
             }
             Ok(messages)
         } else {
-            Err(Box::<dyn Error>::from(format!(
+            Err(PromptError::InvalidVariable(format!(
                 "Variable '{}' should be a list of base messages, got {:?}",
                 self.variable_name, value
             )))
@@ -72,12 +75,12 @@ impl BaseMessagePromptTemplate for MessagesPlaceholder {
 }
 
 pub trait BaseMessagePromptTemplate: Send + Sync {
-    fn format(&self, args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, Box<dyn Error>>;
+    fn format(&self, args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, PromptError>;
 
     fn format_messages(
         &self,
         args: &dyn TemplateArgs,
-    ) -> Result<Vec<Box<dyn BaseMessage>>, Box<dyn Error>> {
+    ) -> Result<Vec<Box<dyn BaseMessage>>, PromptError> {
         Ok(vec![self.format(args)?])
     }
 
@@ -97,7 +100,7 @@ impl ChatMessagePromptTemplate {
     }
 }
 impl BaseMessagePromptTemplate for ChatMessagePromptTemplate {
-    fn format(&self, args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, Box<dyn Error>> {
+    fn format(&self, args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, PromptError> {
         let text = self.prompt.format(args)?;
         Ok(Box::new(ChatMessage::new(&self.role, &text)))
     }
@@ -109,19 +112,43 @@ impl BaseMessagePromptTemplate for ChatMessagePromptTemplate {
 
 pub struct HumanMessagePromptTemplate {
     prompt: PromptTemplate,
+    images: Vec<(PromptTemplate, Option<ImageDetail>)>,
 }
 impl HumanMessagePromptTemplate {
     pub fn new(prompt: PromptTemplate) -> Self {
-        Self { prompt }
+        Self {
+            prompt,
+            images: Vec::new(),
+        }
+    }
+
+    /// Attaches an image to the formatted message. `url` is a template too, so
+    /// it can be a variable such as `{{image_url}}`; it may also be a `data:` URL.
+    pub fn with_image(mut self, url: &str, detail: Option<ImageDetail>) -> Self {
+        self.images
+            .push((PromptTemplate::from_template(url), detail));
+        self
     }
 }
 impl BaseMessagePromptTemplate for HumanMessagePromptTemplate {
-    fn format(&self, args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, Box<dyn Error>> {
+    fn format(&self, args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, PromptError> {
         let text = self.prompt.format(args)?;
-        Ok(Box::new(HumanMessage::new(&text)))
+        let mut message = HumanMessage::new(&text);
+        for (url, detail) in &self.images {
+            message = message.with_image_url(&url.format_unescaped(args)?, *detail);
+        }
+        Ok(Box::new(message))
     }
     fn input_variables(&self) -> Vec<String> {
-        self.prompt.input_variables.clone()
+        let mut input_variables = self.prompt.input_variables.clone();
+        for (url, _) in &self.images {
+            for var in &url.input_variables {
+                if !input_variables.contains(var) {
+                    input_variables.push(var.clone());
+                }
+            }
+        }
+        input_variables
     }
 }
 
@@ -134,7 +161,7 @@ impl AIMessagePromptTemplate {
     }
 }
 impl BaseMessagePromptTemplate for AIMessagePromptTemplate {
-    fn format(&self, args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, Box<dyn Error>> {
+    fn format(&self, args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, PromptError> {
         let text = self.prompt.format(args)?;
         Ok(Box::new(AIMessage::new(&text)))
     }
@@ -153,7 +180,7 @@ impl SystemMessagePromptTemplate {
     }
 }
 impl BaseMessagePromptTemplate for SystemMessagePromptTemplate {
-    fn format(&self, args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, Box<dyn Error>> {
+    fn format(&self, args: &dyn TemplateArgs) -> Result<Box<dyn BaseMessage>, PromptError> {
         let text = self.prompt.format(args)?;
         Ok(Box::new(SystemMessage::new(&text)))
     }
@@ -167,7 +194,7 @@ pub struct ChatPromptValue {
     messages: Vec<Box<dyn BaseMessage>>,
 }
 impl PromptValue for ChatPromptValue {
-    fn to_string(&self) -> Result<String, Box<dyn Error>> {
+    fn to_string(&self) -> Result<String, PromptError> {
         let mut text = String::new();
         for msg in &self.messages {
             text.push_str(&format!("{}:{}\n", &msg.get_type(), &msg.get_content()))
@@ -176,20 +203,17 @@ impl PromptValue for ChatPromptValue {
     }
     fn to_chat_messages(
         &self,
-    ) -> Result<Vec<Box<dyn crate::schemas::messages::BaseMessage>>, Box<dyn Error>> {
+    ) -> Result<Vec<Box<dyn crate::schemas::messages::BaseMessage>>, PromptError> {
         Ok(self.messages.clone())
     }
 }
 
 pub trait BaseChatPromptTemplate: Send + Sync {
-    fn format(&self, args: &dyn TemplateArgs) -> Result<String, Box<dyn Error>> {
+    fn format(&self, args: &dyn TemplateArgs) -> Result<String, PromptError> {
         self.format_prompt(args)?.to_string()
     }
 
-    fn format_prompt(
-        &self,
-        args: &dyn TemplateArgs,
-    ) -> Result<Box<dyn PromptValue>, Box<dyn Error>> {
+    fn format_prompt(&self, args: &dyn TemplateArgs) -> Result<Box<dyn PromptValue>, PromptError> {
         let messages = self.format_messages(args)?;
         Ok(Box::new(ChatPromptValue { messages }))
     }
@@ -197,7 +221,7 @@ pub trait BaseChatPromptTemplate: Send + Sync {
     fn format_messages(
         &self,
         args: &dyn TemplateArgs,
-    ) -> Result<Vec<Box<dyn BaseMessage>>, Box<dyn Error>>;
+    ) -> Result<Vec<Box<dyn BaseMessage>>, PromptError>;
 
     fn input_variables(&self) -> Vec<String>;
 }
@@ -290,11 +314,11 @@ impl BaseChatPromptTemplate for ChatPromptTemplate {
     fn format_messages(
         &self,
         args: &dyn TemplateArgs,
-    ) -> Result<Vec<Box<dyn BaseMessage>>, Box<dyn Error>> {
+    ) -> Result<Vec<Box<dyn BaseMessage>>, PromptError> {
         let merged_args = args.to_map(&self.input_variables)?;
         for var in &self.input_variables {
             if !merged_args.contains_key(var) {
-                return Err(Box::new(std::fmt::Error));
+                return Err(PromptError::DataNotProvided(var.clone()));
             }
         }
         let merged = self.merge_partial_and_user_variables(&merged_args);
@@ -440,4 +464,28 @@ mod tests {
         }
         assert!(prompt.format_messages(&user_vars).is_ok())
     }
+
+    #[test]
+    fn test_human_prompt_with_image_variable() {
+        let prompt = ChatPromptTemplate::from_messages(vec![MessageLike::base_prompt_template(
+            HumanMessagePromptTemplate::new(PromptTemplate::from_template("What is in {{name}}?"))
+                .with_image("{{image_url}}", Some(ImageDetail::Low)),
+        )]);
+        assert_eq!(prompt.input_variables(), vec!["name", "image_url"]);
+
+        let mut user_vars = HashMap::new();
+        user_vars.insert("name".to_string(), json!("this photo"));
+        user_vars.insert("image_url".to_string(), json!("data:image/png;base64,aGk="));
+
+        let messages = prompt.format_messages(&user_vars).unwrap();
+
+        assert_eq!(messages[0].get_content(), "What is in this photo?");
+        assert_eq!(
+            messages[0].get_content_parts(),
+            vec![crate::schemas::messages::ContentPart::image_url(
+                "data:image/png;base64,aGk=",
+                Some(ImageDetail::Low)
+            )]
+        );
+    }
 }
@@ -1,24 +1,30 @@
 mod chat;
+#[allow(clippy::module_inception)]
 mod prompt;
 pub use chat::*;
 pub use prompt::{BasePromptTemplate, PromptTemplate, StringPromptValue};
 
 use serde_json::Value;
-use std::{collections::HashMap, error::Error};
+use std::collections::HashMap;
+
+use crate::errors::PromptError;
 
 pub trait TemplateArgs: Sync + Send {
-    fn to_map(&self, input_variables: &[String]) -> Result<HashMap<String, Value>, Box<dyn Error>>;
+    fn to_map(&self, input_variables: &[String]) -> Result<HashMap<String, Value>, PromptError>;
     fn clone_as_map(&self) -> HashMap<String, Value>;
 }
 
 impl TemplateArgs for String {
-    fn to_map(&self, input_variables: &[String]) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+    fn to_map(&self, input_variables: &[String]) -> Result<HashMap<String, Value>, PromptError> {
         if input_variables.len() == 1 {
             let mut map = HashMap::new();
             map.insert("input".to_string(), Value::String(self.clone()));
             Ok(map)
         } else {
-            Err(Box::new(std::fmt::Error))
+            Err(PromptError::DataNotProvided(format!(
+                "a single input cannot fill the variables {:?}",
+                input_variables
+            )))
         }
     }
 
@@ -30,7 +36,7 @@ impl TemplateArgs for String {
 }
 
 impl TemplateArgs for HashMap<String, Value> {
-    fn to_map(&self, _: &[String]) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+    fn to_map(&self, _: &[String]) -> Result<HashMap<String, Value>, PromptError> {
         Ok(self.clone())
     }
     fn clone_as_map(&self) -> HashMap<String, Value> {
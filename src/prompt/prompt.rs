@@ -1,21 +1,18 @@
-use crate::schemas::{messages::HumanMessage, prompt::PromptValue};
+use crate::{
+    errors::PromptError,
+    schemas::{messages::HumanMessage, prompt::PromptValue},
+};
 use handlebars::Handlebars;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{
-    collections::{HashMap, HashSet},
-    error::Error,
-};
+use std::collections::{HashMap, HashSet};
 
 use super::TemplateArgs;
 
 pub trait BasePromptTemplate {
-    fn format(&self, args: &dyn TemplateArgs) -> Result<String, Box<dyn Error>>;
-    fn format_prompt(
-        &self,
-        args: &dyn TemplateArgs,
-    ) -> Result<Box<dyn PromptValue>, Box<dyn Error>>;
+    fn format(&self, args: &dyn TemplateArgs) -> Result<String, PromptError>;
+    fn format_prompt(&self, args: &dyn TemplateArgs) -> Result<Box<dyn PromptValue>, PromptError>;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -77,26 +74,38 @@ impl PromptTemplate {
 
         merged
     }
-}
 
-impl BasePromptTemplate for PromptTemplate {
-    fn format(&self, args: &dyn TemplateArgs) -> Result<String, Box<dyn Error>> {
+    // Renders without HTML escaping, for values such as URLs and base64 data
+    // that must be sent verbatim.
+    pub(crate) fn format_unescaped(&self, args: &dyn TemplateArgs) -> Result<String, PromptError> {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(handlebars::no_escape);
+        self.render(&handlebars, args)
+    }
+
+    fn render(
+        &self,
+        handlebars: &Handlebars,
+        args: &dyn TemplateArgs,
+    ) -> Result<String, PromptError> {
         let merged_args = args.to_map(&self.input_variables)?;
         for var in &self.input_variables {
             if !merged_args.contains_key(var) {
-                return Err(Box::new(std::fmt::Error));
+                return Err(PromptError::DataNotProvided(var.clone()));
             }
         }
         let merged = self.merge_partial_and_user_variables(&merged_args);
-        let handlebars = Handlebars::new();
         let prompt = handlebars.render_template(&self.template, &merged)?;
         Ok(prompt)
     }
+}
 
-    fn format_prompt(
-        &self,
-        args: &dyn TemplateArgs,
-    ) -> Result<Box<dyn PromptValue>, Box<dyn Error>> {
+impl BasePromptTemplate for PromptTemplate {
+    fn format(&self, args: &dyn TemplateArgs) -> Result<String, PromptError> {
+        self.render(&Handlebars::new(), args)
+    }
+
+    fn format_prompt(&self, args: &dyn TemplateArgs) -> Result<Box<dyn PromptValue>, PromptError> {
         Ok(Box::new(StringPromptValue {
             text: self.format(args)?,
         }))
@@ -109,13 +118,13 @@ pub struct StringPromptValue {
 }
 
 impl PromptValue for StringPromptValue {
-    fn to_string(&self) -> Result<String, Box<dyn Error>> {
+    fn to_string(&self) -> Result<String, PromptError> {
         Ok(self.text.clone())
     }
 
     fn to_chat_messages(
         &self,
-    ) -> Result<Vec<Box<dyn crate::schemas::messages::BaseMessage>>, Box<dyn Error>> {
+    ) -> Result<Vec<Box<dyn crate::schemas::messages::BaseMessage>>, PromptError> {
         Ok(vec![Box::new(HumanMessage::new(&self.text))])
     }
 }
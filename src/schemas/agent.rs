@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 
+use crate::errors::ApiError;
+
 pub enum ToolInput {
     //Will implement this in the future
     StrInput(String),
@@ -28,5 +30,5 @@ pub enum AgentEvent {
 
 pub enum AgentPlan {
     Text(AgentEvent),
-    Stream(mpsc::Receiver<Result<String, reqwest_eventsource::Error>>),
+    Stream(mpsc::Receiver<Result<String, ApiError>>),
 }
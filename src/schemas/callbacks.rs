@@ -0,0 +1,278 @@
+use std::{collections::HashMap, error::Error};
+
+use futures::{stream, StreamExt};
+use serde_json::Value;
+pub use uuid::Uuid;
+
+use crate::errors::{ApiError, LLMError};
+
+use super::{
+    agent::{AgentAction, AgentFinish},
+    llm::{ChatChunk, ChatResult, ChatStream},
+    messages::BaseMessage,
+    run::RunOptions,
+};
+
+/// Hooks called as chat models, chains, tools and agents run. Register handlers
+/// with `RunOptions::with_callback` and pass the options to the `*_with_options`
+/// methods.
+///
+/// Every run gets a `run_id`. The start hooks also receive the `parent_run_id`
+/// of the run that started it, e.g. the chain that called a model, so handlers
+/// can rebuild the tree of a run. The other hooks refer to the run by its id.
+///
+/// Hooks are called inline and should return quickly; send the events to a
+/// channel or a task for slow work. All hooks do nothing by default.
+#[allow(unused_variables)]
+pub trait CallbackHandler: Send + Sync {
+    fn on_llm_start(
+        &self,
+        run_id: Uuid,
+        parent_run_id: Option<Uuid>,
+        messages: &[Box<dyn BaseMessage>],
+    ) {
+    }
+
+    // Called for every content delta of a stream
+    fn on_llm_new_token(&self, run_id: Uuid, token: &str) {}
+
+    // Streams end with the result assembled from their chunks
+    fn on_llm_end(&self, run_id: Uuid, result: &ChatResult) {}
+
+    fn on_llm_error(&self, run_id: Uuid, error: &ApiError) {}
+
+    fn on_chain_start(
+        &self,
+        run_id: Uuid,
+        parent_run_id: Option<Uuid>,
+        inputs: &HashMap<String, Value>,
+    ) {
+    }
+
+    fn on_chain_end(&self, run_id: Uuid, output: &str) {}
+
+    // A chain whose stream fails ends with `LLMError::Stream`, and with
+    // `ApiError::Cancelled` in it when the receiver of the stream is dropped
+    fn on_chain_error(&self, run_id: Uuid, error: &LLMError) {}
+
+    fn on_tool_start(&self, run_id: Uuid, parent_run_id: Option<Uuid>, tool: &str, input: &str) {}
+
+    fn on_tool_end(&self, run_id: Uuid, output: &str) {}
+
+    fn on_tool_error(&self, run_id: Uuid, error: &(dyn Error + Send + Sync)) {}
+
+    // `run_id` is the run of the agent executor
+    fn on_agent_action(&self, run_id: Uuid, action: &AgentAction) {}
+
+    fn on_agent_finish(&self, run_id: Uuid, finish: &AgentFinish) {}
+}
+
+// Reports the content deltas of a model stream as tokens, then the assembled
+// result of `model` or the error it failed with.
+pub(crate) fn watch_llm_stream(
+    inner: ChatStream,
+    run_id: Uuid,
+    model: String,
+    options: RunOptions,
+) -> ChatStream {
+    stream::unfold(Some((inner, Vec::new())), move |state| {
+        let options = options.clone();
+        let model = model.clone();
+        async move {
+            let (mut inner, mut chunks) = state?;
+            match inner.next().await {
+                Some(Ok(chunk)) => {
+                    if let ChatChunk::Content(token) = &chunk {
+                        options.emit(|handler| handler.on_llm_new_token(run_id, token));
+                    }
+                    chunks.push(chunk.clone());
+                    Some((Ok(chunk), Some((inner, chunks))))
+                }
+                Some(Err(e)) => {
+                    options.emit(|handler| handler.on_llm_error(run_id, &e));
+                    Some((Err(e), None))
+                }
+                None => {
+                    let chunks = stream::iter(chunks.into_iter().map(Ok)).boxed();
+                    if let Ok(mut result) = ChatResult::from_stream(chunks).await {
+                        result.model = model;
+                        options.emit(|handler| handler.on_llm_end(run_id, &result));
+                    }
+                    None
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+
+    use crate::{
+        agents::{
+            chat::{output_parser::ConvoOutputParser, ConversationalAgent},
+            executor::AgentExecutor,
+        },
+        chains::chain_trait::ChainTrait,
+        chat_models::{chat_model_trait::ChatTrait, fake::FakeChatModel},
+        errors::BoxError,
+        schemas::{llm::LlmResponse, messages::HumanMessage},
+        tools::tool_trait::Tool,
+    };
+
+    use super::*;
+
+    // Records the events as `(event, run id, parent run id)`
+    #[derive(Default)]
+    struct RecordingHandler {
+        events: Mutex<Vec<(String, Uuid, Option<Uuid>)>>,
+    }
+    impl RecordingHandler {
+        fn push(&self, event: &str, run_id: Uuid, parent_run_id: Option<Uuid>) {
+            let mut events = self.events.lock().unwrap();
+            events.push((event.to_string(), run_id, parent_run_id));
+        }
+
+        fn names(&self) -> Vec<String> {
+            let events = self.events.lock().unwrap();
+            events.iter().map(|(name, _, _)| name.clone()).collect()
+        }
+    }
+    impl CallbackHandler for RecordingHandler {
+        fn on_llm_start(&self, run_id: Uuid, parent: Option<Uuid>, _: &[Box<dyn BaseMessage>]) {
+            self.push("llm_start", run_id, parent);
+        }
+        fn on_llm_new_token(&self, run_id: Uuid, token: &str) {
+            self.push(&format!("token {}", token), run_id, None);
+        }
+        fn on_llm_end(&self, run_id: Uuid, result: &ChatResult) {
+            self.push(&format!("llm_end {}", result.text()), run_id, None);
+        }
+        fn on_chain_start(&self, run_id: Uuid, parent: Option<Uuid>, _: &HashMap<String, Value>) {
+            self.push("chain_start", run_id, parent);
+        }
+        fn on_chain_end(&self, run_id: Uuid, _output: &str) {
+            self.push("chain_end", run_id, None);
+        }
+        fn on_tool_start(&self, run_id: Uuid, parent: Option<Uuid>, tool: &str, _input: &str) {
+            self.push(&format!("tool_start {}", tool), run_id, parent);
+        }
+        fn on_tool_end(&self, run_id: Uuid, output: &str) {
+            self.push(&format!("tool_end {}", output), run_id, None);
+        }
+        fn on_agent_action(&self, run_id: Uuid, action: &AgentAction) {
+            self.push(&format!("agent_action {}", action.tool), run_id, None);
+        }
+        fn on_agent_finish(&self, run_id: Uuid, finish: &AgentFinish) {
+            self.push(
+                &format!("agent_finish {}", finish.return_values),
+                run_id,
+                None,
+            );
+        }
+    }
+
+    #[derive(Clone)]
+    struct CalcTool;
+    #[async_trait]
+    impl Tool for CalcTool {
+        fn name(&self) -> String {
+            "Calculator".to_string()
+        }
+        fn description(&self) -> String {
+            "Calculates".to_string()
+        }
+        async fn call(&self, _input: &str) -> Result<String, BoxError> {
+            Ok("50".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_agent_run_reports_nested_runs() {
+        let llm = FakeChatModel::new(vec![
+            "```json\n{\"action\": \"Calculator\", \"action_input\": \"20 + 30\"}\n```",
+            "```json\n{\"action\": \"Final Answer\", \"action_input\": \"He is 50\"}\n```",
+        ]);
+        let agent = ConversationalAgent::from_llm_and_tools(
+            Box::new(llm),
+            vec![Arc::new(CalcTool)],
+            Box::new(ConvoOutputParser::new()),
+        )
+        .unwrap();
+        let executor = AgentExecutor::from_agent(Box::new(agent));
+        let handler = Arc::new(RecordingHandler::default());
+        let options = RunOptions::new().with_callback(handler.clone());
+
+        let result = executor
+            .call_with_options(&String::from("How old is he?"), &options)
+            .await
+            .unwrap();
+        assert_eq!(result.text(), "He is 50");
+
+        let names: Vec<String> = handler
+            .names()
+            .into_iter()
+            .map(|name| name.split(' ').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "chain_start",
+                "chain_start",
+                "llm_start",
+                "llm_end",
+                "chain_end",
+                "agent_action",
+                "tool_start",
+                "tool_end",
+                "chain_start",
+                "llm_start",
+                "llm_end",
+                "chain_end",
+                "agent_finish",
+                "chain_end",
+            ]
+        );
+        assert_eq!(handler.names()[12], "agent_finish He is 50");
+
+        // The chain runs under the executor, the model under the chain and the
+        // tool under the executor
+        let events = handler.events.lock().unwrap();
+        let executor_run = events[0].1;
+        assert_eq!(events[0].2, None);
+        assert_eq!(events[1].2, Some(executor_run));
+        assert_eq!(events[2].2, Some(events[1].1));
+        assert_eq!(events[6].2, Some(executor_run));
+        assert_eq!(events.last().unwrap().1, executor_run);
+    }
+
+    #[tokio::test]
+    async fn test_stream_reports_tokens_then_result() {
+        let llm = FakeChatModel::new(vec!["hello world"]).with_stream();
+        let handler = Arc::new(RecordingHandler::default());
+        let options = RunOptions::new().with_callback(handler.clone());
+
+        let response = llm
+            .invoke_with_options(vec![Box::new(HumanMessage::new("hi"))], &options)
+            .await
+            .unwrap();
+        let LlmResponse::Stream(chat_stream) = response else {
+            panic!("expected a stream");
+        };
+        chat_stream.collect::<Vec<_>>().await;
+
+        assert_eq!(
+            handler.names(),
+            vec![
+                "llm_start",
+                "token hello ",
+                "token world",
+                "llm_end hello world"
+            ]
+        );
+    }
+}
@@ -1,6 +1,23 @@
 use tokio::sync::mpsc;
 
+use crate::errors::ApiError;
+
 pub enum ChainResponse {
     Text(String),
-    Stream(mpsc::Receiver<Result<String, reqwest_eventsource::Error>>),
+    Stream(mpsc::Receiver<Result<String, ApiError>>),
+}
+impl ChainResponse {
+    /// Returns the text, waiting for the whole stream when streaming.
+    pub async fn into_text(self) -> Result<String, ApiError> {
+        match self {
+            ChainResponse::Text(text) => Ok(text),
+            ChainResponse::Stream(mut stream) => {
+                let mut text = String::new();
+                while let Some(chunk) = stream.recv().await {
+                    text.push_str(&chunk?);
+                }
+                Ok(text)
+            }
+        }
+    }
 }
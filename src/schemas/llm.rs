@@ -1,6 +1,192 @@
-use reqwest_eventsource::EventSource;
+use std::{ops::AddAssign, pin::Pin};
+
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::errors::ApiError;
+
+use super::{
+    messages::{AIMessage, ToolCall},
+    tools::{ToolCallAccumulator, ToolCallDelta},
+};
 
 pub enum LlmResponse {
-    Text(String),
-    Stream(EventSource),
+    Chat(ChatResult),
+    Stream(ChatStream),
+}
+
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<ChatChunk, ApiError>> + Send>>;
+
+// A streamed piece of the first generation. Providers send the finish reason and
+// the usage, when they report them, after the last content or tool call delta.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChatChunk {
+    Content(String),
+    ToolCall(ToolCallDelta),
+    Finish(String),
+    Usage(TokenUsage),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+impl TokenUsage {
+    pub fn new(prompt_tokens: u32, completion_tokens: u32) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+// The shape the model has to answer in. Providers that do not support a format
+// ignore it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema {
+        name: String,
+        schema: Value,
+        strict: bool,
+    },
+}
+impl ResponseFormat {
+    pub fn json_schema(name: &str, schema: Value) -> Self {
+        ResponseFormat::JsonSchema {
+            name: String::from(name),
+            schema,
+            strict: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+    // The most likely alternatives at this position, when top_logprobs is set
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+// One of the candidate answers of a chat completion. The message carries the
+// text and any tool calls requested by the model.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatGeneration {
+    pub index: u32,
+    pub message: AIMessage,
+    pub finish_reason: Option<String>,
+    // Only filled when logprobs were requested
+    #[serde(default)]
+    pub logprobs: Vec<TokenLogprob>,
+}
+impl ChatGeneration {
+    // True when the answer was cut short by the max_tokens limit.
+    pub fn is_truncated(&self) -> bool {
+        matches!(
+            self.finish_reason.as_deref(),
+            Some("length") | Some("max_tokens")
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ChatResult {
+    pub id: Option<String>,
+    pub model: String,
+    pub system_fingerprint: Option<String>,
+    pub generations: Vec<ChatGeneration>,
+    pub usage: Option<TokenUsage>,
+}
+impl ChatResult {
+    pub fn from_text(text: &str) -> Self {
+        Self {
+            generations: vec![ChatGeneration {
+                index: 0,
+                message: AIMessage::new(text),
+                finish_reason: None,
+                logprobs: Vec::new(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Consumes a stream, assembling its chunks into a single generation.
+    pub async fn from_stream(mut stream: ChatStream) -> Result<Self, ApiError> {
+        let mut content = String::new();
+        let mut tool_calls = ToolCallAccumulator::new();
+        let mut finish_reason = None;
+        let mut usage = None;
+
+        while let Some(chunk) = stream.next().await {
+            match chunk? {
+                ChatChunk::Content(delta) => content.push_str(&delta),
+                ChatChunk::ToolCall(delta) => tool_calls.push(&delta),
+                ChatChunk::Finish(reason) => finish_reason = Some(reason),
+                ChatChunk::Usage(chunk_usage) => usage = Some(chunk_usage),
+            }
+        }
+
+        Ok(Self {
+            generations: vec![ChatGeneration {
+                index: 0,
+                message: AIMessage::new(&content).with_tool_calls(tool_calls.finish()),
+                finish_reason,
+                logprobs: Vec::new(),
+            }],
+            usage,
+            ..Default::default()
+        })
+    }
+
+    /// The content of the first generation.
+    pub fn text(&self) -> String {
+        self.generations
+            .first()
+            .map(|generation| generation.message.content.clone())
+            .unwrap_or_default()
+    }
+
+    /// The tool calls requested in the first generation.
+    pub fn tool_calls(&self) -> Vec<ToolCall> {
+        self.generations
+            .first()
+            .map(|generation| generation.message.tool_calls.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn finish_reason(&self) -> Option<&str> {
+        self.generations
+            .first()
+            .and_then(|generation| generation.finish_reason.as_deref())
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.generations
+            .first()
+            .is_some_and(ChatGeneration::is_truncated)
+    }
 }
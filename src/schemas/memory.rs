@@ -4,15 +4,11 @@ pub trait BaseChatMessageHistory: Send + Sync {
     fn messages(&self) -> Vec<Box<dyn BaseMessage>>;
 
     fn add_user_message(&mut self, message: &str) {
-        self.add_message(Box::new(HumanMessage {
-            content: message.to_string(),
-        }));
+        self.add_message(Box::new(HumanMessage::new(message)));
     }
 
     fn add_ai_message(&mut self, message: &str) {
-        self.add_message(Box::new(AIMessage {
-            content: message.to_string(),
-        }));
+        self.add_message(Box::new(AIMessage::new(message)));
     }
 
     fn add_message(&mut self, message: Box<dyn BaseMessage>);
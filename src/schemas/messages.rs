@@ -1,15 +1,27 @@
 use serde_json::Value;
-use std::{
-    collections::HashMap,
-    io::{self, ErrorKind},
-};
+use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use crate::errors::LLMError;
+
 pub trait BaseMessage: Send + Sync {
     fn get_type(&self) -> String;
     fn get_content(&self) -> String;
     fn clone_box(&self) -> Box<dyn BaseMessage>;
+
+    fn get_tool_calls(&self) -> Vec<ToolCall> {
+        Vec::new()
+    }
+
+    fn get_tool_call_id(&self) -> Option<String> {
+        None
+    }
+
+    // Images and files sent along with the text content.
+    fn get_content_parts(&self) -> Vec<ContentPart> {
+        Vec::new()
+    }
 }
 impl Clone for Box<dyn BaseMessage> {
     fn clone(&self) -> Box<dyn BaseMessage> {
@@ -38,16 +50,120 @@ impl<'de> Deserialize<'de> for Box<dyn BaseMessage> {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageDetail {
+    Auto,
+    Low,
+    High,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImageUrl {
+    // An http(s) URL or a `data:` URL with the base64 encoded image
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ImageDetail>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileData {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    // A `data:` URL with the base64 encoded file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_data: Option<String>,
+    // The id of a file already uploaded to the provider
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+}
+
+/// One part of a multimodal message. Serializes to OpenAI's chat format, e.g.
+/// `{"type":"image_url","image_url":{"url":"...","detail":"low"}}`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+    File { file: FileData },
+}
+impl ContentPart {
+    pub fn text(text: &str) -> Self {
+        ContentPart::Text {
+            text: String::from(text),
+        }
+    }
+
+    pub fn image_url(url: &str, detail: Option<ImageDetail>) -> Self {
+        ContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: String::from(url),
+                detail,
+            },
+        }
+    }
+
+    // `data` must already be base64 encoded.
+    pub fn image_base64(media_type: &str, data: &str, detail: Option<ImageDetail>) -> Self {
+        Self::image_url(&format!("data:{};base64,{}", media_type, data), detail)
+    }
+
+    pub fn file_base64(filename: &str, media_type: &str, data: &str) -> Self {
+        ContentPart::File {
+            file: FileData {
+                filename: Some(String::from(filename)),
+                file_data: Some(format!("data:{};base64,{}", media_type, data)),
+                file_id: None,
+            },
+        }
+    }
+
+    pub fn file_id(file_id: &str) -> Self {
+        ContentPart::File {
+            file: FileData {
+                filename: None,
+                file_data: None,
+                file_id: Some(String::from(file_id)),
+            },
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct HumanMessage {
     pub content: String,
+    // Sent after `content`, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content_parts: Vec<ContentPart>,
 }
 impl HumanMessage {
     pub fn new(content: &str) -> Self {
         Self {
             content: String::from(content),
+            content_parts: Vec::new(),
         }
     }
+
+    pub fn with_content_parts(mut self, content_parts: Vec<ContentPart>) -> Self {
+        self.content_parts = content_parts;
+        self
+    }
+
+    pub fn with_image_url(mut self, url: &str, detail: Option<ImageDetail>) -> Self {
+        self.content_parts.push(ContentPart::image_url(url, detail));
+        self
+    }
+
+    pub fn with_image_base64(
+        mut self,
+        media_type: &str,
+        data: &str,
+        detail: Option<ImageDetail>,
+    ) -> Self {
+        self.content_parts
+            .push(ContentPart::image_base64(media_type, data, detail));
+        self
+    }
 }
 impl BaseMessage for HumanMessage {
     fn get_type(&self) -> String {
@@ -60,6 +176,10 @@ impl BaseMessage for HumanMessage {
     fn clone_box(&self) -> Box<dyn BaseMessage> {
         Box::new(self.clone())
     }
+
+    fn get_content_parts(&self) -> Vec<ContentPart> {
+        self.content_parts.clone()
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -87,16 +207,52 @@ impl BaseMessage for SystemMessage {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    // JSON encoded arguments, as generated by the model. They are not guaranteed
+    // to be valid JSON.
+    pub arguments: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub call_type: String,
+    pub function: FunctionCall,
+}
+impl ToolCall {
+    pub fn new(id: &str, name: &str, arguments: &str) -> Self {
+        Self {
+            id: String::from(id),
+            call_type: String::from("function"),
+            function: FunctionCall {
+                name: String::from(name),
+                arguments: String::from(arguments),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AIMessage {
     pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
 }
 impl AIMessage {
     pub fn new(content: &str) -> Self {
         Self {
             content: String::from(content),
+            tool_calls: Vec::new(),
         }
     }
+
+    pub fn with_tool_calls(mut self, tool_calls: Vec<ToolCall>) -> Self {
+        self.tool_calls = tool_calls;
+        self
+    }
 }
 impl BaseMessage for AIMessage {
     fn get_type(&self) -> String {
@@ -110,6 +266,42 @@ impl BaseMessage for AIMessage {
     fn clone_box(&self) -> Box<dyn BaseMessage> {
         Box::new(self.clone())
     }
+
+    fn get_tool_calls(&self) -> Vec<ToolCall> {
+        self.tool_calls.clone()
+    }
+}
+
+// The result of a tool call, sent back to the model.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ToolMessage {
+    pub content: String,
+    pub tool_call_id: String,
+}
+impl ToolMessage {
+    pub fn new(content: &str, tool_call_id: &str) -> Self {
+        Self {
+            content: String::from(content),
+            tool_call_id: String::from(tool_call_id),
+        }
+    }
+}
+impl BaseMessage for ToolMessage {
+    fn get_type(&self) -> String {
+        String::from("tool")
+    }
+
+    fn get_content(&self) -> String {
+        self.content.clone()
+    }
+
+    fn clone_box(&self) -> Box<dyn BaseMessage> {
+        Box::new(self.clone())
+    }
+
+    fn get_tool_call_id(&self) -> Option<String> {
+        Some(self.tool_call_id.clone())
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -141,22 +333,22 @@ impl BaseMessage for ChatMessage {
 
 pub fn message_from_map(
     message: HashMap<String, String>,
-) -> Result<Box<dyn BaseMessage>, Box<dyn std::error::Error + Send>> {
+) -> Result<Box<dyn BaseMessage>, LLMError> {
     let message_type = match message.get("type") {
         Some(t) => t,
-        None => {
-            return Err(Box::new(io::Error::new(
-                ErrorKind::Other,
-                "No type key on map",
-            )))
-        }
+        None => return Err(LLMError::InvalidMessage(String::from("No type key on map"))),
     };
 
     match message_type.as_str() {
         "user" => {
             let content = message.get("content").unwrap_or(&String::from("")).clone();
+            let content_parts = match message.get("content_parts") {
+                Some(content_parts) => serde_json::from_str(content_parts)?,
+                None => Vec::new(),
+            };
             Ok(Box::new(HumanMessage {
                 content: content.to_string(),
+                content_parts,
             }))
         }
 
@@ -169,21 +361,42 @@ pub fn message_from_map(
 
         "assistant" => {
             let content = message.get("content").unwrap_or(&String::from("")).clone();
+            let tool_calls = match message.get("tool_calls") {
+                Some(tool_calls) => serde_json::from_str(tool_calls)?,
+                None => Vec::new(),
+            };
             Ok(Box::new(AIMessage {
                 content: content.to_string(),
+                tool_calls,
+            }))
+        }
+
+        "tool" => {
+            let content = message.get("content").unwrap_or(&String::from("")).clone();
+            let tool_call_id = match message.get("tool_call_id") {
+                Some(id) => id.clone(),
+                None => {
+                    return Err(LLMError::InvalidMessage(String::from(
+                        "No tool_call_id key on map",
+                    )))
+                }
+            };
+            Ok(Box::new(ToolMessage {
+                content,
+                tool_call_id,
             }))
         }
 
-        _ => Err(Box::new(io::Error::new(
-            ErrorKind::Other,
-            format!("Got unexpected message type: {}", message_type),
+        _ => Err(LLMError::InvalidMessage(format!(
+            "Got unexpected message type: {}",
+            message_type
         ))),
     }
 }
 
 pub fn messages_from_map(
     messages: Vec<HashMap<String, String>>,
-) -> Result<Vec<Box<dyn BaseMessage>>, Box<dyn std::error::Error + Send>> {
+) -> Result<Vec<Box<dyn BaseMessage>>, LLMError> {
     messages.into_iter().map(message_from_map).collect()
 }
 
@@ -193,6 +406,23 @@ pub fn message_to_map(message: Box<dyn BaseMessage>) -> HashMap<String, String>
     map.insert("type".to_string(), message.get_type());
     map.insert("content".to_string(), message.get_content());
 
+    // Tool calls and content parts are kept as JSON strings so the map stays flat
+    let tool_calls = message.get_tool_calls();
+    if !tool_calls.is_empty() {
+        if let Ok(tool_calls) = serde_json::to_string(&tool_calls) {
+            map.insert("tool_calls".to_string(), tool_calls);
+        }
+    }
+    if let Some(tool_call_id) = message.get_tool_call_id() {
+        map.insert("tool_call_id".to_string(), tool_call_id);
+    }
+    let content_parts = message.get_content_parts();
+    if !content_parts.is_empty() {
+        if let Ok(content_parts) = serde_json::to_string(&content_parts) {
+            map.insert("content_parts".to_string(), content_parts);
+        }
+    }
+
     map
 }
 
@@ -216,3 +446,56 @@ pub fn is_base_message(value: &Value) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_messages_round_trip_through_map() {
+        let messages: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(AIMessage::new("").with_tool_calls(vec![ToolCall::new(
+                "call_1",
+                "search",
+                r#"{"q":"rust"}"#,
+            )])),
+            Box::new(ToolMessage::new("Rust is a language", "call_1")),
+        ];
+
+        let maps = messages_to_map(messages);
+        let messages = messages_from_map(maps).unwrap();
+
+        assert_eq!(
+            messages[0].get_tool_calls(),
+            vec![ToolCall::new("call_1", "search", r#"{"q":"rust"}"#)]
+        );
+        assert_eq!(messages[1].get_type(), "tool");
+        assert_eq!(messages[1].get_tool_call_id(), Some(String::from("call_1")));
+    }
+
+    #[test]
+    fn test_content_parts_round_trip_through_map() {
+        let message = HumanMessage::new("What is in this image?")
+            .with_image_url("https://example.com/cat.png", Some(ImageDetail::Low))
+            .with_image_base64("image/png", "aGVsbG8=", None);
+
+        let map = message_to_map(Box::new(message.clone()));
+        let restored = message_from_map(map).unwrap();
+
+        assert_eq!(restored.get_content(), "What is in this image?");
+        assert_eq!(restored.get_content_parts(), message.content_parts);
+    }
+
+    #[test]
+    fn test_content_part_serializes_to_openai_format() {
+        let part = ContentPart::image_base64("image/jpeg", "abc", Some(ImageDetail::High));
+
+        assert_eq!(
+            serde_json::to_value(&part).unwrap(),
+            serde_json::json!({
+                "type": "image_url",
+                "image_url": {"url": "data:image/jpeg;base64,abc", "detail": "high"}
+            })
+        );
+    }
+}
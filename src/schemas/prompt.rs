@@ -1,7 +1,6 @@
-use crate::schemas::messages::BaseMessage;
-use std::error::Error;
+use crate::{errors::PromptError, schemas::messages::BaseMessage};
 
 pub trait PromptValue: Send + Sync {
-    fn to_string(&self) -> Result<String, Box<dyn Error>>;
-    fn to_chat_messages(&self) -> Result<Vec<Box<dyn BaseMessage>>, Box<dyn Error>>;
+    fn to_string(&self) -> Result<String, PromptError>;
+    fn to_chat_messages(&self) -> Result<Vec<Box<dyn BaseMessage>>, PromptError>;
 }
@@ -0,0 +1,265 @@
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+
+use futures::{stream, stream::BoxStream, StreamExt};
+use tokio::{sync::mpsc, time::Instant};
+pub use tokio_util::sync::CancellationToken;
+
+use crate::{errors::ApiError, usage::UsageTracker};
+
+use super::callbacks::{CallbackHandler, Uuid};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Per-call settings of a chain, agent or model run: a cancellation token, a
+/// deadline, the callback handlers, a usage tracker and whether the content of
+/// the run may be traced. They reach the model requests, the tool
+/// calls and the tasks forwarding streams, which stop and drop the upstream
+/// request when the run is cancelled, the deadline passes or the receiver of the
+/// stream is dropped.
+#[derive(Clone, Default)]
+pub struct RunOptions {
+    cancellation_token: CancellationToken,
+    deadline: Option<Instant>,
+    callbacks: Vec<Arc<dyn CallbackHandler>>,
+    parent_run_id: Option<Uuid>,
+    usage_tracker: Option<UsageTracker>,
+    trace_content: bool,
+}
+
+impl fmt::Debug for RunOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunOptions")
+            .field("cancellation_token", &self.cancellation_token)
+            .field("deadline", &self.deadline)
+            .field("callbacks", &self.callbacks.len())
+            .field("parent_run_id", &self.parent_run_id)
+            .field("usage_tracker", &self.usage_tracker)
+            .field("trace_content", &self.trace_content)
+            .finish()
+    }
+}
+
+impl RunOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = cancellation_token;
+        self
+    }
+
+    // Keeps the earliest deadline when called more than once.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(
+            self.deadline
+                .map_or(deadline, |current| current.min(deadline)),
+        );
+        self
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn with_callback(mut self, handler: Arc<dyn CallbackHandler>) -> Self {
+        self.callbacks.push(handler);
+        self
+    }
+
+    /// Records the tokens of every model call of the run, and stops the run
+    /// with `ApiError::BudgetExceeded` once the tracker's budget is exceeded.
+    pub fn with_usage_tracker(mut self, usage_tracker: UsageTracker) -> Self {
+        self.callbacks.push(Arc::new(usage_tracker.clone()));
+        self.usage_tracker = Some(usage_tracker);
+        self
+    }
+
+    /// Records the prompts, the answers and the tool inputs and outputs in the
+    /// `tracing` spans of the run. They are redacted by default, as they may
+    /// hold personal data.
+    pub fn with_traced_content(mut self) -> Self {
+        self.trace_content = true;
+        self
+    }
+
+    /// Makes the runs started with these options children of `parent_run_id`.
+    pub fn with_parent_run_id(mut self, parent_run_id: Uuid) -> Self {
+        self.parent_run_id = Some(parent_run_id);
+        self
+    }
+
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn parent_run_id(&self) -> Option<Uuid> {
+        self.parent_run_id
+    }
+
+    pub fn usage_tracker(&self) -> Option<&UsageTracker> {
+        self.usage_tracker.as_ref()
+    }
+
+    /// The content to record in a span, or a placeholder unless the run opted
+    /// in with `with_traced_content`.
+    pub fn redact(&self, content: impl FnOnce() -> String) -> String {
+        if self.trace_content {
+            content()
+        } else {
+            String::from(REDACTED)
+        }
+    }
+
+    /// A new run id, and the options for the runs it starts in turn.
+    pub fn start_run(&self) -> (Uuid, RunOptions) {
+        let run_id = Uuid::new_v4();
+        (run_id, self.clone().with_parent_run_id(run_id))
+    }
+
+    /// Calls `hook` on every callback handler.
+    pub fn emit(&self, hook: impl Fn(&dyn CallbackHandler)) {
+        for handler in &self.callbacks {
+            hook(handler.as_ref());
+        }
+    }
+
+    pub fn has_callbacks(&self) -> bool {
+        !self.callbacks.is_empty()
+    }
+
+    /// The error the run should stop with, if it was cancelled, is past its
+    /// deadline or over its budget.
+    pub fn check(&self) -> Result<(), ApiError> {
+        if self.cancellation_token.is_cancelled() {
+            return Err(ApiError::Cancelled);
+        }
+        if let Some(usage_tracker) = &self.usage_tracker {
+            usage_tracker.check_budget()?;
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(deadline_exceeded()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Completes with the error to stop with once the run is cancelled or past
+    /// its deadline.
+    pub async fn interrupted(&self) -> ApiError {
+        let deadline = async {
+            match self.deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = self.cancellation_token.cancelled() => ApiError::Cancelled,
+            _ = deadline => deadline_exceeded(),
+        }
+    }
+
+    /// Runs `future` until it completes or the run is interrupted. The future is
+    /// dropped on interruption, which aborts any request it has in flight.
+    pub async fn guard<T, E, F>(&self, future: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: From<ApiError>,
+    {
+        tokio::select! {
+            biased;
+            error = self.interrupted() => Err(error.into()),
+            result = future => result,
+        }
+    }
+
+    /// Ends `inner` with the interruption error once the run is interrupted.
+    pub fn guard_stream<T: Send + 'static>(
+        &self,
+        inner: BoxStream<'static, Result<T, ApiError>>,
+    ) -> BoxStream<'static, Result<T, ApiError>> {
+        stream::unfold(Some((inner, self.clone())), |state| async move {
+            let (mut inner, options) = state?;
+            tokio::select! {
+                biased;
+                error = options.interrupted() => Some((Err(error), None)),
+                item = inner.next() => item.map(|item| (item, Some((inner, options)))),
+            }
+        })
+        .boxed()
+    }
+
+    /// Same as `guard_stream`, for the channels returned by chains and agents.
+    /// Dropping the returned receiver also drops `inner`.
+    pub fn guard_receiver<T: Send + 'static>(
+        &self,
+        mut inner: mpsc::Receiver<Result<T, ApiError>>,
+    ) -> mpsc::Receiver<Result<T, ApiError>> {
+        let (tx, rx) = mpsc::channel(100);
+        let options = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let item = tokio::select! {
+                    _ = tx.closed() => break,
+                    error = options.interrupted() => Err(error),
+                    item = inner.recv() => match item {
+                        Some(item) => item,
+                        None => break,
+                    },
+                };
+                let stop = item.is_err();
+                if tx.send(item).await.is_err() || stop {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+}
+
+fn deadline_exceeded() -> ApiError {
+    ApiError::Timeout(String::from("The deadline of the run was exceeded"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_guard_stops_on_cancellation_and_deadline() {
+        let token = CancellationToken::new();
+        let options = RunOptions::new().with_cancellation_token(token.clone());
+        token.cancel();
+        let result: Result<(), ApiError> = options.guard(std::future::pending()).await;
+        assert!(matches!(result, Err(ApiError::Cancelled)));
+        assert!(options.check().is_err());
+
+        let options = RunOptions::new()
+            .with_timeout(Duration::from_secs(60))
+            .with_timeout(Duration::from_millis(20));
+        let result: Result<(), ApiError> = options.guard(std::future::pending()).await;
+        assert!(matches!(result, Err(ApiError::Timeout(_))));
+
+        let result: Result<u32, ApiError> = RunOptions::new().guard(async { Ok(1) }).await;
+        assert_eq!(result.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_guard_stream_ends_with_the_interruption() {
+        let token = CancellationToken::new();
+        let options = RunOptions::new().with_cancellation_token(token.clone());
+        let inner = stream::iter(vec![Ok(1)]).chain(stream::pending()).boxed();
+        let mut guarded = options.guard_stream(inner);
+
+        assert!(matches!(guarded.next().await, Some(Ok(1))));
+        token.cancel();
+        assert!(matches!(
+            guarded.next().await,
+            Some(Err(ApiError::Cancelled))
+        ));
+        assert!(guarded.next().await.is_none());
+    }
+}
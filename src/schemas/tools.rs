@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::tools::tool_trait::Tool;
+
+use super::messages::{FunctionCall, ToolCall};
+
+/// A function the model may call. `parameters` is a JSON schema object.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+impl FunctionDefinition {
+    pub fn new(name: &str, description: &str, parameters: Value) -> Self {
+        Self {
+            name: String::from(name),
+            description: String::from(description),
+            parameters,
+        }
+    }
+
+    pub fn from_tool(tool: &dyn Tool) -> Self {
+        Self {
+            name: tool.name(),
+            description: tool.description(),
+            parameters: tool.parameters(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ToolChoice {
+    Auto,
+    None,
+    Required,
+    Function(String),
+}
+
+// A fragment of a tool call, as sent in streamed chunks. Only the first fragment
+// of each call carries its id and name; the arguments arrive in pieces.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub function: Option<FunctionCallDelta>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCallDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+/// Assembles complete tool calls from streamed fragments.
+#[derive(Debug, Default)]
+pub struct ToolCallAccumulator {
+    tool_calls: Vec<ToolCall>,
+}
+impl ToolCallAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, delta: &ToolCallDelta) {
+        while self.tool_calls.len() <= delta.index {
+            self.tool_calls.push(ToolCall {
+                id: String::new(),
+                call_type: String::from("function"),
+                function: FunctionCall {
+                    name: String::new(),
+                    arguments: String::new(),
+                },
+            });
+        }
+
+        let tool_call = &mut self.tool_calls[delta.index];
+        if let Some(id) = &delta.id {
+            tool_call.id.push_str(id);
+        }
+        if let Some(function) = &delta.function {
+            if let Some(name) = &function.name {
+                tool_call.function.name.push_str(name);
+            }
+            if let Some(arguments) = &function.arguments {
+                tool_call.function.arguments.push_str(arguments);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tool_calls.is_empty()
+    }
+
+    pub fn finish(self) -> Vec<ToolCall> {
+        self.tool_calls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accumulator_assembles_streamed_calls() {
+        let deltas: Vec<ToolCallDelta> = serde_json::from_str(
+            r#"[
+                {"index":0,"id":"call_1","type":"function","function":{"name":"search","arguments":""}},
+                {"index":1,"id":"call_2","type":"function","function":{"name":"calculator","arguments":"{\"expr\""}},
+                {"index":0,"function":{"arguments":"{\"q\":"}},
+                {"index":0,"function":{"arguments":"\"rust\"}"}},
+                {"index":1,"function":{"arguments":":\"1+1\"}"}}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut accumulator = ToolCallAccumulator::new();
+        for delta in &deltas {
+            accumulator.push(delta);
+        }
+
+        assert_eq!(
+            accumulator.finish(),
+            vec![
+                ToolCall::new("call_1", "search", r#"{"q":"rust"}"#),
+                ToolCall::new("call_2", "calculator", r#"{"expr":"1+1"}"#),
+            ]
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use tiktoken_rs::CoreBPE;
+
+use crate::{
+    chat_models::openai::ChatModel,
+    schemas::messages::{BaseMessage, ContentPart},
+};
+
+// Every reply is primed with `<|start|>assistant<|message|>`
+const REPLY_PRIMING_TOKENS: usize = 3;
+// What OpenAI charges for a low detail image, the least an image can cost
+const IMAGE_TOKENS: usize = 85;
+
+/// Byte pair encoding with the cl100k_base ranks used by the GPT-3.5 and GPT-4
+/// models. The ranks are compiled into the binary, so counting works offline,
+/// and are loaded once on first use.
+///
+/// Special tokens such as `<|endoftext|>` in the text are encoded as plain
+/// text, like the API does for user content.
+#[derive(Clone, Copy)]
+pub struct Tokenizer {
+    bpe: &'static CoreBPE,
+}
+
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::cl100k_base()
+    }
+}
+
+impl Tokenizer {
+    pub fn cl100k_base() -> Self {
+        Self {
+            bpe: tiktoken_rs::cl100k_base_singleton(),
+        }
+    }
+
+    pub fn for_model(model: &ChatModel) -> Self {
+        match model {
+            ChatModel::Gpt3_5Turbo
+            | ChatModel::Gpt3_5Turbo16k
+            | ChatModel::GPT3_5TURBO0613
+            | ChatModel::Gpt4
+            | ChatModel::Gpt4TURBO => Self::cl100k_base(),
+        }
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        self.bpe.encode_ordinary(text)
+    }
+
+    /// Returns `None` if a token does not exist in the vocabulary. Text cut in
+    /// the middle of a character decodes with replacement characters.
+    pub fn decode(&self, tokens: &[u32]) -> Option<String> {
+        let bytes = self.bpe.decode_bytes(tokens).ok()?;
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+
+    /// Counts the tokens of a chat request the way OpenAI bills them: the
+    /// content and role of every message, the tool calls, the per-message
+    /// overhead of `model` and the tokens priming the reply. Images are counted
+    /// at their low detail cost.
+    pub fn count_messages(&self, messages: &[Box<dyn BaseMessage>], model: &ChatModel) -> usize {
+        self.count_conversation(messages, model.tokens_per_message())
+    }
+
+    /// Like `count_messages`, with the per-message overhead given directly, e.g.
+    /// to estimate the requests to other providers.
+    pub fn count_conversation<'a>(
+        &self,
+        messages: impl IntoIterator<Item = &'a Box<dyn BaseMessage>>,
+        tokens_per_message: usize,
+    ) -> usize {
+        let tokens: usize = messages
+            .into_iter()
+            .map(|message| tokens_per_message + self.count_message(message.as_ref()))
+            .sum();
+        tokens + REPLY_PRIMING_TOKENS
+    }
+
+    /// Splits `text` into pieces of at most `max_tokens` tokens. Pieces end on
+    /// character boundaries, so one may be a token short.
+    pub fn split(&self, text: &str, max_tokens: usize) -> Vec<String> {
+        let max_tokens = max_tokens.max(1);
+        let tokens = self.encode(text);
+        let mut pieces = Vec::with_capacity(tokens.len().div_ceil(max_tokens));
+        let mut start = 0;
+        while start < tokens.len() {
+            let mut end = (start + max_tokens).min(tokens.len());
+            // Move the end back until the piece is valid UTF-8, unless a single
+            // token holds only part of a character
+            let piece = loop {
+                let bytes = self
+                    .bpe
+                    .decode_bytes(&tokens[start..end])
+                    .unwrap_or_default();
+                match String::from_utf8(bytes) {
+                    Ok(piece) => break piece,
+                    Err(e) if end - start == 1 => {
+                        break String::from_utf8_lossy(e.as_bytes()).into_owned()
+                    }
+                    Err(_) => end -= 1,
+                }
+            };
+            pieces.push(piece);
+            start = end;
+        }
+        pieces
+    }
+
+    /// Counts the role, the content and the tool calls of one message, without
+    /// the per-message overhead.
+    pub fn count_message(&self, message: &dyn BaseMessage) -> usize {
+        let mut tokens = self.count(&message.get_type()) + self.count(&message.get_content());
+        for part in message.get_content_parts() {
+            tokens += match part {
+                ContentPart::Text { text } => self.count(&text),
+                ContentPart::ImageUrl { .. } | ContentPart::File { .. } => IMAGE_TOKENS,
+            };
+        }
+        for tool_call in message.get_tool_calls() {
+            tokens +=
+                self.count(&tool_call.function.name) + self.count(&tool_call.function.arguments);
+        }
+        if let Some(tool_call_id) = message.get_tool_call_id() {
+            tokens += self.count(&tool_call_id);
+        }
+        tokens
+    }
+}
+
+/// Counts the tokens of `text` with cl100k_base.
+pub fn count_tokens(text: &str) -> usize {
+    Tokenizer::cl100k_base().count(text)
+}
+
+/// Counts the tokens of a chat request to `model`, overhead included.
+pub fn count_message_tokens(messages: &[Box<dyn BaseMessage>], model: &ChatModel) -> usize {
+    Tokenizer::for_model(model).count_messages(messages, model)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::messages::{AIMessage, HumanMessage, SystemMessage, ToolCall};
+
+    use super::*;
+
+    #[test]
+    fn test_encodes_like_cl100k_base() {
+        let tokenizer = Tokenizer::default();
+        assert_eq!(tokenizer.encode("hello world"), vec![15339, 1917]);
+        assert_eq!(count_tokens("tiktoken is great!"), 6);
+        assert_eq!(count_tokens("<|endoftext|>"), 7);
+
+        let tokens = tokenizer.encode("Grüße, 世界!");
+        assert_eq!(tokenizer.decode(&tokens).unwrap(), "Grüße, 世界!");
+        assert!(tokenizer.decode(&[u32::MAX]).is_none());
+    }
+
+    #[test]
+    fn test_counts_messages_with_overhead() {
+        let messages: Vec<Box<dyn BaseMessage>> = vec![
+            Box::new(SystemMessage::new("You are helpful")),
+            Box::new(HumanMessage::new("hello world")),
+        ];
+        // 3 per message, the roles, the content and 3 to prime the reply
+        let expected = 3 + 1 + 3 + 3 + 1 + 2 + 3;
+        assert_eq!(count_message_tokens(&messages, &ChatModel::Gpt4), expected);
+
+        let messages: Vec<Box<dyn BaseMessage>> =
+            vec![Box::new(AIMessage::new("").with_tool_calls(vec![
+                ToolCall::new("call_1", "search", "{\"q\":\"rust\"}"),
+            ]))];
+        assert!(count_message_tokens(&messages, &ChatModel::Gpt4) > 3 + 1 + 3);
+    }
+
+    #[test]
+    fn test_splits_on_character_boundaries() {
+        let tokenizer = Tokenizer::default();
+        let text = "The quick brown fox jumps over the lazy dog. 世界".repeat(5);
+        let pieces = tokenizer.split(&text, 7);
+        assert_eq!(pieces.concat(), text);
+        assert!(pieces.iter().all(|piece| tokenizer.count(piece) <= 7));
+        assert!(tokenizer.split("", 7).is_empty());
+    }
+}
@@ -1,13 +1,69 @@
-use std::error::Error;
-use std::string::String;
+use std::{string::String, time::Instant};
 
 use async_trait::async_trait;
+use serde_json::{json, Value};
+use tracing::{field, Instrument};
+
+use crate::{errors::BoxError, schemas::run::RunOptions};
 
 #[async_trait]
 pub trait Tool: CloneBox + Send + Sync {
     fn name(&self) -> String;
     fn description(&self) -> String;
-    async fn call(&self, input: &str) -> Result<String, Box<dyn Error>>;
+
+    // JSON schema of the tool input, used when the tool is offered to a model
+    // through native function calling. Defaults to a single string argument.
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "input": { "type": "string" }
+            },
+            "required": ["input"]
+        })
+    }
+
+    async fn call(&self, input: &str) -> Result<String, BoxError>;
+
+    // Called by agents, in a `tool_call` tracing span. The call is dropped when
+    // the run is cancelled or past its deadline; tools doing long work can
+    // override it to watch `options` themselves.
+    async fn call_with_options(
+        &self,
+        input: &str,
+        options: &RunOptions,
+    ) -> Result<String, BoxError> {
+        let (run_id, _) = options.start_run();
+        let span = tracing::info_span!(
+            "tool_call",
+            %run_id,
+            tool = %self.name(),
+            input = %options.redact(|| input.to_string()),
+            output = field::Empty,
+            latency_ms = field::Empty,
+            error = field::Empty,
+        );
+        let started = Instant::now();
+        options.emit(|handler| {
+            handler.on_tool_start(run_id, options.parent_run_id(), &self.name(), input)
+        });
+        let result = options
+            .guard(self.call(input))
+            .instrument(span.clone())
+            .await;
+        span.record("latency_ms", started.elapsed().as_millis() as u64);
+        match &result {
+            Ok(output) => {
+                span.record("output", options.redact(|| output.clone()));
+                options.emit(|handler| handler.on_tool_end(run_id, output))
+            }
+            Err(e) => {
+                span.record("error", field::display(e));
+                options.emit(|handler| handler.on_tool_error(run_id, e.as_ref()))
+            }
+        }
+        result
+    }
 }
 
 pub trait CloneBox {
@@ -0,0 +1,524 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    errors::{ApiError, LLMError},
+    schemas::{
+        agent::{AgentAction, AgentFinish},
+        callbacks::{CallbackHandler, Uuid},
+        llm::ChatResult,
+        messages::BaseMessage,
+    },
+};
+
+// The file of the JSONL format, in the directory of the tracer
+const JSONL_FILE: &str = "runs.jsonl";
+// Runs still open after this long are written as they are and forgotten
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    // One pretty printed `<run id>.json` file per run
+    Json,
+    // One line per run, appended to `runs.jsonl`
+    Jsonl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunType {
+    // A chain or an agent executor
+    Chain,
+    Llm,
+    Tool,
+}
+
+/// One run of a chain, an agent executor, a chat model or a tool, with the runs
+/// it started as children, in the order they started.
+///
+/// The inputs of a chain are its template arguments, those of a model the
+/// messages it was sent and those of a tool its input. The outputs are the
+/// answer of a chain, the full `ChatResult` of a model and the observation of a
+/// tool. Times are in milliseconds since the Unix epoch.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Run {
+    pub id: Uuid,
+    pub parent_run_id: Option<Uuid>,
+    pub run_type: RunType,
+    // The tool, or the model once it answered
+    pub name: Option<String>,
+    pub inputs: Value,
+    pub outputs: Option<Value>,
+    // The steps an agent executor took, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<AgentAction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish: Option<AgentFinish>,
+    pub error: Option<String>,
+    pub start_time: u64,
+    // Missing for runs still going when the tree was written
+    pub end_time: Option<u64>,
+    #[serde(default)]
+    pub children: Vec<Run>,
+}
+
+impl Run {
+    fn new(id: Uuid, parent_run_id: Option<Uuid>, run_type: RunType, inputs: Value) -> Self {
+        Self {
+            id,
+            parent_run_id,
+            run_type,
+            name: None,
+            inputs,
+            outputs: None,
+            actions: Vec::new(),
+            finish: None,
+            error: None,
+            start_time: now(),
+            end_time: None,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = std::fs::read(path)?;
+        serde_json::from_slice(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn latency_ms(&self) -> Option<u64> {
+        Some(self.end_time?.saturating_sub(self.start_time))
+    }
+
+    /// This run and its descendants, depth first in the order they started.
+    pub fn runs(&self) -> Vec<&Run> {
+        let mut runs = vec![self];
+        for child in &self.children {
+            runs.extend(child.runs());
+        }
+        runs
+    }
+
+    pub fn find(&self, id: Uuid) -> Option<&Run> {
+        self.runs().into_iter().find(|run| run.id == id)
+    }
+}
+
+#[derive(Default)]
+struct TracerState {
+    // Runs whose tree is not written yet, without their children
+    runs: HashMap<Uuid, Run>,
+    children: HashMap<Uuid, Vec<Uuid>>,
+}
+
+impl TracerState {
+    // The runs whose parent is not traced
+    fn roots(&self) -> Vec<Uuid> {
+        self.runs
+            .values()
+            .filter(|run| {
+                run.parent_run_id
+                    .is_none_or(|parent| !self.runs.contains_key(&parent))
+            })
+            .map(|run| run.id)
+            .collect()
+    }
+
+    // Takes the run and its descendants out of the state as one tree.
+    fn take_tree(&mut self, id: Uuid) -> Option<Run> {
+        let mut run = self.runs.remove(&id)?;
+        for child in self.children.remove(&id).unwrap_or_default() {
+            if let Some(child) = self.take_tree(child) {
+                run.children.push(child);
+            }
+        }
+        Some(run)
+    }
+}
+
+/// A `CallbackHandler` that records every run as a tree and writes the tree
+/// when the outermost run ends, e.g. the agent executor: what the model was
+/// sent and answered at each step, the parsed actions, the tool observations,
+/// the timings and the errors.
+///
+/// Trees are written to `<dir>/<run id>.json` by default, or appended as one
+/// line each to `<dir>/runs.jsonl` with `with_jsonl`. A run that fails, or
+/// whose stream is dropped, ends with its error. Runs that never end, e.g. a
+/// stream nobody reads to the end, are written as they are once older than
+/// the maximum age, 10 minutes by default, and when the last clone of the
+/// tracer is dropped.
+///
+/// The traces hold the full prompts and answers. Add the tracer to the
+/// `RunOptions` of the runs to debug, and load the traces back with
+/// `Run::load` or `RunTreeTracer::load_runs`.
+#[derive(Clone)]
+pub struct RunTreeTracer {
+    dir: PathBuf,
+    format: TraceFormat,
+    max_age: Duration,
+    state: Arc<Mutex<TracerState>>,
+    // Keeps the lines of concurrent runs apart in the JSONL file
+    writer: Arc<Mutex<()>>,
+}
+
+impl RunTreeTracer {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            format: TraceFormat::Json,
+            max_age: DEFAULT_MAX_AGE,
+            state: Arc::new(Mutex::new(TracerState::default())),
+            writer: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn with_jsonl(mut self) -> Self {
+        self.format = TraceFormat::Jsonl;
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The file the tree of `run_id` is written to.
+    pub fn path(&self, run_id: Uuid) -> PathBuf {
+        match self.format {
+            TraceFormat::Json => self.dir.join(format!("{}.json", run_id)),
+            TraceFormat::Jsonl => self.dir.join(JSONL_FILE),
+        }
+    }
+
+    /// Every trace written to `dir` in either format, oldest first.
+    pub fn load_runs(dir: impl AsRef<Path>) -> io::Result<Vec<Run>> {
+        let mut runs = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("json") => runs.push(Run::load(path)?),
+                Some("jsonl") => {
+                    for line in std::fs::read_to_string(path)?.lines() {
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        let run = serde_json::from_str(line)
+                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                        runs.push(run);
+                    }
+                }
+                _ => {}
+            }
+        }
+        runs.sort_by_key(|run| run.start_time);
+        Ok(runs)
+    }
+
+    /// Writes the runs still open as they are and forgets them.
+    pub fn flush(&self) {
+        let trees: Vec<Run> = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state
+                .roots()
+                .into_iter()
+                .filter_map(|id| state.take_tree(id))
+                .collect()
+        };
+        for tree in &trees {
+            self.write(tree);
+        }
+    }
+
+    fn start(&self, run: Run) {
+        let expired: Vec<Run> = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(parent) = run.parent_run_id.filter(|id| state.runs.contains_key(id)) {
+                state.children.entry(parent).or_default().push(run.id);
+            }
+            let oldest = run
+                .start_time
+                .saturating_sub(self.max_age.as_millis() as u64);
+            state.runs.insert(run.id, run);
+            let expired: Vec<Uuid> = state
+                .roots()
+                .into_iter()
+                .filter(|id| state.runs[id].start_time < oldest)
+                .collect();
+            expired
+                .into_iter()
+                .filter_map(|id| state.take_tree(id))
+                .collect()
+        };
+        for tree in &expired {
+            log::warn!("Run {} did not end in time, writing it as it is", tree.id);
+            self.write(tree);
+        }
+    }
+
+    fn update(&self, run_id: Uuid, update: impl FnOnce(&mut Run)) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(run) = state.runs.get_mut(&run_id) {
+            update(run);
+        }
+    }
+
+    // Ends the run, writing its tree if it is the outermost run. Runs whose
+    // parent is not traced count as outermost.
+    fn end(&self, run_id: Uuid, update: impl FnOnce(&mut Run)) {
+        let tree = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(run) = state.runs.get_mut(&run_id) else {
+                return;
+            };
+            update(run);
+            run.end_time = Some(now());
+            let parent = run.parent_run_id;
+            if parent.is_some_and(|id| state.runs.contains_key(&id)) {
+                return;
+            }
+            state.take_tree(run_id)
+        };
+        if let Some(tree) = tree {
+            self.write(&tree);
+        }
+    }
+
+    fn write(&self, run: &Run) {
+        let path = self.path(run.id);
+        let result = std::fs::create_dir_all(&self.dir).and_then(|_| match self.format {
+            TraceFormat::Json => std::fs::write(&path, serde_json::to_string_pretty(run)?),
+            TraceFormat::Jsonl => {
+                let mut line = serde_json::to_string(run)?;
+                line.push('\n');
+                let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+                let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+                file.write_all(line.as_bytes())
+            }
+        });
+        if let Err(e) = result {
+            log::error!("Could not write run tree {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Drop for RunTreeTracer {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.state) == 1 {
+            self.flush();
+        }
+    }
+}
+
+impl CallbackHandler for RunTreeTracer {
+    fn on_llm_start(
+        &self,
+        run_id: Uuid,
+        parent_run_id: Option<Uuid>,
+        messages: &[Box<dyn BaseMessage>],
+    ) {
+        let inputs = json!({ "messages": messages });
+        self.start(Run::new(run_id, parent_run_id, RunType::Llm, inputs));
+    }
+
+    fn on_llm_end(&self, run_id: Uuid, result: &ChatResult) {
+        self.end(run_id, |run| {
+            run.name = Some(result.model.clone()).filter(|model| !model.is_empty());
+            run.outputs = serde_json::to_value(result).ok();
+        });
+    }
+
+    fn on_llm_error(&self, run_id: Uuid, error: &ApiError) {
+        self.end(run_id, |run| run.error = Some(error.to_string()));
+    }
+
+    fn on_chain_start(
+        &self,
+        run_id: Uuid,
+        parent_run_id: Option<Uuid>,
+        inputs: &HashMap<String, Value>,
+    ) {
+        let inputs = json!(inputs);
+        self.start(Run::new(run_id, parent_run_id, RunType::Chain, inputs));
+    }
+
+    fn on_chain_end(&self, run_id: Uuid, output: &str) {
+        self.end(run_id, |run| {
+            run.outputs = Some(json!({ "output": output }))
+        });
+    }
+
+    fn on_chain_error(&self, run_id: Uuid, error: &LLMError) {
+        self.end(run_id, |run| run.error = Some(error.to_string()));
+    }
+
+    fn on_tool_start(&self, run_id: Uuid, parent_run_id: Option<Uuid>, tool: &str, input: &str) {
+        let mut run = Run::new(
+            run_id,
+            parent_run_id,
+            RunType::Tool,
+            json!({ "input": input }),
+        );
+        run.name = Some(tool.to_string());
+        self.start(run);
+    }
+
+    fn on_tool_end(&self, run_id: Uuid, output: &str) {
+        self.end(run_id, |run| {
+            run.outputs = Some(json!({ "output": output }))
+        });
+    }
+
+    fn on_tool_error(&self, run_id: Uuid, error: &(dyn Error + Send + Sync)) {
+        self.end(run_id, |run| run.error = Some(error.to_string()));
+    }
+
+    fn on_agent_action(&self, run_id: Uuid, action: &AgentAction) {
+        self.update(run_id, |run| run.actions.push(action.clone()));
+    }
+
+    fn on_agent_finish(&self, run_id: Uuid, finish: &AgentFinish) {
+        self.update(run_id, |run| run.finish = Some(finish.clone()));
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use futures::StreamExt;
+
+    use crate::{
+        agents::{
+            chat::{output_parser::ConvoOutputParser, ConversationalAgent},
+            executor::AgentExecutor,
+        },
+        chains::chain_trait::ChainTrait,
+        chat_models::{chat_model_trait::ChatTrait, fake::FakeChatModel},
+        errors::BoxError,
+        schemas::{llm::LlmResponse, messages::HumanMessage, run::RunOptions},
+        tools::tool_trait::Tool,
+    };
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct FailingTool;
+    #[async_trait]
+    impl Tool for FailingTool {
+        fn name(&self) -> String {
+            "Search".to_string()
+        }
+        fn description(&self) -> String {
+            "Searches the web".to_string()
+        }
+        async fn call(&self, _input: &str) -> Result<String, BoxError> {
+            Err("The search engine is down".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_writes_and_reloads_the_tree_of_a_failed_agent_run() {
+        let llm = FakeChatModel::new(vec![
+            "```json\n{\"action\": \"Search\", \"action_input\": \"weather in Lima\"}\n```",
+        ]);
+        let agent = ConversationalAgent::from_llm_and_tools(
+            Box::new(llm),
+            vec![Arc::new(FailingTool)],
+            Box::new(ConvoOutputParser::new()),
+        )
+        .unwrap();
+        let executor = AgentExecutor::from_agent(Box::new(agent));
+        let dir = tempfile::tempdir().unwrap();
+        let tracer = RunTreeTracer::new(dir.path());
+        let options = RunOptions::new().with_callback(Arc::new(tracer.clone()));
+
+        let result = executor
+            .call_with_options(&String::from("Weather in Lima?"), &options)
+            .await;
+        assert!(result.is_err());
+
+        let runs = RunTreeTracer::load_runs(dir.path()).unwrap();
+        assert_eq!(runs.len(), 1);
+        let root = &runs[0];
+        assert_eq!(root.run_type, RunType::Chain);
+        assert_eq!(root.inputs["input"], "Weather in Lima?");
+        assert_eq!(root.actions[0].tool, "Search");
+        assert!(root.error.as_ref().unwrap().contains("Search"));
+        assert!(root.latency_ms().is_some());
+
+        // The plan of the agent, then the tool call
+        let kinds: Vec<RunType> = root.children.iter().map(|run| run.run_type).collect();
+        assert_eq!(kinds, vec![RunType::Chain, RunType::Tool]);
+        let llm_run = &root.children[0].children[0];
+        assert_eq!(llm_run.run_type, RunType::Llm);
+        let prompt = llm_run.inputs["messages"].to_string();
+        assert!(prompt.contains("Weather in Lima?"));
+        assert!(llm_run
+            .outputs
+            .as_ref()
+            .unwrap()
+            .to_string()
+            .contains("weather in Lima"));
+        let tool_run = &root.children[1];
+        assert_eq!(tool_run.name.as_deref(), Some("Search"));
+        assert_eq!(tool_run.error.as_deref(), Some("The search engine is down"));
+
+        let reloaded = Run::load(tracer.path(root.id)).unwrap();
+        assert_eq!(reloaded.runs().len(), root.runs().len());
+        assert!(reloaded.find(llm_run.id).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_appends_finished_and_abandoned_runs_as_jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let tracer = RunTreeTracer::new(dir.path()).with_jsonl();
+        let options = RunOptions::new().with_callback(Arc::new(tracer.clone()));
+        let llm = FakeChatModel::new(vec!["Hello there"]).with_stream();
+
+        let LlmResponse::Stream(chat_stream) = llm
+            .invoke_with_options(vec![Box::new(HumanMessage::new("Hi"))], &options)
+            .await
+            .unwrap()
+        else {
+            panic!("expected a stream");
+        };
+        chat_stream.collect::<Vec<_>>().await;
+        // Never read, so the run never ends
+        let abandoned = llm
+            .invoke_with_options(vec![Box::new(HumanMessage::new("Bye"))], &options)
+            .await
+            .unwrap();
+        let runs = RunTreeTracer::load_runs(dir.path()).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].end_time.is_some());
+
+        // Dropping the last clone writes the runs still open
+        drop(abandoned);
+        drop(options);
+        drop(tracer);
+        let runs = RunTreeTracer::load_runs(dir.path()).unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[1].run_type, RunType::Llm);
+        assert!(runs[1].end_time.is_none());
+        assert!(runs[1].inputs.to_string().contains("Bye"));
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    collections::HashMap,
+    ops::AddAssign,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::ApiError,
+    schemas::{
+        callbacks::{CallbackHandler, Uuid},
+        llm::{ChatResult, TokenUsage},
+    },
+};
+
+// The model name of results that do not report one
+const UNKNOWN_MODEL: &str = "unknown";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub embedding_tokens: u64,
+}
+impl ModelUsage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens + self.embedding_tokens
+    }
+}
+impl AddAssign for ModelUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.embedding_tokens += other.embedding_tokens;
+    }
+}
+
+// Prices in dollars per million tokens. Embedding tokens are billed as input.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+impl ModelPrice {
+    pub fn per_million(input: f64, output: f64) -> Self {
+        Self { input, output }
+    }
+
+    pub fn cost(&self, usage: &ModelUsage) -> f64 {
+        let input = (usage.prompt_tokens + usage.embedding_tokens) as f64 * self.input;
+        let output = usage.completion_tokens as f64 * self.output;
+        (input + output) / 1_000_000.0
+    }
+}
+
+/// The price of each model. A model without a price of its own takes the one
+/// of the longest name it starts with, so `gpt-4o` also prices the dated
+/// `gpt-4o-2024-08-06` reported by the API. Unknown models cost nothing.
+#[derive(Clone, Debug, Default)]
+pub struct PriceTable {
+    prices: HashMap<String, ModelPrice>,
+}
+impl PriceTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_price(mut self, model: &str, price: ModelPrice) -> Self {
+        self.prices.insert(model.to_string(), price);
+        self
+    }
+
+    pub fn price(&self, model: &str) -> Option<ModelPrice> {
+        if let Some(price) = self.prices.get(model) {
+            return Some(*price);
+        }
+        self.prices
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| *price)
+    }
+
+    pub fn cost(&self, model: &str, usage: &ModelUsage) -> f64 {
+        self.price(model)
+            .map(|price| price.cost(usage))
+            .unwrap_or_default()
+    }
+}
+
+/// Adds up the tokens spent per model and prices them with a `PriceTable`.
+///
+/// Clones share their counts, so keep one tracker per customer or per tenant
+/// and hand it to every run made on their behalf with
+/// `RunOptions::with_usage_tracker`, or to the OpenAI models and embedders
+/// called directly with their own `with_usage_tracker`. Attach it one way or
+/// the other for a given model, or its requests are counted twice.
+///
+/// With a budget, runs fail with `ApiError::BudgetExceeded` once the spend or
+/// the tokens go past it. The budget is checked before each request, so the
+/// request that crosses it still completes.
+#[derive(Clone, Debug, Default)]
+pub struct UsageTracker {
+    prices: PriceTable,
+    max_cost: Option<f64>,
+    max_tokens: Option<u64>,
+    usage: Arc<Mutex<HashMap<String, ModelUsage>>>,
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_prices(mut self, prices: PriceTable) -> Self {
+        self.prices = prices;
+        self
+    }
+
+    /// Maximum spend in dollars, priced with the `PriceTable`.
+    pub fn with_budget(mut self, max_cost: f64) -> Self {
+        self.max_cost = Some(max_cost);
+        self
+    }
+
+    /// Maximum number of tokens, of every kind and model together.
+    pub fn with_token_budget(mut self, max_tokens: u64) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Adds one chat or completion request to `model`.
+    pub fn record(&self, model: &str, usage: TokenUsage) {
+        self.add(
+            model,
+            ModelUsage {
+                requests: 1,
+                prompt_tokens: usage.prompt_tokens.into(),
+                completion_tokens: usage.completion_tokens.into(),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Adds one embedding request to `model`.
+    pub fn record_embedding(&self, model: &str, tokens: u64) {
+        self.add(
+            model,
+            ModelUsage {
+                requests: 1,
+                embedding_tokens: tokens,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// The usage so far, by model.
+    pub fn usage(&self) -> HashMap<String, ModelUsage> {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The usage so far, all models together.
+    pub fn total(&self) -> ModelUsage {
+        let mut total = ModelUsage::default();
+        for usage in self.usage().into_values() {
+            total += usage;
+        }
+        total
+    }
+
+    /// The spend so far in dollars.
+    pub fn cost(&self) -> f64 {
+        self.usage()
+            .iter()
+            .map(|(model, usage)| self.prices.cost(model, usage))
+            .sum()
+    }
+
+    pub fn reset(&self) {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// The error to stop with once the budget is exceeded.
+    pub fn check_budget(&self) -> Result<(), ApiError> {
+        if let Some(max_cost) = self.max_cost {
+            let cost = self.cost();
+            if cost > max_cost {
+                return Err(ApiError::BudgetExceeded(format!(
+                    "spent ${:.4} of ${:.4}",
+                    cost, max_cost
+                )));
+            }
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            let tokens = self.total().total_tokens();
+            if tokens > max_tokens {
+                return Err(ApiError::BudgetExceeded(format!(
+                    "used {} of {} tokens",
+                    tokens, max_tokens
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&self, model: &str, usage: ModelUsage) {
+        let model = if model.is_empty() {
+            UNKNOWN_MODEL
+        } else {
+            model
+        };
+        let mut state = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        *state.entry(model.to_string()).or_default() += usage;
+    }
+}
+
+impl CallbackHandler for UsageTracker {
+    fn on_llm_end(&self, _run_id: Uuid, result: &ChatResult) {
+        match result.usage {
+            Some(usage) => self.record(&result.model, usage),
+            None => {
+                log::debug!("No usage reported by {}", result.model);
+                self.record(&result.model, TokenUsage::default());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        chains::{chain_trait::ChainTrait, llmchat_chain::LLMChatChain},
+        chat_models::fake::FakeChatModel,
+        prompt::{ChatPromptTemplate, HumanMessagePromptTemplate, MessageLike, PromptTemplate},
+        schemas::run::RunOptions,
+    };
+
+    use super::*;
+
+    fn chain(model: FakeChatModel) -> LLMChatChain {
+        let prompt = ChatPromptTemplate::from_messages(vec![MessageLike::base_prompt_template(
+            HumanMessagePromptTemplate::new(PromptTemplate::from_template("{{input}}")),
+        )]);
+        LLMChatChain::new(prompt, Box::new(model))
+    }
+
+    #[test]
+    fn test_prices_usage_by_model() {
+        let prices = PriceTable::new()
+            .with_price("gpt-4o", ModelPrice::per_million(2.5, 10.0))
+            .with_price("gpt-4o-mini", ModelPrice::per_million(0.15, 0.6))
+            .with_price("text-embedding-3-small", ModelPrice::per_million(0.02, 0.0));
+        let tracker = UsageTracker::new().with_prices(prices);
+        tracker.record("gpt-4o-2024-08-06", TokenUsage::new(1_000_000, 100_000));
+        tracker.record("gpt-4o-mini-2024-07-18", TokenUsage::new(1_000_000, 0));
+        tracker.record_embedding("text-embedding-3-small", 500_000);
+        tracker.record("llama3", TokenUsage::new(10, 10));
+
+        assert!((tracker.cost() - (2.5 + 1.0 + 0.15 + 0.01)).abs() < 1e-9);
+        let usage = tracker.usage();
+        assert_eq!(usage["gpt-4o-2024-08-06"].completion_tokens, 100_000);
+        assert_eq!(usage["llama3"].requests, 1);
+        assert_eq!(tracker.total().total_tokens(), 2_600_020);
+
+        tracker.reset();
+        assert_eq!(tracker.total(), ModelUsage::default());
+    }
+
+    #[tokio::test]
+    async fn test_tracks_runs_and_stops_over_budget() {
+        let model = FakeChatModel::new(vec!["Hello there"])
+            .with_stream()
+            .with_usage(TokenUsage::new(8, 2));
+        let chain = chain(model);
+        let tracker = UsageTracker::new().with_token_budget(15);
+        let options = RunOptions::new().with_usage_tracker(tracker.clone());
+        let input = String::from("Hi");
+
+        // The stream reports its usage once it has been read to the end
+        chain.call_with_options(&input, &options).await.unwrap();
+        assert_eq!(tracker.usage()["fake"].prompt_tokens, 8);
+        chain.call_with_options(&input, &options).await.unwrap();
+        assert_eq!(tracker.total().total_tokens(), 20);
+
+        let error = chain.call_with_options(&input, &options).await.unwrap_err();
+        assert!(matches!(
+            error.api_error(),
+            Some(ApiError::BudgetExceeded(_))
+        ));
+        assert_eq!(tracker.total().requests, 2);
+    }
+}